@@ -0,0 +1,39 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use managesieve::Command;
+
+fn script(len: usize) -> String {
+    let line = "if header :contains \"subject\" \"spam\" { fileinto \"Junk\"; }\r\n";
+    line.repeat(len / line.len() + 1)[..len].to_string()
+}
+
+fn bench_putscript(c: &mut Criterion) {
+    let mut group = c.benchmark_group("putscript");
+    for len in [1 << 10, 1 << 16, 1 << 22] {
+        let script = script(len);
+        let command = Command::put_script("generated", script.as_str()).unwrap();
+        group.throughput(Throughput::Bytes(len as u64));
+
+        // How Command was rendered before encode(), with the literal
+        // formatted into its own String first.
+        group.bench_with_input(BenchmarkId::new("format_twice", len), &script, |b, s| {
+            b.iter(|| {
+                let literal = format!("{{{}+}}\r\n{}", s.len(), s);
+                format!("PUTSCRIPT \"generated\" {}\r\n", literal)
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("to_string", len), &command, |b, c| {
+            b.iter(|| c.to_string())
+        });
+        let mut buf = Vec::with_capacity(len + 64);
+        group.bench_with_input(BenchmarkId::new("encode", len), &command, |b, c| {
+            b.iter(|| {
+                buf.clear();
+                c.encode(&mut buf).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_putscript);
+criterion_main!(benches);
@@ -0,0 +1,199 @@
+use std::collections::VecDeque;
+use std::io;
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::decoder::ResponseScanner;
+use crate::limits::ParserLimits;
+use crate::session::ResponseKind;
+use crate::types::{Command, CommandResponse};
+
+/// A [`tokio_util::codec`] codec for the client side of a managesieve
+/// connection, encoding [`Command`]s and decoding [`CommandResponse`]s.
+///
+/// The kind of each response is inferred from the commands encoded so far,
+/// starting with the server's greeting. Responses to commands written by
+/// other means can be announced with [`ManageSieveCodec::expect`].
+///
+/// After a successful STARTTLS the codec expects the capabilities the server
+/// sends once the TLS handshake is complete. The handshake itself must be
+/// performed on the underlying stream, for example after
+/// `Framed::into_parts`.
+#[derive(Debug)]
+pub struct ManageSieveCodec {
+    // Scans the read buffer in place.
+    scanner: ResponseScanner,
+    expected: VecDeque<ResponseKind>,
+}
+
+impl Default for ManageSieveCodec {
+    fn default() -> Self {
+        ManageSieveCodec::new()
+    }
+}
+
+impl ManageSieveCodec {
+    /// Creates a codec for a newly opened connection, which expects the
+    /// server's greeting first.
+    pub fn new() -> ManageSieveCodec {
+        ManageSieveCodec::with_limits(ParserLimits::default())
+    }
+
+    /// Like [`ManageSieveCodec::new`], but rejects responses exceeding
+    /// `limits`.
+    pub fn with_limits(limits: ParserLimits) -> ManageSieveCodec {
+        ManageSieveCodec {
+            scanner: ResponseScanner::with_limits(limits),
+            expected: vec![ResponseKind::Greeting].into(),
+        }
+    }
+
+    /// Adds a response of the given kind to those expected from the server.
+    pub fn expect(&mut self, kind: ResponseKind) {
+        self.expected.push_back(kind);
+    }
+
+    /// The kind of the next response expected from the server, if any.
+    pub fn expected(&self) -> Option<ResponseKind> {
+        self.expected.front().copied()
+    }
+}
+
+impl Encoder<Command> for ManageSieveCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Command, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.encode_buf(dst)?;
+        self.expected.push_back(ResponseKind::from(&item));
+        Ok(())
+    }
+}
+
+impl Decoder for ManageSieveCodec {
+    type Item = CommandResponse;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // `src` is only advanced past complete responses, so that what
+        // follows them stays there for `decode_eof`, or for
+        // `FramedRead::into_parts` after STARTTLS.
+        self.scanner.scan(src);
+
+        // A response nobody asked for can only be a BYE, or an error.
+        let kind = self.expected().unwrap_or(ResponseKind::Noop);
+        let (end, response) = match self.scanner.decode(src, kind)? {
+            Some(decoded) => decoded,
+            None => return Ok(None),
+        };
+        src.advance(end);
+
+        match &response {
+            CommandResponse::Challenge(_) => {}
+            CommandResponse::Bye(_) => self.expected.clear(),
+            CommandResponse::Ok(_) if kind == ResponseKind::StartTls => {
+                self.expected.pop_front();
+                self.expected.push_front(ResponseKind::Capability);
+            }
+            _ => {
+                self.expected.pop_front();
+            }
+        }
+        Ok(Some(response))
+    }
+}
+
+#[test]
+fn test_codec_inferred() {
+    let mut codec = ManageSieveCodec::new();
+    let mut buf = BytesMut::new();
+
+    buf.extend_from_slice(b"\"STARTTLS\"\r\nOK\r\n");
+    assert!(matches!(
+        codec.decode(&mut buf),
+        Ok(Some(CommandResponse::Capabilities(_, _)))
+    ));
+    assert_eq!(codec.expected(), None);
+
+    codec.encode(Command::StartTls, &mut buf).unwrap();
+    codec.encode(Command::ListScripts, &mut buf).unwrap();
+    assert_eq!(&buf[..], b"STARTTLS\r\nLISTSCRIPTS\r\n");
+    buf.clear();
+
+    buf.extend_from_slice(b"OK\r\n\"VERSION\" \"1.0\"\r\n");
+    assert!(matches!(
+        codec.decode(&mut buf),
+        Ok(Some(CommandResponse::Ok(_)))
+    ));
+    assert_eq!(codec.expected(), Some(ResponseKind::Capability));
+    assert_eq!(&buf[..], b"\"VERSION\" \"1.0\"\r\n");
+    assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    assert_eq!(buf.len(), 17);
+
+    buf.extend_from_slice(b"OK\r\n\"a\" ACTIVE\r\nOK\r\n");
+    assert!(matches!(
+        codec.decode(&mut buf),
+        Ok(Some(CommandResponse::Capabilities(_, _)))
+    ));
+    assert!(matches!(
+        codec.decode(&mut buf),
+        Ok(Some(CommandResponse::ScriptList(_, _)))
+    ));
+    assert_eq!(codec.expected(), None);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_codec_explicit() {
+    let mut codec = ManageSieveCodec::new();
+    let mut buf = BytesMut::from(&b"BYE (TRYLATER)\r\n"[..]);
+    assert!(matches!(
+        codec.decode(&mut buf),
+        Ok(Some(CommandResponse::Bye(_)))
+    ));
+    assert_eq!(codec.expected(), None);
+
+    codec.expect(ResponseKind::GetScript);
+    buf.extend_from_slice(b"{5}\r\nkeep;\r\nOK\r\n");
+    assert!(matches!(
+        codec.decode(&mut buf),
+        Ok(Some(CommandResponse::Script(s, _))) if s == "keep;"
+    ));
+
+    buf.extend_from_slice(b"garbage\r\nOK\r\n");
+    codec.expect(ResponseKind::Logout);
+    assert!(codec.decode(&mut buf).is_err());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_codec_framed() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::Framed;
+
+    let (client, mut server) = tokio::io::duplex(4096);
+    let mut framed = Framed::new(client, ManageSieveCodec::new());
+
+    server
+        .write_all(b"\"IMPLEMENTATION\" \"Example\"\r\n\"VERSION\" \"1.0\"\r\nOK\r\n")
+        .await
+        .unwrap();
+    let greeting = framed.next().await.unwrap().unwrap();
+    assert!(matches!(greeting, CommandResponse::Capabilities(caps, _) if caps.len() == 2));
+
+    framed
+        .send(Command::get_script("a").unwrap())
+        .await
+        .unwrap();
+    let mut sent = [0u8; 15];
+    server.read_exact(&mut sent).await.unwrap();
+    assert_eq!(&sent, b"GETSCRIPT \"a\"\r\n");
+
+    server
+        .write_all(include_bytes!("test_input/response_getscript-1.txt"))
+        .await
+        .unwrap();
+    let script = framed.next().await.unwrap().unwrap();
+    assert!(matches!(script, CommandResponse::Script(s, _) if s.len() == 100));
+}
@@ -0,0 +1,365 @@
+use std::str;
+
+use crate::error::{from_utf8, Error, ParseFailure};
+use crate::limits::{Limit, ParserLimits};
+use crate::session::ResponseKind;
+use crate::types::CommandResponse;
+
+// Whether a line is an OK, NO or BYE response, which ends the response to
+// any command other than AUTHENTICATE. A line ending in a bare LF counts, so
+// that the parser reports it rather than the decoder waiting for more.
+fn is_status(line: &[u8]) -> bool {
+    [&b"OK"[..], b"NO", b"BYE"].iter().any(|tag| {
+        line.len() > tag.len()
+            && line[..tag.len()].eq_ignore_ascii_case(tag)
+            && matches!(line[tag.len()], b' ' | b'\r' | b'\n')
+    })
+}
+
+// Returns the length of a literal announced at the end of a line, e.g.
+// `{12}` or `{12+}`.
+pub(crate) fn literal_len(segment: &[u8]) -> Option<usize> {
+    let segment = segment.strip_suffix(b"}")?;
+    let segment = segment.strip_suffix(b"+").unwrap_or(segment);
+    let open = segment.iter().rposition(|&b| b == b'{')?;
+    let digits = &segment[open + 1..];
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// Accumulates bytes received from the server and returns each response once
+/// it is complete.
+///
+/// Bytes are scanned only once as they arrive, so that a large literal, such
+/// as the script returned by GETSCRIPT, may be received in many small reads
+/// without being reparsed each time. Each response is parsed once, by
+/// [`ResponseKind::parse`], when all of it has arrived.
+///
+/// An error leaves the decoder failed: the offending input stays buffered,
+/// so every later call to [`ResponseDecoder::decode`] fails too. Since the
+/// responses that follow can no longer be told apart, the connection should
+/// be closed.
+#[derive(Debug, Default)]
+pub struct ResponseDecoder {
+    buf: Vec<u8>,
+    scanner: ResponseScanner,
+}
+
+impl ResponseDecoder {
+    pub fn new() -> ResponseDecoder {
+        ResponseDecoder::default()
+    }
+
+    /// Creates a decoder that rejects responses exceeding `limits`.
+    pub fn with_limits(limits: ParserLimits) -> ResponseDecoder {
+        ResponseDecoder {
+            buf: Vec::new(),
+            scanner: ResponseScanner::with_limits(limits),
+        }
+    }
+
+    /// Appends bytes received from the server.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        self.scanner.scan(&self.buf);
+    }
+
+    /// Bytes received but not yet returned as part of a response.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Octets of a literal that are known to be still to come.
+    pub fn pending_literal(&self) -> usize {
+        self.scanner.literal
+    }
+
+    /// Returns the next response, which is expected to be of the given kind,
+    /// or `None` if it has not been completely received.
+    pub fn decode(&mut self, kind: ResponseKind) -> Result<Option<CommandResponse>, Error> {
+        match self.scanner.decode(&self.buf, kind)? {
+            Some((end, response)) => {
+                self.buf.drain(..end);
+                Ok(Some(response))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+// The state of a ResponseDecoder apart from its buffer, so that a buffer
+// held elsewhere, such as the codec's, can be decoded in place.
+#[derive(Debug, Default)]
+pub(crate) struct ResponseScanner {
+    // Next byte to be scanned.
+    pos: usize,
+    // Start of the current line, and of its segment following any literal.
+    line_start: usize,
+    segment_start: usize,
+    // Octets of the current literal not yet received.
+    literal: usize,
+    // End offset of each complete line not yet decoded, and whether it is a
+    // status line.
+    lines: Vec<(usize, bool)>,
+    limits: ParserLimits,
+    // A limit exceeded while scanning, after which nothing more is decoded.
+    exceeded: Option<Limit>,
+}
+
+impl ResponseScanner {
+    pub(crate) fn with_limits(limits: ParserLimits) -> ResponseScanner {
+        ResponseScanner {
+            limits,
+            ..ResponseScanner::default()
+        }
+    }
+
+    // Scans the bytes appended to `buf` since the last call.
+    pub(crate) fn scan(&mut self, buf: &[u8]) {
+        while self.pos < buf.len() && self.exceeded.is_none() {
+            if self.literal > 0 {
+                let n = self.literal.min(buf.len() - self.pos);
+                self.pos += n;
+                self.literal -= n;
+                if self.literal == 0 {
+                    self.segment_start = self.pos;
+                }
+                continue;
+            }
+
+            let nl = match buf[self.pos..].iter().position(|&b| b == b'\n') {
+                Some(i) => self.pos + i,
+                None => {
+                    self.pos = buf.len();
+                    if self.pos - self.segment_start > self.limits.max_line_length {
+                        self.exceeded = Some(Limit::LineLength);
+                    }
+                    break;
+                }
+            };
+            if nl - self.segment_start > self.limits.max_line_length {
+                self.exceeded = Some(Limit::LineLength);
+                break;
+            }
+            self.pos = nl + 1;
+
+            let segment = &buf[self.segment_start..nl];
+            let segment = segment.strip_suffix(b"\r").unwrap_or(segment);
+            match literal_len(segment) {
+                Some(len) if len > self.limits.max_literal_size => {
+                    self.exceeded = Some(Limit::LiteralSize);
+                }
+                Some(len) => {
+                    self.literal = len;
+                    self.segment_start = self.pos;
+                }
+                None => {
+                    let status = is_status(&buf[self.line_start..self.pos]);
+                    self.lines.push((self.pos, status));
+                    self.line_start = self.pos;
+                    self.segment_start = self.pos;
+                }
+            }
+        }
+    }
+
+    // Fails if the lines received so far of an incomplete response already
+    // list more items than permitted.
+    fn check_incomplete(
+        &self,
+        kind: ResponseKind,
+    ) -> Result<Option<(usize, CommandResponse)>, Error> {
+        let (max, limit) = match kind {
+            ResponseKind::ListScripts => (self.limits.max_scripts, Limit::Scripts),
+            ResponseKind::Greeting | ResponseKind::Capability => {
+                (self.limits.max_capabilities, Limit::Capabilities)
+            }
+            _ => return Ok(None),
+        };
+        if self.lines.len() > max {
+            return Err(Error::LimitExceeded(limit));
+        }
+        Ok(None)
+    }
+
+    // Finds the end of the first response of the given kind, if all of its
+    // lines have been received.
+    fn response_end(&self, kind: ResponseKind) -> Option<usize> {
+        self.lines
+            .iter()
+            .find(|(_, status)| *status || kind == ResponseKind::Authenticate)
+            .map(|(end, _)| *end)
+    }
+
+    // Parses the next response in `buf`, if it is complete, returning its
+    // length along with it. The caller must then remove that many bytes
+    // from the start of `buf`.
+    pub(crate) fn decode(
+        &mut self,
+        buf: &[u8],
+        kind: ResponseKind,
+    ) -> Result<Option<(usize, CommandResponse)>, Error> {
+        let end = match (self.response_end(kind), self.exceeded) {
+            (Some(end), _) => end,
+            (None, Some(limit)) => return Err(Error::LimitExceeded(limit)),
+            (None, None) => return self.check_incomplete(kind),
+        };
+
+        let input = from_utf8(&buf[..end])?;
+        let response = match kind.parse_with_limits(input, &self.limits)? {
+            ("", response) => response,
+            (left, _) => {
+                let offset = input.len() - left.len();
+                return Err(Error::InvalidResponse(ParseFailure::new(
+                    input, offset, "response",
+                )));
+            }
+        };
+
+        self.pos -= end;
+        self.line_start -= end;
+        self.segment_start -= end;
+        self.lines.retain(|(e, _)| *e > end);
+        for (e, _) in &mut self.lines {
+            *e -= end;
+        }
+        Ok(Some((end, response)))
+    }
+}
+
+#[test]
+fn test_literal_len() {
+    assert_eq!(literal_len(b"{3}"), Some(3));
+    assert_eq!(literal_len(b"\"TAG\" {12+}"), Some(12));
+    assert_eq!(literal_len(b"{}"), None);
+    assert_eq!(literal_len(b"\"a}\""), None);
+}
+
+#[cfg(test)]
+const FIXTURES: &[(ResponseKind, &str)] = &[
+    (
+        ResponseKind::Greeting,
+        include_str!("test_input/response_capability-1.txt"),
+    ),
+    (
+        ResponseKind::GetScript,
+        include_str!("test_input/response_getscript-1.txt"),
+    ),
+    (
+        ResponseKind::ListScripts,
+        include_str!("test_input/response_listscripts-1.txt"),
+    ),
+    (
+        ResponseKind::StartTls,
+        include_str!("test_input/response_starttls-1.txt"),
+    ),
+    (ResponseKind::Authenticate, "{9}\r\nchallenge\r\nOK\r\n"),
+    (
+        ResponseKind::PutScript,
+        "NO (QUOTA/MAXSIZE) \"Too big\"\r\n",
+    ),
+    (ResponseKind::Noop, "BYE (TRYLATER)\r\n"),
+];
+
+#[test]
+fn test_decoder_split() {
+    for (kind, fixture) in FIXTURES {
+        let expected = kind.parse(fixture).unwrap();
+        let bytes = fixture.as_bytes();
+        let end = fixture.len() - expected.0.len();
+
+        for i in 0..=bytes.len() {
+            let mut decoder = ResponseDecoder::new();
+            decoder.feed(&bytes[..i]);
+            let first = decoder.decode(*kind).unwrap();
+            assert_eq!(first.is_some(), i >= end, "{:?} split at {}", kind, i);
+            decoder.feed(&bytes[i..]);
+            let response = first.or_else(|| decoder.decode(*kind).unwrap());
+            assert_eq!(response.as_ref(), Some(&expected.1));
+            assert_eq!(decoder.buffered(), expected.0.as_bytes());
+        }
+    }
+}
+
+#[test]
+fn test_decoder_bytewise() {
+    for (kind, fixture) in FIXTURES {
+        let (left, expected) = kind.parse(fixture).unwrap();
+        let mut decoder = ResponseDecoder::new();
+        let mut responses = Vec::new();
+        for b in &fixture.as_bytes()[..fixture.len() - left.len()] {
+            decoder.feed(&[*b]);
+            responses.extend(decoder.decode(*kind).unwrap());
+        }
+        assert_eq!(responses, vec![expected]);
+        assert!(decoder.buffered().is_empty());
+    }
+}
+
+#[test]
+fn test_decoder_limits() {
+    let limits = ParserLimits {
+        max_literal_size: 8,
+        max_scripts: 1,
+        max_line_length: 16,
+        ..ParserLimits::default()
+    };
+
+    let mut decoder = ResponseDecoder::with_limits(limits);
+    decoder.feed(b"{7}\r\nkeep;\r\n\r\nOK\r\n{9}\r\n");
+    assert!(decoder.decode(ResponseKind::GetScript).unwrap().is_some());
+    assert_eq!(
+        decoder.decode(ResponseKind::GetScript),
+        Err(Error::LimitExceeded(Limit::LiteralSize))
+    );
+
+    let mut decoder = ResponseDecoder::with_limits(limits);
+    decoder.feed(b"\"a\"\r\n");
+    assert_eq!(decoder.decode(ResponseKind::ListScripts), Ok(None));
+    decoder.feed(b"\"b\"\r\n");
+    assert_eq!(
+        decoder.decode(ResponseKind::ListScripts),
+        Err(Error::LimitExceeded(Limit::Scripts))
+    );
+
+    let mut decoder = ResponseDecoder::with_limits(limits);
+    decoder.feed(b"NO \"0123456789abcdef");
+    assert_eq!(
+        decoder.decode(ResponseKind::Noop),
+        Err(Error::LimitExceeded(Limit::LineLength))
+    );
+}
+
+#[test]
+fn test_decoder_pipelined() {
+    let mut decoder = ResponseDecoder::new();
+    decoder.feed(b"{8}\r\nOK\r\nNO\r\n\r\nOK\r\n\"a\" ACTIVE\r\nOK\r\nOK");
+    assert_eq!(decoder.pending_literal(), 0);
+    assert!(matches!(
+        decoder.decode(ResponseKind::GetScript),
+        Ok(Some(CommandResponse::Script(s, _))) if s == "OK\r\nNO\r\n"
+    ));
+    assert!(matches!(
+        decoder.decode(ResponseKind::ListScripts),
+        Ok(Some(CommandResponse::ScriptList(s, _))) if s.len() == 1
+    ));
+    assert_eq!(decoder.decode(ResponseKind::Noop), Ok(None));
+    decoder.feed(b"\r\n{100}\r\nabc");
+    assert!(decoder.decode(ResponseKind::Noop).unwrap().is_some());
+    assert_eq!(decoder.pending_literal(), 97);
+    assert_eq!(decoder.decode(ResponseKind::GetScript), Ok(None));
+}
+
+#[test]
+fn test_decoder_errors() {
+    let mut decoder = ResponseDecoder::new();
+    decoder.feed(b"OK\n");
+    assert!(matches!(
+        decoder.decode(ResponseKind::Noop),
+        Err(Error::InvalidResponse(_))
+    ));
+    decoder.feed(b"OK\r\n");
+    assert!(decoder.decode(ResponseKind::Noop).is_err());
+}
@@ -0,0 +1,225 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::limits::Limit;
+use crate::parser as p;
+use crate::types::Response;
+
+// Longest snippet of offending input kept in a ParseFailure, in chars.
+const SNIPPET_LEN: usize = 32;
+
+/// Where and why a response failed to parse.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseFailure {
+    /// Byte offset of the offending input from the start of the response.
+    pub offset: usize,
+    /// The input at `offset`, truncated.
+    pub snippet: String,
+    /// The element of the RFC 5804 grammar that could not be parsed, such as
+    /// `"resp-code"` or `"response-listscripts"`.
+    pub element: &'static str,
+}
+
+impl ParseFailure {
+    pub(crate) fn new(input: &str, offset: usize, element: &'static str) -> ParseFailure {
+        let snippet = input
+            .get(offset..)
+            .unwrap_or_default()
+            .chars()
+            .take(SNIPPET_LEN)
+            .collect();
+        ParseFailure {
+            offset,
+            snippet,
+            element,
+        }
+    }
+
+    // Describes a part of `input`, such as a parsed string, that turned out
+    // to be invalid.
+    pub(crate) fn at(input: &str, part: &str, element: &'static str) -> ParseFailure {
+        ParseFailure::new(input, offset_in(input, part), element)
+    }
+}
+
+// The offset of `part` within `input`. Parts not borrowed from `input`, such
+// as strings that were unescaped, are placed at its end.
+pub(crate) fn offset_in(input: &str, part: &str) -> usize {
+    (part.as_ptr() as usize)
+        .checked_sub(input.as_ptr() as usize)
+        .filter(|offset| *offset <= input.len())
+        .unwrap_or(input.len())
+}
+
+// Interprets a response received as bytes, which must be UTF-8.
+pub(crate) fn from_utf8(bytes: &[u8]) -> Result<&str, Error> {
+    std::str::from_utf8(bytes).map_err(|e| {
+        let offset = e.valid_up_to();
+        let end = bytes.len().min(offset + SNIPPET_LEN);
+        Error::InvalidResponse(ParseFailure {
+            offset,
+            snippet: String::from_utf8_lossy(&bytes[offset..end]).into_owned(),
+            element: "UTF-8",
+        })
+    })
+}
+
+impl fmt::Display for ParseFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid {} at byte {} near {:?}",
+            self.element, self.offset, self.snippet
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// More input is needed to complete the response. Holds the number of
+    /// further bytes needed, if known.
+    IncompleteResponse(Option<usize>),
+    /// The server responded in a manner not conforming to RFC 5804.
+    InvalidResponse(ParseFailure),
+    /// The client sent a command not conforming to RFC 5804.
+    InvalidCommand(ParseFailure),
+    /// A value given by the caller was rejected: a script name breaking the
+    /// rules of RFC 5804, a script that is not UTF-8 or not of its announced
+    /// length, malformed SCRAM keys, or a command that cannot be pipelined.
+    InvalidInput,
+    /// The command is not permitted in the current session state, or a
+    /// response arrived that the session was not expecting.
+    InvalidState,
+    /// The server answered NO where the response was expected to carry data,
+    /// for example to GETSCRIPT.
+    Refused(Response),
+    /// The server sent BYE instead of the expected response, and is closing
+    /// the connection.
+    UnsolicitedBye(Response),
+    /// Reading from or writing to a stream failed.
+    Io(io::Error),
+    /// The response exceeded one of the [`ParserLimits`](crate::ParserLimits).
+    LimitExceeded(Limit),
+}
+
+impl Error {
+    /// Converts an error from the parsers, locating it within `input`.
+    pub(crate) fn parse(input: &str, e: nom::Err<p::ParseError<'_>>) -> Error {
+        match e {
+            nom::Err::Incomplete(nom::Needed::Size(n)) => Error::IncompleteResponse(Some(n.get())),
+            nom::Err::Incomplete(nom::Needed::Unknown) => Error::IncompleteResponse(None),
+            nom::Err::Error(e) | nom::Err::Failure(e) => match e.limit {
+                Some(limit) => Error::LimitExceeded(limit),
+                None => Error::InvalidResponse(ParseFailure::at(
+                    input,
+                    e.input,
+                    e.context.unwrap_or("response"),
+                )),
+            },
+        }
+    }
+
+    /// Like [`Error::parse`], for input sent by a client.
+    pub(crate) fn parse_command(input: &str, e: nom::Err<p::ParseError<'_>>) -> Error {
+        match Error::parse(input, e) {
+            Error::InvalidResponse(failure) => Error::InvalidCommand(failure),
+            e => e,
+        }
+    }
+
+    /// Whether more input may complete the response.
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self, Error::IncompleteResponse(_))
+    }
+}
+
+/// I/O errors are compared by their kind.
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Error::IncompleteResponse(a), Error::IncompleteResponse(b)) => a == b,
+            (Error::InvalidResponse(a), Error::InvalidResponse(b)) => a == b,
+            (Error::InvalidCommand(a), Error::InvalidCommand(b)) => a == b,
+            (Error::InvalidInput, Error::InvalidInput) => true,
+            (Error::InvalidState, Error::InvalidState) => true,
+            (Error::Refused(a), Error::Refused(b)) => a == b,
+            (Error::UnsolicitedBye(a), Error::UnsolicitedBye(b)) => a == b,
+            (Error::Io(a), Error::Io(b)) => a.kind() == b.kind(),
+            (Error::LimitExceeded(a), Error::LimitExceeded(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IncompleteResponse(Some(n)) => {
+                write!(f, "incomplete response, {} more bytes needed", n)
+            }
+            Error::IncompleteResponse(None) => write!(f, "incomplete response"),
+            Error::InvalidResponse(failure) | Error::InvalidCommand(failure) => {
+                write!(f, "{}", failure)
+            }
+            Error::InvalidInput => write!(f, "invalid input"),
+            Error::InvalidState => write!(f, "not permitted in the current session state"),
+            Error::Refused(r) => write!(f, "server refused the command: {}", r),
+            Error::UnsolicitedBye(r) => write!(f, "server closed the connection: {}", r),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::LimitExceeded(limit) => write!(f, "response exceeds the {} limit", limit),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[test]
+fn test_error_parse() {
+    let input = "OK (QUOTA/) \"x\"\r\n";
+    let e = crate::response_noop(input).unwrap_err();
+    assert!(matches!(
+        &e,
+        Error::InvalidResponse(ParseFailure {
+            offset: 9,
+            element: "resp-code",
+            ..
+        })
+    ));
+    assert_eq!(
+        e.to_string(),
+        "invalid resp-code at byte 9 near \"/) \\\"x\\\"\\r\\n\""
+    );
+
+    assert!(matches!(
+        crate::response_getscript("{10}\r\nkeep;"),
+        Err(Error::IncompleteResponse(Some(5)))
+    ));
+    assert!(crate::response_noop("OK").unwrap_err().is_incomplete());
+
+    let e: io::Error = Error::LimitExceeded(Limit::Scripts).into();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(e.to_string(), "response exceeds the script count limit");
+}
@@ -0,0 +1,382 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::script::SieveScript;
+use crate::sieve_name::SieveName;
+use crate::store::{ScriptStore, StoreError};
+use crate::types::{QuotaVariant, ScriptEntry};
+
+const EXTENSION: &str = ".sieve";
+
+// Longest file name most filesystems allow, in octets.
+const MAX_FILE_NAME: usize = 255;
+
+// Distinguishes temporary files created by one process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A [`ScriptStore`] over a directory laid out as Dovecot Pigeonhole's
+/// `file` storage: each script is a `<name>.sieve` file, and the active
+/// script is the target of a symlink outside the directory, such as
+/// `~/.dovecot.sieve` pointing into `~/sieve`.
+///
+/// Scripts are written to the `tmp` subdirectory and renamed into place,
+/// and the symlink is replaced by renaming a new one over it, so that
+/// neither is seen half written. In file names, `%`, `/` and a leading `.`
+/// are percent-encoded.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+    active_link: PathBuf,
+    max_scripts: Option<usize>,
+    max_script_size: Option<usize>,
+}
+
+impl FileStore {
+    /// Creates a store for the scripts in `dir`, which is created if
+    /// missing, with the active script linked from `active_link`.
+    pub fn new(dir: impl Into<PathBuf>, active_link: impl Into<PathBuf>) -> io::Result<FileStore> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join("tmp"))?;
+        Ok(FileStore {
+            dir,
+            active_link: active_link.into(),
+            max_scripts: None,
+            max_script_size: None,
+        })
+    }
+
+    /// Refuses to store more than `max` scripts.
+    pub fn with_max_scripts(mut self, max: usize) -> FileStore {
+        self.max_scripts = Some(max);
+        self
+    }
+
+    /// Refuses to store scripts larger than `size` octets.
+    pub fn with_max_script_size(mut self, size: usize) -> FileStore {
+        self.max_script_size = Some(size);
+        self
+    }
+
+    fn path(&self, name: &SieveName) -> Result<PathBuf, StoreError> {
+        let file_name = to_file_name(name);
+        if file_name.len() > MAX_FILE_NAME {
+            return Err(StoreError::Other("Script name too long".to_string()));
+        }
+        Ok(self.dir.join(file_name))
+    }
+
+    fn existing_path(&self, name: &SieveName) -> Result<PathBuf, StoreError> {
+        let path = self.path(name)?;
+        match path.is_file() {
+            true => Ok(path),
+            false => Err(StoreError::Nonexistent),
+        }
+    }
+
+    // The file name of the active script, if the link points into the
+    // directory.
+    fn active_file(&self) -> Result<Option<PathBuf>, StoreError> {
+        let target = match fs::read_link(&self.active_link) {
+            Ok(target) => target,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            // Not a symlink, so no script managed here is active.
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // A relative target is resolved against the directory holding the
+        // link.
+        let parent = match self.active_link.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        let target = parent.join(target);
+        let target_dir = match target.parent().map(fs::canonicalize) {
+            Some(Ok(dir)) => dir,
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => return Ok(None),
+        };
+        match target_dir == fs::canonicalize(&self.dir)? {
+            true => Ok(target.file_name().map(PathBuf::from)),
+            false => Ok(None),
+        }
+    }
+
+    fn is_active(&self, path: &Path) -> Result<bool, StoreError> {
+        Ok(self.active_file()?.as_deref() == path.file_name().map(Path::new))
+    }
+
+    // Whether the active link exists. Anything other than a symlink in its
+    // place was not created here, and is left alone.
+    fn link_exists(&self) -> Result<bool, StoreError> {
+        match fs::symlink_metadata(&self.active_link) {
+            Ok(m) if m.file_type().is_symlink() => Ok(true),
+            Ok(_) => Err(StoreError::Other(
+                "Active script is not managed by this server".to_string(),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Points the active link at `path`, replacing any previous link.
+    fn link(&self, path: &Path) -> Result<(), StoreError> {
+        self.link_exists()?;
+        let parent = self.active_link.parent().unwrap_or_else(|| Path::new(""));
+        let target = path.strip_prefix(parent).unwrap_or(path);
+        let temp = temp_path(&self.active_link);
+        symlink(target, &temp)?;
+        fs::rename(&temp, &self.active_link).map_err(|e| {
+            let _ = fs::remove_file(&temp);
+            e.into()
+        })
+    }
+
+    fn unlink(&self) -> Result<(), StoreError> {
+        if self.link_exists()? {
+            fs::remove_file(&self.active_link)?;
+        }
+        Ok(())
+    }
+
+    fn count(&self) -> Result<usize, StoreError> {
+        Ok(self.names()?.count())
+    }
+
+    fn names(&self) -> Result<impl Iterator<Item = (SieveName, PathBuf)>, StoreError> {
+        Ok(fs::read_dir(&self.dir)?.filter_map(|entry| {
+            let entry = entry.ok()?;
+            if !entry.file_type().ok()?.is_file() {
+                return None;
+            }
+            let name = from_file_name(&entry.file_name())?;
+            Some((name, entry.path()))
+        }))
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+/// The file name holding the script `name`.
+pub(crate) fn to_file_name(name: &SieveName) -> String {
+    let mut file_name = String::with_capacity(name.as_str().len() + EXTENSION.len());
+    for (i, c) in name.as_str().char_indices() {
+        match c {
+            '%' => file_name.push_str("%25"),
+            '/' => file_name.push_str("%2F"),
+            '.' if i == 0 => file_name.push_str("%2E"),
+            c => file_name.push(c),
+        }
+    }
+    file_name.push_str(EXTENSION);
+    file_name
+}
+
+/// The script name held in a file, if the file holds a script.
+pub(crate) fn from_file_name(file_name: &OsStr) -> Option<SieveName> {
+    let encoded = file_name.to_str()?.strip_suffix(EXTENSION)?;
+    let mut name = String::with_capacity(encoded.len());
+    let mut rest = encoded;
+    while let Some(i) = rest.find('%') {
+        name.push_str(&rest[..i]);
+        let c = match rest.get(i + 1..i + 3)? {
+            "25" => '%',
+            "2F" => '/',
+            "2E" if name.is_empty() => '.',
+            _ => return None,
+        };
+        name.push(c);
+        rest = &rest[i + 3..];
+    }
+    name.push_str(rest);
+    // Only names encoded by to_file_name are accepted, so that each name
+    // has one file.
+    let name = SieveName::new(&name).ok()?;
+    match to_file_name(&name) == file_name.to_str()? {
+        true => Some(name),
+        false => None,
+    }
+}
+
+#[test]
+fn test_file_name() {
+    let name = |s| SieveName::new(s).unwrap();
+    assert_eq!(to_file_name(&name("vacation")), "vacation.sieve");
+    assert_eq!(to_file_name(&name("a/b%c")), "a%2Fb%25c.sieve");
+    assert_eq!(to_file_name(&name("..x.y")), "%2E.x.y.sieve");
+    for s in ["vacation", "a/b%c", "..x.y", "%2F"] {
+        let file_name = to_file_name(&name(s));
+        assert_eq!(from_file_name(OsStr::new(&file_name)), Some(name(s)));
+    }
+    for file_name in [".x.sieve", "a%2fb.sieve", "a%.sieve", "a.txt", "a%2E.sieve"] {
+        assert_eq!(from_file_name(OsStr::new(file_name)), None);
+    }
+}
+
+impl ScriptStore for FileStore {
+    fn list(&mut self) -> Result<Vec<ScriptEntry>, StoreError> {
+        let active = self.active_file()?;
+        let mut scripts: Vec<ScriptEntry> = self
+            .names()?
+            .map(|(name, path)| {
+                let is_active = active.as_deref() == path.file_name().map(Path::new);
+                (name, is_active)
+            })
+            .collect();
+        scripts.sort();
+        Ok(scripts)
+    }
+
+    fn get(&mut self, name: &SieveName) -> Result<SieveScript, StoreError> {
+        let path = self.existing_path(name)?;
+        SieveScript::from_utf8(fs::read(path)?)
+            .map_err(|_| StoreError::Other("Script is not UTF-8".to_string()))
+    }
+
+    fn put(&mut self, name: &SieveName, script: &SieveScript) -> Result<(), StoreError> {
+        self.have_space(name, script.len())?;
+        let path = self.path(name)?;
+        let temp = temp_path(
+            &self
+                .dir
+                .join("tmp")
+                .join(path.file_name().unwrap_or_default()),
+        );
+        let written = fs::File::create(&temp).and_then(|mut file| {
+            file.write_all(script.as_str().as_bytes())?;
+            file.sync_all()
+        });
+        match written.and_then(|_| fs::rename(&temp, &path)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let _ = fs::remove_file(&temp);
+                Err(e.into())
+            }
+        }
+    }
+
+    fn delete(&mut self, name: &SieveName) -> Result<(), StoreError> {
+        let path = self.existing_path(name)?;
+        if self.is_active(&path)? {
+            return Err(StoreError::Active);
+        }
+        Ok(fs::remove_file(path)?)
+    }
+
+    fn rename(&mut self, old_name: &SieveName, new_name: &SieveName) -> Result<(), StoreError> {
+        let old_path = self.existing_path(old_name)?;
+        let new_path = self.path(new_name)?;
+        // Linking fails rather than replace an existing script.
+        match fs::hard_link(&old_path, &new_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(StoreError::AlreadyExists)
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if self.is_active(&old_path)? {
+            self.link(&new_path)?;
+        }
+        Ok(fs::remove_file(old_path)?)
+    }
+
+    fn set_active(&mut self, name: Option<&SieveName>) -> Result<(), StoreError> {
+        match name {
+            Some(name) => {
+                let path = self.existing_path(name)?;
+                self.link(&path)
+            }
+            None => self.unlink(),
+        }
+    }
+
+    fn have_space(&mut self, name: &SieveName, size: usize) -> Result<(), StoreError> {
+        if self.max_script_size.is_some_and(|max| size > max) {
+            return Err(StoreError::Quota(QuotaVariant::MaxSize));
+        }
+        if let Some(max) = self.max_scripts {
+            if !self.path(name)?.is_file() && self.count()? >= max {
+                return Err(StoreError::Quota(QuotaVariant::MaxScripts));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_file_store() {
+    let home = tempfile::tempdir().unwrap();
+    let dir = home.path().join("sieve");
+    let link = home.path().join(".dovecot.sieve");
+    let mut store = FileStore::new(&dir, &link)
+        .unwrap()
+        .with_max_scripts(2)
+        .with_max_script_size(20);
+    let name = |s| SieveName::new(s).unwrap();
+    let script = SieveScript::new("keep;\r\n");
+
+    store.put(&name("a/b"), &script).unwrap();
+    store.put(&name("c"), &script).unwrap();
+    assert!(dir.join("a%2Fb.sieve").is_file());
+    assert_eq!(
+        store.put(&name("d"), &script),
+        Err(StoreError::Quota(QuotaVariant::MaxScripts))
+    );
+    assert_eq!(
+        store.put(&name("c"), &SieveScript::new("x".repeat(21).as_str())),
+        Err(StoreError::Quota(QuotaVariant::MaxSize))
+    );
+    assert_eq!(store.get(&name("a/b")).unwrap(), script);
+    assert_eq!(fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+
+    store.set_active(Some(&name("a/b"))).unwrap();
+    assert_eq!(
+        fs::read_link(&link).unwrap(),
+        Path::new("sieve/a%2Fb.sieve")
+    );
+    assert_eq!(store.delete(&name("a/b")), Err(StoreError::Active));
+    assert_eq!(
+        store.rename(&name("a/b"), &name("c")),
+        Err(StoreError::AlreadyExists)
+    );
+    store.rename(&name("a/b"), &name("e")).unwrap();
+    assert_eq!(
+        store.list().unwrap(),
+        vec![(name("c"), false), (name("e"), true)]
+    );
+    assert_eq!(fs::read_to_string(&link).unwrap(), "keep;\r\n");
+
+    assert_eq!(store.get(&name("a/b")), Err(StoreError::Nonexistent));
+    store.set_active(None).unwrap();
+    assert!(fs::symlink_metadata(&link).is_err());
+    store.delete(&name("e")).unwrap();
+    assert_eq!(store.list().unwrap(), vec![(name("c"), false)]);
+
+    // A regular file in place of the link is not replaced.
+    fs::write(&link, "discard;").unwrap();
+    assert_eq!(store.list().unwrap(), vec![(name("c"), false)]);
+    assert!(store.set_active(None).is_err());
+    assert!(store.set_active(Some(&name("c"))).is_err());
+    assert_eq!(fs::read_to_string(&link).unwrap(), "discard;");
+
+    // A link to a script of the same name elsewhere leaves it inactive.
+    let elsewhere = home.path().join("elsewhere");
+    fs::create_dir(&elsewhere).unwrap();
+    fs::write(elsewhere.join("c.sieve"), "discard;").unwrap();
+    fs::remove_file(&link).unwrap();
+    symlink(elsewhere.join("c.sieve"), &link).unwrap();
+    assert_eq!(store.list().unwrap(), vec![(name("c"), false)]);
+    store.delete(&name("c")).unwrap();
+}
@@ -5,19 +5,172 @@
 //!
 //! Commands can be generated in the correct form by constructing a [`Command`]
 //! of the desired type, which can then be converted into a string and sent.
+//! Script names are held as [`SieveName`]s, which can only be constructed
+//! from names valid under RFC 5804, including being in Unicode Normalization
+//! Form C. Script content is held as a [`SieveScript`], which can be
+//! normalized to the CRLF line endings that servers expect.
 //!
 //! Response parsing is achieved by passing bytes received from the managesieve
 //! server to the expected `response_` function. It will either return data and
 //! a [`Response`], `Error::IncompleteResponse` if more bytes are expected to form a
 //! complete response, or `Error::InvalidResponse` if the server has responded
-//! in a nonconforming manner.
+//! in a nonconforming manner. The latter carries a [`ParseFailure`] locating
+//! the offending input. Where a response must carry data, such as the script
+//! returned by GETSCRIPT, a NO response results in `Error::Refused`.
+//!
+//! The server may send BYE at any time before closing the connection. When
+//! a response begins with BYE, `response_` functions return
+//! `Error::UnsolicitedBye` with the server's response code and human-readable
+//! text, whichever response was expected. The greeting sent when the
+//! connection is opened is parsed with [`response_greeting`].
+//!
+//! A server may announce literals, lists and lines of any size. The parsers
+//! reject responses exceeding the [`ParserLimits`] in effect with
+//! `Error::LimitExceeded`, rather than buffer them without bound.
+//!
+//! Functions ending in `_ref`, such as [`response_listscripts_ref`], return
+//! [`ResponseRef`], [`CapabilityRef`] and `Cow` strings borrowed from the
+//! input, allocating only for quoted strings that contain escapes. Their
+//! `into_owned` methods convert them to the types returned by the other
+//! `response_` functions.
+//!
+//! Where the command is only known at run time, as in a proxy,
+//! [`parse_response_for`] parses the response to any [`Command`] into a
+//! [`CommandResponse`].
+//!
+//! A [`Session`] tracks the protocol state of a connection without doing any
+//! IO itself. It rejects commands that are not permitted in the current state
+//! and reports which `response_` function to apply to the next response.
+//!
+//! Where data arrives in chunks, a [`ResponseDecoder`] accumulates it and
+//! returns each response once it is complete, without rescanning earlier
+//! chunks.
+//!
+//! With the `tokio-codec` feature, [`ManageSieveCodec`] provides the same for
+//! use with `tokio_util::codec::Framed`.
 //!
 //! It is possible to pipeline multiple managesieve commands, and receive a
 //! stream of bytes comprising multiple responses. In this case, `response_`
 //! functions return the remaining bytes after successfully parsing the first
-//! response.
+//! response. A [`Pipeline`] sends a batch of commands and matches each
+//! response to the command it answers.
+//!
+//! Scripts returned by GETSCRIPT can be copied to a writer as they arrive,
+//! without holding the whole script in memory, using [`GetScriptStream`] or
+//! [`read_getscript`]. Likewise, [`write_putscript`] and [`PutScriptWriter`]
+//! send PUTSCRIPT with a script of known length supplied in pieces. With
+//! the `async` feature, `write_putscript_async` does so from a tokio reader,
+//! and `Server::serve_async` runs a connection over a tokio stream.
+//!
+//! [`parse_command`] parses commands sent by a client, as a server would.
+//! A [`Server`] answers them, enforcing the session states and carrying out
+//! script commands on a [`ScriptStore`] opened for the authenticated user,
+//! such as a [`MemoryStore`]. Each [`ServerConnection`] performs no IO
+//! itself, but [`Server::serve`] runs one over a stream. On Unix, a
+//! `FileStore` keeps scripts in a directory laid out as Dovecot Pigeonhole
+//! does, with the active script chosen by a symlink. With the `sqlite`
+//! feature, a [`SqliteDatabase`] holds the scripts and quotas of many users,
+//! each opened as a [`SqliteStore`].
+//!
+//! An [`Authenticator`] given to [`Server::with_authenticator`] answers
+//! AUTHENTICATE with the PLAIN mechanism, and with SCRAM-SHA-256 given the
+//! `scram` feature, checking users against a [`CredentialStore`] such as
+//! [`StaticCredentials`]. Other mechanisms implement [`SaslMechanism`].
+//!
+//! The [`sieve`] module reads Sieve scripts (RFC 5228) into a syntax tree
+//! whose elements carry their position in the script, so that a client can
+//! check a script with [`SieveScript::parse`] before sending it.
+//! [`SieveScript::check`] also reports each extension the script requires,
+//! notification method it uses, and redirect it makes beyond those the
+//! server's capabilities allow. [`SieveScript::formatted`] renders a script
+//! in a canonical layout, keeping its comments, so that scripts read back
+//! with GETSCRIPT can be reviewed and compared.
+//!
+//! [`Server::with_validator`] checks scripts sent with PUTSCRIPT and
+//! CHECKSCRIPT with a [`ScriptValidator`], refusing those with errors and
+//! answering `OK (WARNINGS)` to those with warnings. A [`CommandValidator`]
+//! runs a local compiler such as `sievec`, and a [`SyntaxValidator`] checks
+//! scripts with the [`sieve`] parser.
+//!
+//! With the `tls` feature, [`Server::with_tls`] offers STARTTLS, upgrading
+//! the connection with rustls, using a configuration such as one from
+//! [`load_tls_config`]. STARTTLS is announced only until TLS is
+//! established, after which the capabilities are sent again. Mechanisms
+//! sending the password in the clear, such as PLAIN, are not announced until
+//! then, and an [`Authenticator`] may refuse them with `ENCRYPT-NEEDED`. [`Server::serve_async`] upgrades with the `tokio-tls`
+//! feature.
+//!
+//! With the `testing` feature, [`MockServer`] serves tests from scripts held
+//! in memory, over an in-memory [`MockConnection`], any stream, or a
+//! localhost socket. It accepts any AUTHENTICATE, can be told to fail the
+//! next command of a kind, and records a transcript of each [`Exchange`] for
+//! assertions.
+//!
+//! With the `serde` feature, [`Command`], [`CommandResponse`], [`Response`],
+//! [`ResponseCode`], [`QuotaVariant`], [`OkNoBye`] and [`Capability`]
+//! implement `Serialize` and `Deserialize`. Enums use serde's default
+//! externally tagged representation, keyed by variant name, so that
+//! `Command::GetScript` becomes `{"GetScript": "name"}` and
+//! `Command::ListScripts` becomes `"ListScripts"`. A [`Response`] is an object with `tag`, `code` and
+//! `human` fields. [`SieveName`] and [`SieveScript`] are plain strings, and
+//! names are validated when deserialized. These representations are part of
+//! the crate's API. Use `redacted` on a [`Command`] or [`CommandResponse`]
+//! to replace scripts and SASL challenges with a placeholder before logging
+//! them.
 
+#[cfg(feature = "tokio-codec")]
+mod codec;
+mod decoder;
+mod error;
+#[cfg(unix)]
+mod file_store;
+mod limits;
 mod parser;
+mod pipeline;
+mod sasl;
+#[cfg(feature = "scram")]
+mod scram;
+mod script;
+mod server;
+mod session;
+pub mod sieve;
+mod sieve_name;
+#[cfg(feature = "sqlite")]
+mod sqlite_store;
+mod store;
+mod streaming;
+#[cfg(feature = "testing")]
+mod testing;
+#[cfg(feature = "tls")]
+mod tls;
 mod types;
+mod validator;
 
+#[cfg(feature = "tokio-codec")]
+pub use codec::*;
+pub use decoder::*;
+pub use either::Either;
+pub use error::*;
+#[cfg(unix)]
+pub use file_store::*;
+pub use limits::*;
+pub use pipeline::*;
+#[cfg(feature = "tls")]
+pub use rustls;
+pub use sasl::*;
+#[cfg(feature = "scram")]
+pub use scram::ScramKeys;
+pub use script::*;
+pub use server::*;
+pub use session::*;
+pub use sieve_name::*;
+#[cfg(feature = "sqlite")]
+pub use sqlite_store::*;
+pub use store::*;
+pub use streaming::*;
+#[cfg(feature = "testing")]
+pub use testing::*;
+#[cfg(feature = "tls")]
+pub use tls::*;
 pub use types::*;
+pub use validator::*;
@@ -0,0 +1,72 @@
+use std::fmt;
+
+/// Bounds on what the parsers accept from the server, so that a broken or
+/// hostile server cannot make the client buffer or allocate without limit.
+///
+/// The `response_` functions apply the default limits. Others can be given
+/// to [`ResponseKind::parse_with_limits`](crate::ResponseKind::parse_with_limits),
+/// and to the `with_limits` constructors of
+/// [`ResponseDecoder`](crate::ResponseDecoder), [`Pipeline`](crate::Pipeline),
+/// [`GetScriptStream`](crate::GetScriptStream) and `ManageSieveCodec`. A
+/// response exceeding a limit results in `Error::LimitExceeded`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParserLimits {
+    /// Largest literal the server may announce, in octets.
+    pub max_literal_size: usize,
+    /// Most scripts that may be listed in response to LISTSCRIPTS.
+    pub max_scripts: usize,
+    /// Most capability lines that may be listed in one response.
+    pub max_capabilities: usize,
+    /// Longest line that may be received, in octets, not counting any
+    /// literals it contains.
+    pub max_line_length: usize,
+}
+
+impl ParserLimits {
+    /// The default limits: literals of up to 16 MiB, 10,000 scripts, 256
+    /// capabilities and lines of up to 64 KiB.
+    pub const fn new() -> ParserLimits {
+        ParserLimits {
+            max_literal_size: 16 * 1024 * 1024,
+            max_scripts: 10_000,
+            max_capabilities: 256,
+            max_line_length: 64 * 1024,
+        }
+    }
+
+    /// Limits that accept anything, for servers that are trusted.
+    pub const fn unlimited() -> ParserLimits {
+        ParserLimits {
+            max_literal_size: usize::MAX,
+            max_scripts: usize::MAX,
+            max_capabilities: usize::MAX,
+            max_line_length: usize::MAX,
+        }
+    }
+}
+
+impl Default for ParserLimits {
+    fn default() -> Self {
+        ParserLimits::new()
+    }
+}
+
+/// The limit in [`ParserLimits`] that a response exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    LiteralSize,
+    Scripts,
+    Capabilities,
+    LineLength,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::LiteralSize => "literal size",
+            Limit::Scripts => "script count",
+            Limit::Capabilities => "capability count",
+            Limit::LineLength => "line length",
+        })
+    }
+}
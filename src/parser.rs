@@ -1,36 +1,122 @@
 #![allow(dead_code)]
 
+use std::borrow::Cow;
+
 use either::Either;
 use nom::{
     branch::alt,
-    bytes::streaming::{escaped_transform, tag, tag_no_case, take},
-    character::streaming::{crlf, digit1, none_of, space1},
-    combinator::{map, map_res, opt, value},
-    error::{make_error, ErrorKind},
-    multi::{length_data, many0},
+    bytes::streaming::{tag, tag_no_case},
+    character::streaming::{alpha1, crlf, digit1, space1},
+    combinator::{cut, map, map_res, opt, value},
+    error::{context, make_error, ContextError, ErrorKind, FromExternalError},
+    multi::{length_data, many_m_n},
     sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
-    IResult,
+    IResult, Needed,
 };
 
-use crate::types::{OkNoBye, QuotaVariant, Response, ResponseCode, SieveUrl};
+use crate::limits::{Limit, ParserLimits};
+use crate::types::{OkNoBye, QuotaVariant, ResponseCode, ResponseRef, ScriptEntryRef, SieveUrl};
+
+/// A capability name and its optional value, as sent by the server.
+pub type CapabilityLine<'a> = (Cow<'a, str>, Option<Cow<'a, str>>);
+
+/// The error returned by the parsers, which records the innermost grammar
+/// element being parsed, and the limit exceeded if parsing stopped because
+/// of one.
+#[derive(Debug, PartialEq)]
+pub struct ParseError<'a> {
+    pub input: &'a str,
+    pub kind: ErrorKind,
+    pub context: Option<&'static str>,
+    pub limit: Option<Limit>,
+}
+
+impl<'a> nom::error::ParseError<&'a str> for ParseError<'a> {
+    fn from_error_kind(input: &'a str, kind: ErrorKind) -> Self {
+        ParseError {
+            input,
+            kind,
+            context: None,
+            limit: None,
+        }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl<'a> ContextError<&'a str> for ParseError<'a> {
+    fn add_context(_: &'a str, ctx: &'static str, mut other: Self) -> Self {
+        other.context.get_or_insert(ctx);
+        other
+    }
+}
+
+impl<'a, E> FromExternalError<&'a str, E> for ParseError<'a> {
+    fn from_external_error(input: &'a str, kind: ErrorKind, _: E) -> Self {
+        make_error(input, kind)
+    }
+}
+
+pub type PResult<'a, O> = IResult<&'a str, O, ParseError<'a>>;
+
+fn exceeded<T>(input: &str, limit: Limit) -> PResult<'_, T> {
+    Err(nom::Err::Failure(ParseError {
+        input,
+        kind: ErrorKind::TooLarge,
+        context: None,
+        limit: Some(limit),
+    }))
+}
+
+// Like many0, but fails once more than `max` items have been parsed.
+fn many_limited<'a, O, F>(
+    mut f: F,
+    max: usize,
+    limit: Limit,
+) -> impl FnMut(&'a str) -> PResult<'a, Vec<O>>
+where
+    F: FnMut(&'a str) -> PResult<'a, O>,
+{
+    move |mut input| {
+        let mut items = Vec::new();
+        loop {
+            match f(input) {
+                Ok((rest, item)) => {
+                    if items.len() == max {
+                        return exceeded(input, limit);
+                    }
+                    items.push(item);
+                    input = rest;
+                }
+                Err(nom::Err::Error(_)) => return Ok((input, items)),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+const LIMITS: ParserLimits = ParserLimits::new();
 
-pub(crate) fn ok(input: &str) -> IResult<&str, OkNoBye> {
+pub(crate) fn ok(input: &str) -> PResult<'_, OkNoBye> {
     value(OkNoBye::Ok, tag_no_case("OK"))(input)
 }
 
-pub(crate) fn no(input: &str) -> IResult<&str, OkNoBye> {
+pub(crate) fn no(input: &str) -> PResult<'_, OkNoBye> {
     value(OkNoBye::No, tag_no_case("NO"))(input)
 }
 
-pub(crate) fn bye(input: &str) -> IResult<&str, OkNoBye> {
+pub(crate) fn bye(input: &str) -> PResult<'_, OkNoBye> {
     value(OkNoBye::Bye, tag_no_case("BYE"))(input)
 }
 
-pub(crate) fn nobye(input: &str) -> IResult<&str, OkNoBye> {
+pub(crate) fn nobye(input: &str) -> PResult<'_, OkNoBye> {
     alt((no, bye))(input)
 }
 
-fn atom(input: &str) -> IResult<&str, ResponseCode> {
+fn atom(input: &str) -> PResult<'_, ResponseCode> {
     map(
         alt((
             tag("AUTH-TOO-WEAK"),
@@ -71,132 +157,250 @@ fn atom(input: &str) -> IResult<&str, ResponseCode> {
 #[test]
 fn test_atom() {
     assert!(matches!(atom("SASL"), Ok(("", ResponseCode::Sasl))));
-    assert!(matches!(atom("ABCDE"), Err(_)));
+    assert!(atom("ABCDE").is_err());
 }
 
-fn literal_s2c_len(input: &str) -> IResult<&str, usize> {
-    terminated(
-        delimited(
-            tag("{"),
-            map_res(digit1, |s: &str| s.parse::<usize>()),
-            tag("}"),
+pub fn literal_s2c_len<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, usize> {
+    let (rest, len) = context(
+        "literal-s2c",
+        terminated(
+            delimited(
+                tag("{"),
+                map_res(digit1, |s: &str| s.parse::<usize>()),
+                tag("}"),
+            ),
+            crlf,
         ),
-        crlf,
-    )(input)
+    )(input)?;
+    if len > limits.max_literal_size {
+        return exceeded(input, Limit::LiteralSize);
+    }
+    Ok((rest, len))
 }
 
 #[test]
 fn test_literal_s2c_len() {
-    assert!(matches!(literal_s2c_len("{3}\r\n"), Ok(("", 3))));
-    assert!(matches!(literal_s2c_len("{0}\r\n"), Ok(("", 0))));
-    assert!(matches!(literal_s2c_len("{3}"), Err(_)));
-    assert!(matches!(literal_s2c_len("{3}\r\nab"), Ok(("ab", 3))));
+    assert!(matches!(literal_s2c_len("{3}\r\n", &LIMITS), Ok(("", 3))));
+    assert!(matches!(literal_s2c_len("{0}\r\n", &LIMITS), Ok(("", 0))));
+    assert!(literal_s2c_len("{3}", &LIMITS).is_err());
+    assert!(matches!(
+        literal_s2c_len("{3}\r\nab", &LIMITS),
+        Ok(("ab", 3))
+    ));
+    let limits = ParserLimits {
+        max_literal_size: 2,
+        ..LIMITS
+    };
+    assert!(matches!(
+        literal_s2c_len("{3}\r\n", &limits),
+        Err(nom::Err::Failure(ParseError {
+            limit: Some(Limit::LiteralSize),
+            ..
+        }))
+    ));
 }
 
-// Needs to return String because quoted_string does too.
-fn literal_s2c(input: &str) -> IResult<&str, String> {
-    map(length_data(literal_s2c_len), |s| s.to_owned())(input)
+fn literal_s2c<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, Cow<'a, str>> {
+    map(length_data(|i| literal_s2c_len(i, limits)), Cow::Borrowed)(input)
 }
 
 #[test]
 fn test_literal_s2c() {
-    assert_eq!(literal_s2c("{3}\r\nabc").unwrap().1, "abc");
-    assert!(literal_s2c("{4}\r\nabc").is_err());
-    assert!(literal_s2c("{0}\r\n").is_ok());
+    assert_eq!(literal_s2c("{3}\r\nabc", &LIMITS).unwrap().1, "abc");
+    assert!(literal_s2c("{4}\r\nabc", &LIMITS).is_err());
+    assert!(literal_s2c("{0}\r\n", &LIMITS).is_ok());
 }
 
-fn sievestring_s2c(input: &str) -> IResult<&str, String> {
-    alt((literal_s2c, quoted_string))(input)
+fn sievestring_s2c<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, Cow<'a, str>> {
+    context(
+        "string",
+        alt((|i| literal_s2c(i, limits), |i| quoted_string(i, limits))),
+    )(input)
 }
 
 #[test]
 fn test_sievestring_s2c() {
-    assert_eq!(sievestring_s2c("{3}\r\nabc").unwrap().1, "abc");
-    assert_eq!(sievestring_s2c("\"hello\"").unwrap().1, "hello");
-}
-
-fn literal_c2s_len(input: &str) -> IResult<&str, usize> {
-    terminated(
-        delimited(
-            tag("{"),
-            map_res(digit1, |s: &str| s.parse::<usize>()),
-            alt((tag("+}"), tag("}"))),
+    assert_eq!(sievestring_s2c("{3}\r\nabc", &LIMITS).unwrap().1, "abc");
+    assert_eq!(sievestring_s2c("\"hello\"", &LIMITS).unwrap().1, "hello");
+}
+
+fn literal_c2s_len<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, usize> {
+    let (rest, len) = context(
+        "literal-c2s",
+        terminated(
+            delimited(
+                tag("{"),
+                map_res(digit1, |s: &str| s.parse::<usize>()),
+                alt((tag("+}"), tag("}"))),
+            ),
+            crlf,
         ),
-        crlf,
-    )(input)
+    )(input)?;
+    if len > limits.max_literal_size {
+        return exceeded(input, Limit::LiteralSize);
+    }
+    Ok((rest, len))
 }
 
 #[test]
 fn test_literal_c2s_len() {
     test_literal_s2c_len();
-    assert!(matches!(literal_c2s_len("{3+}\r\n"), Ok(("", 3))));
+    assert!(matches!(literal_c2s_len("{3+}\r\n", &LIMITS), Ok(("", 3))));
 }
 
-fn literal_c2s(input: &str) -> IResult<&str, String> {
-    map(length_data(literal_c2s_len), |s| s.to_owned())(input)
+fn literal_c2s<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, Cow<'a, str>> {
+    map(length_data(|i| literal_c2s_len(i, limits)), Cow::Borrowed)(input)
 }
 
 #[test]
 fn test_literal_c2s() {
     test_literal_s2c();
-    assert_eq!(literal_c2s("{3+}\r\nabc").unwrap().1, "abc");
-    assert!(literal_c2s("{4+}\r\nabc").is_err());
+    assert_eq!(literal_c2s("{3+}\r\nabc", &LIMITS).unwrap().1, "abc");
+    assert!(literal_c2s("{4+}\r\nabc", &LIMITS).is_err());
 }
 
-fn sievestring_c2s(input: &str) -> IResult<&str, String> {
-    alt((literal_c2s, quoted_string))(input)
+fn sievestring_c2s<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, Cow<'a, str>> {
+    context(
+        "string",
+        alt((|i| literal_c2s(i, limits), |i| quoted_string(i, limits))),
+    )(input)
 }
 
 #[test]
 fn test_sievestring_c2s() {
-    assert_eq!(sievestring_c2s("{3+}\r\nabc").unwrap().1, "abc");
-    assert_eq!(sievestring_c2s("\"hello\"").unwrap().1, "hello");
+    assert_eq!(sievestring_c2s("{3+}\r\nabc", &LIMITS).unwrap().1, "abc");
+    assert_eq!(sievestring_c2s("\"hello\"", &LIMITS).unwrap().1, "hello");
 }
 
-fn code(input: &str) -> IResult<&str, (ResponseCode, Option<String>)> {
-    delimited(
-        tag("("),
-        pair(atom, opt(preceded(space1, sievestring_s2c))),
-        tag(")"),
+fn code<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> PResult<'a, (ResponseCode, Option<Cow<'a, str>>)> {
+    context(
+        "resp-code",
+        map(
+            delimited(
+                tag("("),
+                cut(pair(
+                    atom,
+                    opt(preceded(space1, |i| sievestring_s2c(i, limits))),
+                )),
+                cut(tag(")")),
+            ),
+            // The referral URL belongs to the response code itself.
+            |(code, s)| match (code, s) {
+                (ResponseCode::Referral(_), Some(url)) => {
+                    (ResponseCode::Referral(url.into_owned()), None)
+                }
+                (code, s) => (code, s),
+            },
+        ),
     )(input)
 }
 
 #[test]
 fn test_code() {
     assert!(matches!(
-        code("(QUOTA)"),
+        code("(QUOTA)", &LIMITS),
         Ok(("", (ResponseCode::Quota(QuotaVariant::None), None)))
     ));
     assert_eq!(
-        code("(TAG {16}\r\nSTARTTLS-SYNC-42)"),
+        code("(TAG {16}\r\nSTARTTLS-SYNC-42)", &LIMITS),
+        Ok((
+            "",
+            (ResponseCode::Tag, Some(Cow::Borrowed("STARTTLS-SYNC-42")))
+        ))
+    );
+    assert_eq!(
+        code("(TAG \"STARTTLS-SYNC-42\")", &LIMITS),
         Ok((
             "",
-            (ResponseCode::Tag, Some("STARTTLS-SYNC-42".to_string()))
+            (ResponseCode::Tag, Some(Cow::Borrowed("STARTTLS-SYNC-42")))
         ))
     );
     assert_eq!(
-        code("(TAG \"STARTTLS-SYNC-42\")"),
+        code("(REFERRAL \"sieve://example.com\")", &LIMITS),
         Ok((
             "",
-            (ResponseCode::Tag, Some("STARTTLS-SYNC-42".to_string()))
+            (
+                ResponseCode::Referral("sieve://example.com".to_string()),
+                None
+            )
         ))
     );
 }
 
-fn quoted_string(input: &str) -> IResult<&str, String> {
-    let one: usize = 1;
-    delimited(
-        tag("\""),
-        escaped_transform(none_of(r#"\""#), '\\', take(one)),
-        tag("\""),
-    )(input)
+// Borrows from the input unless the string contains escapes. A quoted
+// string may not be longer than a line.
+fn quoted_string<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, Cow<'a, str>> {
+    let (input, _) = tag("\"")(input)?;
+    let mut unescaped: Option<String> = None;
+    let mut start = 0;
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '"' && i >= limits.max_line_length {
+            return exceeded(input, Limit::LineLength);
+        }
+        match c {
+            '"' => {
+                let s = match unescaped {
+                    Some(mut s) => {
+                        s.push_str(&input[start..i]);
+                        Cow::Owned(s)
+                    }
+                    None => Cow::Borrowed(&input[..i]),
+                };
+                return Ok((&input[i + 1..], s));
+            }
+            '\\' => {
+                unescaped
+                    .get_or_insert_with(String::new)
+                    .push_str(&input[start..i]);
+                // The escaped character is kept with the text that follows.
+                match chars.next() {
+                    Some((j, _)) => start = j,
+                    None => break,
+                }
+            }
+            _ => {}
+        }
+    }
+    Err(nom::Err::Incomplete(Needed::new(1)))
 }
 
 #[test]
 fn test_quoted_string() {
-    quoted_string("\"hello\"").unwrap();
-    quoted_string("\"\"").unwrap();
-    assert!(quoted_string("hello").is_err());
+    quoted_string("\"hello\"", &LIMITS).unwrap();
+    quoted_string("\"\"", &LIMITS).unwrap();
+    assert!(quoted_string("hello", &LIMITS).is_err());
+    assert!(matches!(
+        quoted_string("\"hello\" ", &LIMITS),
+        Ok((" ", Cow::Borrowed("hello")))
+    ));
+    assert!(matches!(
+        quoted_string("\"a\\\"b\\\\\"", &LIMITS),
+        Ok(("", Cow::Owned(s))) if s == "a\"b\\"
+    ));
+    assert!(matches!(
+        quoted_string("\"ab", &LIMITS),
+        Err(nom::Err::Incomplete(_))
+    ));
+    assert!(matches!(
+        quoted_string("\"ab\\", &LIMITS),
+        Err(nom::Err::Incomplete(_))
+    ));
+    let limits = ParserLimits {
+        max_line_length: 2,
+        ..LIMITS
+    };
+    assert!(quoted_string("\"ab\"", &limits).is_ok());
+    assert!(matches!(
+        quoted_string("\"abc", &limits),
+        Err(nom::Err::Failure(ParseError {
+            limit: Some(Limit::LineLength),
+            ..
+        }))
+    ));
 }
 
 // see section 1.6 of rfc 5804
@@ -209,11 +413,14 @@ pub fn is_bad_sieve_name_char(c: char) -> bool {
     }
 }
 
-pub fn sieve_name_c2s(input: &str) -> IResult<&str, String> {
-    match sievestring_c2s(input) {
+pub fn sieve_name_c2s<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, Cow<'a, str>> {
+    match sievestring_c2s(input, limits) {
         Err(e) => Err(e),
         Ok((rest, s)) => match s.chars().find(|c| is_bad_sieve_name_char(*c)) {
-            Some(_) => Err(nom::Err::Failure(make_error(input, ErrorKind::Char))),
+            Some(_) => Err(nom::Err::Failure(ParseError {
+                context: Some("sieve-name"),
+                ..make_error(input, ErrorKind::Char)
+            })),
             None => Ok((rest, s)),
         },
     }
@@ -221,204 +428,483 @@ pub fn sieve_name_c2s(input: &str) -> IResult<&str, String> {
 
 #[test]
 fn test_sieve_name_c2s() {
-    sieve_name_c2s("\"hello\"").unwrap();
-    sieve_name_c2s("\"hello\u{1337}\"").unwrap();
-    sieve_name_c2s("{3}\r\nabc").unwrap();
+    sieve_name_c2s("\"hello\"", &LIMITS).unwrap();
+    sieve_name_c2s("\"hello\u{1337}\"", &LIMITS).unwrap();
+    sieve_name_c2s("{3}\r\nabc", &LIMITS).unwrap();
     assert!(matches!(
-        sieve_name_c2s("\"he\x1f\""),
+        sieve_name_c2s("\"he\x1f\"", &LIMITS),
         Err(nom::Err::Failure(_))
     ));
-    assert!(matches!(sieve_name_c2s("\"he\" \x1f"), Ok((" \x1f", _))));
+    assert!(matches!(
+        sieve_name_c2s("\"he\" \x1f", &LIMITS),
+        Ok((" \x1f", _))
+    ));
 }
 
-pub fn active_sieve_name(input: &str) -> IResult<&str, Option<String>> {
-    opt(sieve_name_c2s)(input)
+pub fn active_sieve_name<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> PResult<'a, Option<Cow<'a, str>>> {
+    opt(|i| sieve_name_c2s(i, limits))(input)
 }
 
 #[test]
 fn test_active_sieve_name() {
-    assert!(active_sieve_name("hello  ").unwrap().1.is_none());
-    assert!(active_sieve_name("\"hello \" ").unwrap().1.is_some());
-    assert!(active_sieve_name("\"hello\x7f \" ").is_err());
-    assert!(active_sieve_name("\"\"").is_ok());
+    assert!(active_sieve_name("hello  ", &LIMITS).unwrap().1.is_none());
+    assert!(active_sieve_name("\"hello \" ", &LIMITS)
+        .unwrap()
+        .1
+        .is_some());
+    assert!(active_sieve_name("\"hello\x7f \" ", &LIMITS).is_err());
+    assert!(active_sieve_name("\"\"", &LIMITS).is_ok());
     assert!(matches!(
-        active_sieve_name("hello   "),
+        active_sieve_name("hello   ", &LIMITS),
         Ok(("hello   ", None))
     ));
-    assert!(matches!(active_sieve_name("   "), Ok((_, None))));
+    assert!(matches!(active_sieve_name("   ", &LIMITS), Ok((_, None))));
 }
 
-pub fn response_ok(input: &str) -> IResult<&str, Response> {
-    terminated(
-        map(
-            tuple((
-                ok,
-                opt(preceded(space1, code)),
-                opt(preceded(space1, quoted_string)),
-            )),
-            |(_, code, human)| Response {
-                tag: OkNoBye::Ok,
-                code,
-                human,
-            },
+/// An argument to a command sent by the client.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Argument<'a> {
+    String(Cow<'a, str>),
+    Number(usize),
+}
+
+/// A command name and its arguments, as sent by the client.
+pub type CommandLine<'a> = (&'a str, Vec<Argument<'a>>);
+
+fn argument<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, Argument<'a>> {
+    alt((
+        map(|i| sievestring_c2s(i, limits), Argument::String),
+        map_res(digit1, |s: &str| s.parse().map(Argument::Number)),
+    ))(input)
+}
+
+// No command takes more arguments than this.
+const MAX_ARGUMENTS: usize = 2;
+
+pub fn command<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, CommandLine<'a>> {
+    context(
+        "command",
+        terminated(
+            pair(
+                alpha1,
+                many_m_n(0, MAX_ARGUMENTS, preceded(space1, |i| argument(i, limits))),
+            ),
+            crlf,
         ),
-        crlf,
     )(input)
 }
 
-pub fn response_nobye(input: &str) -> IResult<&str, Response> {
-    terminated(
-        map(
-            tuple((
-                nobye,
-                opt(preceded(space1, code)),
-                opt(preceded(space1, quoted_string)),
-            )),
-            |(oknobye, code, human)| Response {
-                tag: oknobye,
-                code,
-                human,
-            },
+/// A client's response to a SASL challenge: a string, which is `"*"` to
+/// abort the exchange.
+pub fn sasl_response<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, Cow<'a, str>> {
+    context(
+        "sasl-response",
+        terminated(|i| sievestring_c2s(i, limits), crlf),
+    )(input)
+}
+
+#[test]
+fn test_sasl_response() {
+    assert_eq!(
+        sasl_response("\"AGEAYg==\"\r\nNOOP", &LIMITS),
+        Ok(("NOOP", Cow::Borrowed("AGEAYg==")))
+    );
+    assert_eq!(
+        sasl_response("{1+}\r\n*\r\n", &LIMITS),
+        Ok(("", Cow::Borrowed("*")))
+    );
+    assert!(sasl_response("NOOP\r\n", &LIMITS).is_err());
+}
+
+#[test]
+fn test_command() {
+    assert_eq!(
+        command("HAVESPACE \"a\" 10\r\n", &LIMITS),
+        Ok((
+            "",
+            (
+                "HAVESPACE",
+                vec![Argument::String(Cow::Borrowed("a")), Argument::Number(10)]
+            )
+        ))
+    );
+    assert_eq!(
+        command("putscript \"a\" {5+}\r\nkeep;\r\nNOOP", &LIMITS).unwrap(),
+        (
+            "NOOP",
+            (
+                "putscript",
+                vec![
+                    Argument::String(Cow::Borrowed("a")),
+                    Argument::String(Cow::Borrowed("keep;"))
+                ]
+            )
+        )
+    );
+    assert!(matches!(
+        command("NOOP", &LIMITS),
+        Err(nom::Err::Incomplete(_))
+    ));
+    assert!(command("GETSCRIPT a\r\n", &LIMITS).is_err());
+    assert!(command("NOOP 1 2 3\r\n", &LIMITS).is_err());
+}
+
+pub fn response_ok<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, ResponseRef<'a>> {
+    context(
+        "response-oknobye",
+        terminated(
+            map(
+                tuple((
+                    ok,
+                    opt(preceded(space1, |i| code(i, limits))),
+                    opt(preceded(space1, |i| sievestring_s2c(i, limits))),
+                )),
+                |(_, code, human)| ResponseRef {
+                    tag: OkNoBye::Ok,
+                    code,
+                    human,
+                },
+            ),
+            crlf,
         ),
-        crlf,
     )(input)
 }
 
-pub fn response(input: &str) -> IResult<&str, Response> {
-    alt((response_ok, response_nobye))(input)
+pub fn response_nobye<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, ResponseRef<'a>> {
+    context(
+        "response-oknobye",
+        terminated(
+            map(
+                tuple((
+                    nobye,
+                    opt(preceded(space1, |i| code(i, limits))),
+                    opt(preceded(space1, |i| sievestring_s2c(i, limits))),
+                )),
+                |(oknobye, code, human)| ResponseRef {
+                    tag: oknobye,
+                    code,
+                    human,
+                },
+            ),
+            crlf,
+        ),
+    )(input)
+}
+
+pub fn response_bye<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, ResponseRef<'a>> {
+    context(
+        "response-oknobye",
+        terminated(
+            map(
+                tuple((
+                    bye,
+                    opt(preceded(space1, |i| code(i, limits))),
+                    opt(preceded(space1, |i| sievestring_s2c(i, limits))),
+                )),
+                |(tag, code, human)| ResponseRef { tag, code, human },
+            ),
+            crlf,
+        ),
+    )(input)
+}
+
+#[test]
+fn test_response_bye() {
+    response_bye("BYE\r\n", &LIMITS).unwrap();
+    response_bye("bye (TRYLATER) \"later\"\r\n", &LIMITS).unwrap();
+    assert!(response_bye("NO\r\n", &LIMITS).is_err());
+    assert!(matches!(
+        response_bye("BY", &LIMITS),
+        Err(nom::Err::Incomplete(_))
+    ));
+}
+
+pub fn response<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, ResponseRef<'a>> {
+    context(
+        "response-oknobye",
+        alt((|i| response_ok(i, limits), |i| response_nobye(i, limits))),
+    )(input)
 }
 
 #[test]
 fn test_response() {
-    response("ok\r\n").unwrap();
-    response("nO\r\n").unwrap();
-    response("BYE\r\n").unwrap();
-    response("ok (QUOTA)\r\n").unwrap();
-    response("ok (QUOTA) \"hello\"\r\n").unwrap();
-    assert!(matches!(response("ok"), Err(_)));
-    assert!(matches!(response(" ok\r\n"), Err(_)));
-    assert!(matches!(response("ok (\r\n"), Err(_)));
-    assert!(matches!(response("ok (QUOTA\r\n"), Err(_)));
-    assert!(matches!(response("ok (QUOTA/)\r\n"), Err(_)));
-}
-
-pub fn response_getscript(input: &str) -> IResult<&str, (Option<String>, Response)> {
-    alt((
-        map(
-            separated_pair(sievestring_s2c, crlf, response_ok),
-            |(s, r)| (Some(s), r),
-        ),
-        map(response_nobye, |r| (None, r)),
-    ))(input)
+    response("ok\r\n", &LIMITS).unwrap();
+    response("nO\r\n", &LIMITS).unwrap();
+    response("BYE\r\n", &LIMITS).unwrap();
+    response("ok (QUOTA)\r\n", &LIMITS).unwrap();
+    response("ok (QUOTA) \"hello\"\r\n", &LIMITS).unwrap();
+    let (_, r) = response("NO {3}\r\na\r\n\r\n", &LIMITS).unwrap();
+    assert_eq!(r.human.as_deref(), Some("a\r\n"));
+    assert!(response("ok", &LIMITS).is_err());
+    assert!(response(" ok\r\n", &LIMITS).is_err());
+    assert!(response("ok (\r\n", &LIMITS).is_err());
+    assert!(response("ok (QUOTA\r\n", &LIMITS).is_err());
+    assert!(response("ok (QUOTA/)\r\n", &LIMITS).is_err());
+}
+
+pub fn response_getscript<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> PResult<'a, (Option<Cow<'a, str>>, ResponseRef<'a>)> {
+    context(
+        "response-getscript",
+        alt((
+            map(
+                separated_pair(
+                    |i| sievestring_s2c(i, limits),
+                    crlf,
+                    |i| response_ok(i, limits),
+                ),
+                |(s, r)| (Some(s), r),
+            ),
+            map(|i| response_nobye(i, limits), |r| (None, r)),
+        )),
+    )(input)
 }
 
 #[test]
 fn test_response_getscript() {
-    response_getscript("\"hello\"\r\nOK\r\n").unwrap();
-    response_getscript("NO\r\n").unwrap();
-    assert!(matches!(response_getscript("\"hello\"\r\nBYE\r\n"), Err(_)));
+    response_getscript("\"hello\"\r\nOK\r\n", &LIMITS).unwrap();
+    response_getscript("NO\r\n", &LIMITS).unwrap();
+    assert!(response_getscript("\"hello\"\r\nBYE\r\n", &LIMITS).is_err());
+}
+
+/// The remainder of the response to GETSCRIPT after the script's literal.
+pub fn response_getscript_trailer<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> PResult<'a, ResponseRef<'a>> {
+    context(
+        "response-getscript",
+        preceded(crlf, |i| response(i, limits)),
+    )(input)
 }
 
-pub fn response_listscripts(input: &str) -> IResult<&str, (Vec<(String, bool)>, Response)> {
-    pair(
-        many0(terminated(
-            pair(
-                sievestring_s2c,
-                map(opt(pair(space1, tag_no_case("ACTIVE"))), |o| o.is_some()),
+#[test]
+fn test_response_getscript_trailer() {
+    response_getscript_trailer("\r\nOK\r\n", &LIMITS).unwrap();
+    assert!(response_getscript_trailer("OK\r\n", &LIMITS).is_err());
+}
+
+pub fn response_listscripts<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> PResult<'a, (Vec<ScriptEntryRef<'a>>, ResponseRef<'a>)> {
+    context(
+        "response-listscripts",
+        pair(
+            many_limited(
+                terminated(
+                    pair(
+                        |i| sievestring_s2c(i, limits),
+                        map(opt(pair(space1, tag_no_case("ACTIVE"))), |o| o.is_some()),
+                    ),
+                    crlf,
+                ),
+                limits.max_scripts,
+                Limit::Scripts,
             ),
-            crlf,
-        )),
-        response,
+            |i| response(i, limits),
+        ),
     )(input)
 }
 
 #[test]
 fn test_response_listscripts() {
-    response_listscripts("\"script1\"\r\n\"script2\"\r\nOK\r\n").unwrap();
-    response_listscripts("\"script1\" ACTIVE\r\n\"script2\"\r\nOK\r\n").unwrap();
-    response_listscripts("\"script1\" active\r\n\"script2\"\r\nOK\r\n").unwrap();
-    response_listscripts("OK\r\n").unwrap();
-    response_listscripts("BYE\r\n").unwrap();
+    response_listscripts("\"script1\"\r\n\"script2\"\r\nOK\r\n", &LIMITS).unwrap();
+    response_listscripts("\"script1\" ACTIVE\r\n\"script2\"\r\nOK\r\n", &LIMITS).unwrap();
+    response_listscripts("\"script1\" active\r\n\"script2\"\r\nOK\r\n", &LIMITS).unwrap();
+    response_listscripts("OK\r\n", &LIMITS).unwrap();
+    response_listscripts("BYE\r\n", &LIMITS).unwrap();
+
+    let limits = ParserLimits {
+        max_scripts: 1,
+        ..LIMITS
+    };
+    response_listscripts("\"script1\"\r\nOK\r\n", &limits).unwrap();
+    assert!(matches!(
+        response_listscripts("\"script1\"\r\n\"script2\"\r\n", &limits),
+        Err(nom::Err::Failure(ParseError {
+            limit: Some(Limit::Scripts),
+            ..
+        }))
+    ));
 }
 
-fn single_capability(input: &str) -> IResult<&str, (String, Option<String>)> {
-    terminated(
-        pair(sievestring_s2c, opt(preceded(space1, sievestring_s2c))),
-        crlf,
+fn single_capability<'a>(input: &'a str, limits: &ParserLimits) -> PResult<'a, CapabilityLine<'a>> {
+    context(
+        "single-capability",
+        terminated(
+            pair(
+                |i| sievestring_s2c(i, limits),
+                opt(preceded(space1, |i| sievestring_s2c(i, limits))),
+            ),
+            crlf,
+        ),
     )(input)
 }
 
 #[test]
 fn test_single_capability() {
-    single_capability("\"CAPABILITY1\"\r\n").unwrap();
-    single_capability("\"CAPABILITY2\" \"a b c d e\"\r\n").unwrap();
-    assert!(single_capability("\"CAPABILITY2\" \r\n").is_err());
-}
-
-pub fn response_capability(
-    input: &str,
-) -> IResult<&str, (Vec<(String, Option<String>)>, Response)> {
-    pair(many0(single_capability), response)(input)
+    single_capability("\"CAPABILITY1\"\r\n", &LIMITS).unwrap();
+    single_capability("\"CAPABILITY2\" \"a b c d e\"\r\n", &LIMITS).unwrap();
+    assert!(single_capability("\"CAPABILITY2\" \r\n", &LIMITS).is_err());
+}
+
+pub fn response_capability<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> PResult<'a, (Vec<CapabilityLine<'a>>, ResponseRef<'a>)> {
+    context(
+        "response-capability",
+        pair(
+            many_limited(
+                |i| single_capability(i, limits),
+                limits.max_capabilities,
+                Limit::Capabilities,
+            ),
+            |i| response(i, limits),
+        ),
+    )(input)
 }
 
 #[test]
 fn test_response_capability() {
-    response_capability("\"CAPABILITY1\"\r\n\"CAPABILITY2\"\r\nOK\r\n").unwrap();
+    response_capability("\"CAPABILITY1\"\r\n\"CAPABILITY2\"\r\nOK\r\n", &LIMITS).unwrap();
 }
 
 #[test]
 fn test_response_capability_2() {
     let inc1 = include_str!("test_input/response_capability-1.txt");
-    response_capability(inc1).unwrap();
+    response_capability(inc1, &LIMITS).unwrap();
+
+    let limits = ParserLimits {
+        max_capabilities: 5,
+        ..LIMITS
+    };
+    assert!(matches!(
+        response_capability(inc1, &limits),
+        Err(nom::Err::Failure(ParseError {
+            limit: Some(Limit::Capabilities),
+            ..
+        }))
+    ));
 }
 
-pub fn response_starttls(input: &str) -> IResult<&str, (Vec<(String, Option<String>)>, Response)> {
-    alt((
-        preceded(response_ok, response_capability),
-        map(response_nobye, |r| (Vec::new(), r)),
-    ))(input)
+pub fn response_starttls<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> PResult<'a, (Vec<CapabilityLine<'a>>, ResponseRef<'a>)> {
+    context(
+        "response-starttls",
+        alt((
+            preceded(
+                |i| response_ok(i, limits),
+                |i| response_capability(i, limits),
+            ),
+            map(|i| response_nobye(i, limits), |r| (Vec::new(), r)),
+        )),
+    )(input)
 }
 
 #[test]
 fn test_response_starttls() {
-    response_starttls("OK\r\n\"CAPABILITY1\"\r\n\"CAPABILITY2\"\r\nOK\r\n").unwrap();
-    response_starttls("BYE\r\n").unwrap();
+    response_starttls(
+        "OK\r\n\"CAPABILITY1\"\r\n\"CAPABILITY2\"\r\nOK\r\n",
+        &LIMITS,
+    )
+    .unwrap();
+    response_starttls("BYE\r\n", &LIMITS).unwrap();
 }
 
 /// Server responds to authenticate with either a challenge or a oknobye
 /// response.
-pub fn response_authenticate_initial(input: &str) -> IResult<&str, Either<String, Response>> {
-    alt((
-        map(terminated(sievestring_s2c, crlf), |s| Either::Left(s)),
-        map(response_nobye, |r| Either::Right(r)),
-    ))(input)
+pub fn response_authenticate_initial<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> PResult<'a, Either<Cow<'a, str>, ResponseRef<'a>>> {
+    context(
+        "response-authenticate",
+        alt((
+            map(
+                terminated(|i| sievestring_s2c(i, limits), crlf),
+                Either::Left,
+            ),
+            map(|i| response_nobye(i, limits), Either::Right),
+        )),
+    )(input)
 }
 
 #[test]
 fn test_response_authenticate_initial() {
-    response_authenticate_initial("{4}\r\nabcd\r\n").unwrap();
-    response_authenticate_initial("BYE\r\n").unwrap();
+    response_authenticate_initial("{4}\r\nabcd\r\n", &LIMITS).unwrap();
+    response_authenticate_initial("BYE\r\n", &LIMITS).unwrap();
+}
+
+/// Server responds to authenticate, and to each client response, with either
+/// a further challenge or the final oknobye response.
+pub fn response_authenticate<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> PResult<'a, Either<Cow<'a, str>, ResponseRef<'a>>> {
+    context(
+        "response-authenticate",
+        alt((
+            map(
+                terminated(|i| sievestring_s2c(i, limits), crlf),
+                Either::Left,
+            ),
+            map(|i| response(i, limits), Either::Right),
+        )),
+    )(input)
+}
+
+#[test]
+fn test_response_authenticate() {
+    assert_eq!(
+        response_authenticate("\"cmVhbG0=\"\r\n", &LIMITS),
+        Ok(("", Either::Left(Cow::Borrowed("cmVhbG0="))))
+    );
+    assert!(matches!(
+        response_authenticate("OK (SASL \"cnNwYXV0aA==\")\r\n", &LIMITS),
+        Ok(("", Either::Right(_)))
+    ));
+    response_authenticate("NO \"Bad password\"\r\n", &LIMITS).unwrap();
 }
 
 /// Server responds to client response with oknobye and can also include new
 /// capabilities if OK.
-pub fn response_authenticate_complete(
-    input: &str,
-) -> IResult<&str, (Option<Vec<(String, Option<String>)>>, Response)> {
-    alt((
-        map(
-            pair(response_ok, opt(response_capability)),
-            |(a, b)| match b {
-                None => (None, a),
-                Some((s, r)) => (Some(s), r),
-            },
-        ),
-        map(response_nobye, |r| (None, r)),
-    ))(input)
+pub fn response_authenticate_complete<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> PResult<'a, (Option<Vec<CapabilityLine<'a>>>, ResponseRef<'a>)> {
+    context(
+        "response-authenticate",
+        alt((
+            map(
+                pair(
+                    |i| response_ok(i, limits),
+                    opt(|i| response_capability(i, limits)),
+                ),
+                |(a, b)| match b {
+                    None => (None, a),
+                    Some((s, r)) => (Some(s), r),
+                },
+            ),
+            map(|i| response_nobye(i, limits), |r| (None, r)),
+        )),
+    )(input)
 }
 
 #[test]
 fn test_response_authenticate_complete() {
-    response_authenticate_complete("OK\r\n\"CAPABILITY1\"\r\n\"CAPABILITY2\"\r\nOK\r\n").unwrap();
-    response_authenticate_complete("BYE\r\n").unwrap();
+    response_authenticate_complete(
+        "OK\r\n\"CAPABILITY1\"\r\n\"CAPABILITY2\"\r\nOK\r\n",
+        &LIMITS,
+    )
+    .unwrap();
+    response_authenticate_complete("BYE\r\n", &LIMITS).unwrap();
 }
@@ -0,0 +1,227 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use crate::decoder::ResponseDecoder;
+use crate::error::Error;
+use crate::limits::ParserLimits;
+use crate::session::ResponseKind;
+use crate::types::{Command, CommandResponse};
+
+/// Sends a batch of commands without waiting for each response, and matches
+/// the responses to their commands in order.
+///
+/// Commands are added with [`Pipeline::push`] and rendered together by
+/// [`Pipeline::take_batch`]. Bytes received from the server are passed to
+/// [`Pipeline::feed`], after which [`Pipeline::next_response`] returns each
+/// command with its response. If the server sends BYE, the commands after
+/// the one it answered remain unanswered and no further responses are
+/// returned.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    unsent: Vec<Command>,
+    pending: VecDeque<Command>,
+    decoder: ResponseDecoder,
+    closed: bool,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// Creates a pipeline that rejects responses exceeding `limits`.
+    pub fn with_limits(limits: ParserLimits) -> Pipeline {
+        Pipeline {
+            decoder: ResponseDecoder::with_limits(limits),
+            ..Pipeline::default()
+        }
+    }
+
+    /// Adds a command to the next batch. AUTHENTICATE and STARTTLS cannot be
+    /// pipelined, since further exchanges depend on their responses.
+    pub fn push(&mut self, command: Command) -> Result<(), Error> {
+        match command {
+            Command::Authenticate(..) | Command::StartTls => Err(Error::InvalidInput),
+            command => {
+                self.unsent.push(command);
+                Ok(())
+            }
+        }
+    }
+
+    /// Renders the commands added since the last batch, to be written to the
+    /// server at once.
+    pub fn take_batch(&mut self) -> String {
+        let batch = self.unsent.iter().map(|c| c.to_string()).collect();
+        self.pending.extend(self.unsent.drain(..));
+        batch
+    }
+
+    /// Appends bytes received from the server.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.decoder.feed(data);
+    }
+
+    /// Returns the oldest unanswered command together with its response, or
+    /// `None` if that response has not been completely received.
+    pub fn next_response(&mut self) -> Result<Option<(Command, CommandResponse)>, Error> {
+        if self.closed {
+            return Ok(None);
+        }
+        let kind = match self.pending.front() {
+            Some(command) => ResponseKind::from(command),
+            None => return Ok(None),
+        };
+        match self.decoder.decode(kind)? {
+            Some(response) => {
+                self.closed = matches!(response, CommandResponse::Bye(_));
+                let command = self.pending.pop_front().unwrap();
+                Ok(Some((command, response)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the server has sent BYE.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Commands sent but not yet answered.
+    pub fn unanswered(&self) -> impl Iterator<Item = &Command> {
+        self.pending.iter()
+    }
+
+    /// Writes the pending batch to `stream` and reads until every command has
+    /// been answered or the server has sent BYE. Returns each command with
+    /// its response.
+    pub fn run<S: Read + Write>(
+        &mut self,
+        stream: &mut S,
+    ) -> io::Result<Vec<(Command, CommandResponse)>> {
+        stream.write_all(self.take_batch().as_bytes())?;
+        stream.flush()?;
+
+        let mut responses = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            while let Some(response) = self.next_response()? {
+                responses.push(response);
+            }
+            if self.closed || self.pending.is_empty() {
+                return Ok(responses);
+            }
+            match stream.read(&mut buf)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => self.feed(&buf[..n]),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+struct MockStream {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+#[cfg(test)]
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Return one byte at a time to exercise reassembly.
+        self.input.read(&mut buf[..1])
+    }
+}
+
+#[cfg(test)]
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pipeline() {
+    let mut pipeline = Pipeline::new();
+    pipeline.push(Command::ListScripts).unwrap();
+    pipeline.push(Command::get_script("a").unwrap()).unwrap();
+    pipeline.push(Command::noop()).unwrap();
+    assert_eq!(pipeline.push(Command::StartTls), Err(Error::InvalidInput));
+    assert_eq!(
+        pipeline.take_batch(),
+        "LISTSCRIPTS\r\nGETSCRIPT \"a\"\r\nNOOP\r\n"
+    );
+    assert_eq!(pipeline.take_batch(), "");
+
+    pipeline.feed(b"\"a\" ACTIVE\r\nOK\r\nNO (NONEXISTENT)\r\nO");
+    assert!(matches!(
+        pipeline.next_response(),
+        Ok(Some((
+            Command::ListScripts,
+            CommandResponse::ScriptList(_, _)
+        )))
+    ));
+    assert!(matches!(
+        pipeline.next_response(),
+        Ok(Some((Command::GetScript(_), CommandResponse::No(_))))
+    ));
+    assert_eq!(pipeline.next_response(), Ok(None));
+    pipeline.feed(b"K\r\n");
+    assert!(matches!(
+        pipeline.next_response(),
+        Ok(Some((Command::Noop(None), CommandResponse::Ok(_))))
+    ));
+    assert_eq!(pipeline.next_response(), Ok(None));
+    assert_eq!(pipeline.unanswered().count(), 0);
+}
+
+#[test]
+fn test_pipeline_run() {
+    let mut input = include_bytes!("test_input/response_getscript-1.txt").to_vec();
+    input.extend_from_slice(b"BYE (TRYLATER) \"Shutting down\"\r\n");
+    let mut stream = MockStream {
+        input: io::Cursor::new(input),
+        output: Vec::new(),
+    };
+
+    let mut pipeline = Pipeline::new();
+    pipeline.push(Command::get_script("a").unwrap()).unwrap();
+    pipeline.push(Command::ListScripts).unwrap();
+    pipeline.push(Command::Logout).unwrap();
+    let responses = pipeline.run(&mut stream).unwrap();
+
+    assert_eq!(
+        stream.output,
+        b"GETSCRIPT \"a\"\r\nLISTSCRIPTS\r\nLOGOUT\r\n"
+    );
+    assert_eq!(responses.len(), 2);
+    assert!(matches!(responses[0].1, CommandResponse::Script(_, _)));
+    assert!(matches!(
+        responses[1],
+        (Command::ListScripts, CommandResponse::Bye(_))
+    ));
+    assert!(pipeline.is_closed());
+    assert_eq!(
+        pipeline.unanswered().collect::<Vec<_>>(),
+        vec![&Command::Logout]
+    );
+}
+
+#[test]
+fn test_pipeline_eof() {
+    let mut stream = MockStream {
+        input: io::Cursor::new(b"OK\r\n".to_vec()),
+        output: Vec::new(),
+    };
+    let mut pipeline = Pipeline::new();
+    pipeline.push(Command::noop()).unwrap();
+    pipeline.push(Command::noop()).unwrap();
+    assert_eq!(
+        pipeline.run(&mut stream).unwrap_err().kind(),
+        io::ErrorKind::UnexpectedEof
+    );
+}
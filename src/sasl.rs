@@ -0,0 +1,429 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+#[cfg(feature = "scram")]
+use crate::scram::{ScramKeys, ScramSha256};
+use crate::types::{OkNoBye, Response, ResponseCode};
+
+/// The outcome of a step of a server-side SASL exchange.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SaslStep {
+    /// Send this challenge and wait for the client's response.
+    Challenge(Vec<u8>),
+    /// The client is authenticated, and authorized to act as `user`. Any
+    /// `data` is sent in the OK response's SASL response code.
+    Success { user: String, data: Option<Vec<u8>> },
+    /// Authentication failed, and is reported with this NO response.
+    Failure(Response),
+}
+
+/// The server side of a SASL mechanism, for a single exchange.
+pub trait SaslMechanism: Send {
+    /// Processes the client's next response, decoded from base64. The
+    /// first is the initial response sent with AUTHENTICATE, or `None` if
+    /// there was none.
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep;
+}
+
+/// The secret against which a user's authentication is checked.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credential {
+    /// A password in plain text, usable with any mechanism.
+    Password(String),
+    /// Keys derived from a password, usable with SCRAM-SHA-256 and PLAIN.
+    #[cfg(feature = "scram")]
+    ScramSha256(ScramKeys),
+}
+
+impl Credential {
+    /// Whether `password` is the password this credential was made from.
+    pub fn verify_password(&self, password: &str) -> bool {
+        match self {
+            Credential::Password(p) => ct_eq(p.as_bytes(), password.as_bytes()),
+            #[cfg(feature = "scram")]
+            Credential::ScramSha256(keys) => keys.verify_password(password),
+        }
+    }
+}
+
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credential::Password(_) => f.write_str("Password(..)"),
+            #[cfg(feature = "scram")]
+            Credential::ScramSha256(_) => f.write_str("ScramSha256(..)"),
+        }
+    }
+}
+
+// Compares secrets in time independent of where they differ.
+pub(crate) fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Looks up the users a server's SASL mechanisms authenticate.
+pub trait CredentialStore: Send + Sync {
+    /// The credential of `user`, or `None` if there is no such user.
+    fn credential(&self, user: &str) -> Option<Credential>;
+
+    /// Whether `user` may authenticate with `mechanism`. If not,
+    /// authentication fails with `AUTH-TOO-WEAK`. Allows any by default.
+    fn allows_mechanism(&self, _user: &str, _mechanism: &str) -> bool {
+        true
+    }
+
+    /// Whether `user`, once authenticated, may act as `authzid`. Only as
+    /// themselves by default.
+    fn may_authorize(&self, user: &str, authzid: &str) -> bool {
+        user == authzid
+    }
+}
+
+/// A [`CredentialStore`] of `user:credential` lines, in the manner of a
+/// Dovecot passwd-file.
+///
+/// The credential is `{PLAIN}` followed by a password, `{SCRAM-SHA-256}`
+/// followed by `ScramKeys` with the `scram` feature, or
+/// a bare password. Any further `:`-separated fields are ignored, as are
+/// blank lines and lines starting with `#`.
+#[derive(Debug, Clone, Default)]
+pub struct StaticCredentials {
+    users: HashMap<String, Credential>,
+    proxies: HashSet<String>,
+}
+
+impl StaticCredentials {
+    pub fn new() -> StaticCredentials {
+        StaticCredentials::default()
+    }
+
+    /// Reads the credentials in the file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<StaticCredentials> {
+        StaticCredentials::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Reads credentials from `text`, failing with
+    /// `io::ErrorKind::InvalidData` at the first malformed line.
+    pub fn parse(text: &str) -> io::Result<StaticCredentials> {
+        let mut credentials = StaticCredentials::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", i + 1, reason),
+                )
+            };
+            let mut fields = line.split(':');
+            let user = fields.next().unwrap_or_default();
+            let secret = fields.next().ok_or_else(|| invalid("no credential"))?;
+            if user.is_empty() {
+                return Err(invalid("no user"));
+            }
+            let credential = if let Some(password) = secret.strip_prefix("{PLAIN}") {
+                Credential::Password(password.to_owned())
+            } else if let Some(keys) = secret.strip_prefix("{SCRAM-SHA-256}") {
+                scram_credential(keys)
+                    .ok_or_else(|| invalid("invalid or unsupported SCRAM keys"))?
+            } else if secret.starts_with('{') {
+                return Err(invalid("unknown scheme"));
+            } else {
+                Credential::Password(secret.to_owned())
+            };
+            credentials.users.insert(user.to_owned(), credential);
+        }
+        Ok(credentials)
+    }
+
+    /// Adds or replaces a user.
+    pub fn with_user(mut self, user: &str, credential: Credential) -> StaticCredentials {
+        self.users.insert(user.to_owned(), credential);
+        self
+    }
+
+    /// Allows `user` to act as any other user, as an administrator might.
+    pub fn with_proxy(mut self, user: &str) -> StaticCredentials {
+        self.proxies.insert(user.to_owned());
+        self
+    }
+}
+
+#[cfg(feature = "scram")]
+fn scram_credential(keys: &str) -> Option<Credential> {
+    keys.parse().ok().map(Credential::ScramSha256)
+}
+
+#[cfg(not(feature = "scram"))]
+fn scram_credential(_keys: &str) -> Option<Credential> {
+    None
+}
+
+impl CredentialStore for StaticCredentials {
+    fn credential(&self, user: &str) -> Option<Credential> {
+        self.users.get(user).cloned()
+    }
+
+    fn may_authorize(&self, user: &str, authzid: &str) -> bool {
+        user == authzid || self.proxies.contains(user)
+    }
+}
+
+/// The SASL mechanisms a [`Server`](crate::Server) offers, and the
+/// credentials they check.
+#[derive(Clone)]
+pub struct Authenticator {
+    credentials: Arc<dyn CredentialStore>,
+    mechanisms: Vec<String>,
+    plaintext_requires_encryption: bool,
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authenticator")
+            .field("mechanisms", &self.mechanisms)
+            .field(
+                "plaintext_requires_encryption",
+                &self.plaintext_requires_encryption,
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl Authenticator {
+    /// Names of the mechanisms implemented here, strongest first.
+    pub const MECHANISMS: &'static [&'static str] = &[
+        #[cfg(feature = "scram")]
+        "SCRAM-SHA-256",
+        "PLAIN",
+    ];
+
+    /// Offers all of [`Authenticator::MECHANISMS`], checked against
+    /// `credentials`.
+    pub fn new(credentials: impl CredentialStore + 'static) -> Authenticator {
+        Authenticator {
+            credentials: Arc::new(credentials),
+            mechanisms: Authenticator::MECHANISMS
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            plaintext_requires_encryption: false,
+        }
+    }
+
+    /// Offers only those of `mechanisms` that are implemented, in the order
+    /// given.
+    pub fn with_mechanisms(mut self, mechanisms: &[&str]) -> Authenticator {
+        self.mechanisms = mechanisms
+            .iter()
+            .filter(|m| Authenticator::MECHANISMS.contains(m))
+            .map(|m| m.to_string())
+            .collect();
+        self
+    }
+
+    /// Refuses mechanisms that send the password in the clear, such as
+    /// PLAIN, with `ENCRYPT-NEEDED` unless the connection is encrypted.
+    pub fn with_plaintext_requires_encryption(mut self, required: bool) -> Authenticator {
+        self.plaintext_requires_encryption = required;
+        self
+    }
+
+    pub fn mechanisms(&self) -> &[String] {
+        &self.mechanisms
+    }
+
+    /// The mechanisms usable on a connection, leaving out those that would
+    /// be refused with `ENCRYPT-NEEDED` unless it is `encrypted`.
+    pub fn mechanisms_for(&self, encrypted: bool) -> Vec<String> {
+        self.mechanisms
+            .iter()
+            .filter(|m| {
+                encrypted || !self.plaintext_requires_encryption || !Authenticator::is_plaintext(m)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Whether `mechanism` sends the password in the clear.
+    pub fn is_plaintext(mechanism: &str) -> bool {
+        mechanism.eq_ignore_ascii_case("PLAIN")
+    }
+
+    /// Starts an exchange using `mechanism`, or returns the NO response
+    /// refusing it.
+    pub fn start(
+        &self,
+        mechanism: &str,
+        encrypted: bool,
+    ) -> Result<Box<dyn SaslMechanism>, Response> {
+        let mechanism = self
+            .mechanisms
+            .iter()
+            .find(|m| m.eq_ignore_ascii_case(mechanism))
+            .ok_or_else(|| no(None, "Unsupported SASL mechanism"))?;
+        if Authenticator::is_plaintext(mechanism)
+            && self.plaintext_requires_encryption
+            && !encrypted
+        {
+            return Err(no(Some(ResponseCode::EncryptNeeded), "Encryption required"));
+        }
+        let credentials = self.credentials.clone();
+        match mechanism.as_str() {
+            #[cfg(feature = "scram")]
+            "SCRAM-SHA-256" => Ok(Box::new(ScramSha256::new(credentials))),
+            _ => Ok(Box::new(Plain { credentials })),
+        }
+    }
+}
+
+pub(crate) fn no(code: Option<ResponseCode>, human: &str) -> Response {
+    Response {
+        tag: OkNoBye::No,
+        code: code.map(|code| (code, None)),
+        human: Some(human.to_owned()),
+    }
+}
+
+pub(crate) fn failed() -> SaslStep {
+    SaslStep::Failure(no(None, "Authentication failed"))
+}
+
+// Completes an exchange once `user` has proven their identity, checking
+// their policy and their authorization to act as `authzid`.
+pub(crate) fn authorize(
+    credentials: &dyn CredentialStore,
+    mechanism: &str,
+    user: &str,
+    authzid: Option<&str>,
+    data: Option<Vec<u8>>,
+) -> SaslStep {
+    if !credentials.allows_mechanism(user, mechanism) {
+        return SaslStep::Failure(no(
+            Some(ResponseCode::AuthTooWeak),
+            "Mechanism not permitted for this user",
+        ));
+    }
+    let authzid = authzid.filter(|a| !a.is_empty()).unwrap_or(user);
+    if !credentials.may_authorize(user, authzid) {
+        return SaslStep::Failure(no(None, "Not authorized"));
+    }
+    SaslStep::Success {
+        user: authzid.to_owned(),
+        data,
+    }
+}
+
+/// PLAIN, from RFC 4616.
+struct Plain {
+    credentials: Arc<dyn CredentialStore>,
+}
+
+impl SaslMechanism for Plain {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+        let response = match response {
+            Some(response) => response,
+            None => return SaslStep::Challenge(Vec::new()),
+        };
+        let message = match std::str::from_utf8(response) {
+            Ok(message) => message,
+            Err(_) => return failed(),
+        };
+        let (authzid, user, password) = match message.split('\0').collect::<Vec<_>>()[..] {
+            [authzid, user, password] => (authzid, user, password),
+            _ => return failed(),
+        };
+        match self.credentials.credential(user) {
+            Some(credential) if credential.verify_password(password) => {
+                authorize(&*self.credentials, "PLAIN", user, Some(authzid), None)
+            }
+            _ => failed(),
+        }
+    }
+}
+
+#[test]
+fn test_plain() {
+    let credentials = StaticCredentials::parse(
+        "# users\n\
+         alice:{PLAIN}secret:1000\n\
+         \n\
+         admin:hunter2\n",
+    )
+    .unwrap()
+    .with_proxy("admin");
+    let authenticator = Authenticator::new(credentials).with_plaintext_requires_encryption(true);
+    assert!(authenticator.mechanisms().contains(&"PLAIN".to_string()));
+
+    assert_eq!(
+        authenticator
+            .start("PLAIN", false)
+            .err()
+            .unwrap()
+            .to_string(),
+        "NO (ENCRYPT-NEEDED) \"Encryption required\""
+    );
+    assert!(authenticator.start("CRAM-MD5", true).is_err());
+
+    let step = |response: &[u8]| {
+        authenticator
+            .start("plain", true)
+            .unwrap()
+            .step(Some(response))
+    };
+    assert_eq!(
+        step(b"\0alice\0secret"),
+        SaslStep::Success {
+            user: "alice".to_string(),
+            data: None
+        }
+    );
+    assert_eq!(step(b"\0alice\0wrong"), failed());
+    assert_eq!(step(b"\0bob\0secret"), failed());
+    assert_eq!(step(b"alice"), failed());
+    assert_eq!(
+        step(b"admin\0alice\0secret"),
+        SaslStep::Failure(no(None, "Not authorized"))
+    );
+    assert_eq!(
+        step(b"alice\0admin\0hunter2"),
+        SaslStep::Success {
+            user: "alice".to_string(),
+            data: None
+        }
+    );
+
+    let mut plain = authenticator.start("PLAIN", true).unwrap();
+    assert_eq!(plain.step(None), SaslStep::Challenge(Vec::new()));
+
+    assert!(StaticCredentials::parse("alice").is_err());
+    assert!(StaticCredentials::parse("alice:{MD5}abc").is_err());
+}
+
+#[test]
+fn test_auth_too_weak() {
+    struct ScramOnly;
+
+    impl CredentialStore for ScramOnly {
+        fn credential(&self, _user: &str) -> Option<Credential> {
+            Some(Credential::Password("secret".to_string()))
+        }
+
+        fn allows_mechanism(&self, _user: &str, mechanism: &str) -> bool {
+            mechanism != "PLAIN"
+        }
+    }
+
+    let mut plain = Authenticator::new(ScramOnly).start("PLAIN", true).unwrap();
+    match plain.step(Some(b"\0alice\0secret")) {
+        SaslStep::Failure(response) => {
+            assert_eq!(response.code, Some((ResponseCode::AuthTooWeak, None)))
+        }
+        step => panic!("{:?}", step),
+    }
+}
@@ -0,0 +1,396 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::sasl::{
+    authorize, ct_eq, failed, no, Credential, CredentialStore, SaslMechanism, SaslStep,
+};
+use crate::types::ResponseCode;
+
+const MECHANISM: &str = "SCRAM-SHA-256";
+
+/// Iterations used for keys derived from a plain text password, as RFC 7677
+/// recommends at least.
+const ITERATIONS: u32 = 4096;
+
+/// The keys from which a server checks SCRAM-SHA-256 authentication, as
+/// RFC 5802 describes, without holding the password itself.
+///
+/// They are written, and parsed, in Dovecot's form: the iteration count,
+/// then the salt, stored key and server key in base64, separated by commas.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ScramKeys {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub stored_key: [u8; 32],
+    pub server_key: [u8; 32],
+}
+
+impl ScramKeys {
+    /// Derives the keys for `password`, which is used as given rather than
+    /// prepared with SASLprep.
+    pub fn derive(password: &str, salt: &[u8], iterations: u32) -> ScramKeys {
+        let mut salted_password = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut salted_password);
+        let client_key = hmac(&salted_password, b"Client Key");
+        ScramKeys {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: Sha256::digest(client_key).into(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        }
+    }
+
+    /// Whether the keys were derived from `password`.
+    pub fn verify_password(&self, password: &str) -> bool {
+        let keys = ScramKeys::derive(password, &self.salt, self.iterations);
+        ct_eq(&keys.stored_key, &self.stored_key)
+    }
+}
+
+impl fmt::Debug for ScramKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScramKeys")
+            .field("iterations", &self.iterations)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for ScramKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.iterations,
+            BASE64.encode(&self.salt),
+            BASE64.encode(self.stored_key),
+            BASE64.encode(self.server_key)
+        )
+    }
+}
+
+impl FromStr for ScramKeys {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = |s: &str| -> Result<[u8; 32], Error> {
+            let bytes = BASE64.decode(s).map_err(|_| Error::InvalidInput)?;
+            bytes.try_into().map_err(|_| Error::InvalidInput)
+        };
+        match s.split(',').collect::<Vec<_>>()[..] {
+            [iterations, salt, stored_key, server_key] => Ok(ScramKeys {
+                iterations: iterations.parse().map_err(|_| Error::InvalidInput)?,
+                salt: BASE64.decode(salt).map_err(|_| Error::InvalidInput)?,
+                stored_key: key(stored_key)?,
+                server_key: key(server_key)?,
+            }),
+            _ => Err(Error::InvalidInput),
+        }
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+fn random(len: usize) -> Option<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    getrandom::fill(&mut bytes).ok()?;
+    Some(bytes)
+}
+
+// A salt for `user` that stays the same for the life of the process but
+// cannot be predicted, keyed by a random secret. It is given to users with
+// a plain text password and to unknown users alike, so that the challenge
+// does not reveal whether a user exists.
+fn stable_salt(user: &str) -> Option<Vec<u8>> {
+    static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+    let secret = match SECRET.get() {
+        Some(secret) => secret,
+        None => {
+            let secret = random(32)?;
+            SECRET.get_or_init(|| secret)
+        }
+    };
+    Some(hmac(secret, user.as_bytes())[..16].to_vec())
+}
+
+// The value of the attribute `name` at the start of `attrs`, and the
+// attributes after it.
+fn attribute<'a>(attrs: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let rest = attrs.strip_prefix(name)?.strip_prefix('=')?;
+    Some(rest.split_once(',').unwrap_or((rest, "")))
+}
+
+// Decodes a saslname, in which `,` and `=` are escaped as `=2C` and `=3D`.
+fn saslname(s: &str) -> Option<String> {
+    let mut name = String::with_capacity(s.len());
+    let mut parts = s.split('=');
+    name.push_str(parts.next()?);
+    for part in parts {
+        match part.get(..2)? {
+            "2C" => name.push(','),
+            "3D" => name.push('='),
+            _ => return None,
+        }
+        name.push_str(&part[2..]);
+    }
+    Some(name)
+}
+
+// What the server remembers between its first message and the client's
+// final message.
+struct Challenged {
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+    user: String,
+    authzid: Option<String>,
+    keys: ScramKeys,
+}
+
+enum State {
+    Initial,
+    Challenged(Box<Challenged>),
+    Done,
+}
+
+/// SCRAM-SHA-256, from RFC 7677, without channel binding.
+pub(crate) struct ScramSha256 {
+    credentials: Arc<dyn CredentialStore>,
+    state: State,
+    // Fixes the server's part of the nonce, for tests.
+    server_nonce: Option<String>,
+}
+
+impl ScramSha256 {
+    pub(crate) fn new(credentials: Arc<dyn CredentialStore>) -> ScramSha256 {
+        ScramSha256 {
+            credentials,
+            state: State::Initial,
+            server_nonce: None,
+        }
+    }
+
+    fn client_first(&mut self, message: &str) -> SaslStep {
+        // gs2-header: a channel binding flag and an optional authzid.
+        let (authzid, client_first_bare) = match message
+            .strip_prefix("n,")
+            .or_else(|| message.strip_prefix("y,"))
+            .and_then(|rest| rest.split_once(','))
+        {
+            Some(("", bare)) => (None, bare),
+            Some((authzid, bare)) => match authzid.strip_prefix("a=").and_then(saslname) {
+                Some(authzid) => (Some(authzid), bare),
+                None => return failed(),
+            },
+            None => return failed(),
+        };
+        let gs2_header = &message[..message.len() - client_first_bare.len()];
+        let parsed = attribute(client_first_bare, "n").and_then(|(user, rest)| {
+            let (client_nonce, _) = attribute(rest, "r")?;
+            Some((saslname(user)?, client_nonce))
+        });
+        let (user, client_nonce) = match parsed {
+            Some((user, nonce)) if !nonce.is_empty() => (user, nonce),
+            _ => return failed(),
+        };
+
+        let keys = match (self.credentials.credential(&user), stable_salt(&user)) {
+            (Some(Credential::ScramSha256(keys)), _) => keys,
+            (_, None) => return SaslStep::Failure(try_later()),
+            (Some(Credential::Password(password)), Some(salt)) => {
+                ScramKeys::derive(&password, &salt, ITERATIONS)
+            }
+            // An unknown user is challenged as any other, and fails at the
+            // proof, as RFC 5802 section 5.1 suggests. No client key hashes
+            // to the zero stored key.
+            (None, Some(salt)) => ScramKeys {
+                iterations: ITERATIONS,
+                salt,
+                stored_key: [0; 32],
+                server_key: [0; 32],
+            },
+        };
+        let server_nonce = match self.server_nonce.take() {
+            Some(nonce) => nonce,
+            None => match random(18) {
+                Some(bytes) => BASE64.encode(bytes),
+                None => return SaslStep::Failure(try_later()),
+            },
+        };
+        let nonce = format!("{}{}", client_nonce, server_nonce);
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            BASE64.encode(&keys.salt),
+            keys.iterations
+        );
+        let challenge = server_first.clone().into_bytes();
+        self.state = State::Challenged(Box::new(Challenged {
+            gs2_header: gs2_header.to_owned(),
+            client_first_bare: client_first_bare.to_owned(),
+            server_first,
+            nonce,
+            user,
+            authzid,
+            keys,
+        }));
+        SaslStep::Challenge(challenge)
+    }
+}
+
+fn try_later() -> crate::types::Response {
+    no(Some(ResponseCode::TryLater), "No randomness available")
+}
+
+impl SaslMechanism for ScramSha256 {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+        let message = match response.map(std::str::from_utf8) {
+            // The client sends its first message after an empty challenge.
+            None => return SaslStep::Challenge(Vec::new()),
+            Some(Ok(message)) => message,
+            Some(Err(_)) => return failed(),
+        };
+        let challenged = match std::mem::replace(&mut self.state, State::Done) {
+            State::Initial => return self.client_first(message),
+            State::Challenged(challenged) => challenged,
+            State::Done => return failed(),
+        };
+        let Challenged {
+            gs2_header,
+            client_first_bare,
+            server_first,
+            nonce,
+            user,
+            authzid,
+            keys,
+        } = *challenged;
+
+        // client-final-message: channel binding, nonce, then the proof.
+        let (without_proof, proof) = match message.rsplit_once(",p=") {
+            Some(parts) => parts,
+            None => return failed(),
+        };
+        let channel_binding = attribute(without_proof, "c");
+        let client_nonce = channel_binding.and_then(|(_, rest)| attribute(rest, "r"));
+        let proof = BASE64.decode(proof).ok();
+        match (channel_binding, client_nonce, proof) {
+            (Some((c, _)), Some((r, _)), Some(proof))
+                if c == BASE64.encode(&gs2_header) && r == nonce && proof.len() == 32 =>
+            {
+                let auth_message =
+                    format!("{},{},{}", client_first_bare, server_first, without_proof);
+                let client_signature = hmac(&keys.stored_key, auth_message.as_bytes());
+                let client_key: Vec<u8> = proof
+                    .iter()
+                    .zip(client_signature)
+                    .map(|(p, s)| p ^ s)
+                    .collect();
+                if !ct_eq(&Sha256::digest(client_key), &keys.stored_key) {
+                    return failed();
+                }
+                let server_signature = hmac(&keys.server_key, auth_message.as_bytes());
+                let data = format!("v={}", BASE64.encode(server_signature));
+                authorize(
+                    &*self.credentials,
+                    MECHANISM,
+                    &user,
+                    authzid.as_deref(),
+                    Some(data.into_bytes()),
+                )
+            }
+            _ => failed(),
+        }
+    }
+}
+
+#[test]
+fn test_scram_keys() {
+    let keys = ScramKeys::derive("pencil", b"salt", 4096);
+    assert!(keys.verify_password("pencil"));
+    assert!(!keys.verify_password("pen"));
+    assert_eq!(keys.to_string().parse::<ScramKeys>(), Ok(keys));
+    assert!("4096,c2FsdA==,AA==,AA==".parse::<ScramKeys>().is_err());
+}
+
+#[test]
+fn test_scram_sha256() {
+    use crate::sasl::StaticCredentials;
+
+    // The example exchange from RFC 7677.
+    let salt = BASE64.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+    let keys = ScramKeys::derive("pencil", &salt, 4096);
+    let credentials = StaticCredentials::new()
+        .with_user("user", Credential::ScramSha256(keys))
+        .with_user("other", Credential::Password("pencil".to_string()));
+    let credentials: Arc<dyn CredentialStore> = Arc::new(credentials);
+    let mut scram = ScramSha256::new(credentials.clone());
+    scram.server_nonce = Some("%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_string());
+
+    assert_eq!(scram.step(None), SaslStep::Challenge(Vec::new()));
+    assert_eq!(
+        scram.step(Some(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO")),
+        SaslStep::Challenge(
+            b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+              s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+                .to_vec()
+        )
+    );
+    let client_final = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                        p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+    assert_eq!(
+        scram.step(Some(client_final.as_bytes())),
+        SaslStep::Success {
+            user: "user".to_string(),
+            data: Some(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=".to_vec())
+        }
+    );
+    assert_eq!(scram.step(Some(b"")), failed());
+
+    // A wrong proof, and a nonce not the server's.
+    let mut scram = ScramSha256::new(credentials.clone());
+    scram.server_nonce = Some("%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_string());
+    scram.step(Some(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO"));
+    let wrong = client_final.replace("p=dH", "p=dG");
+    assert_eq!(scram.step(Some(wrong.as_bytes())), failed());
+    let mut scram = ScramSha256::new(credentials.clone());
+    scram.step(Some(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO"));
+    assert_eq!(scram.step(Some(client_final.as_bytes())), failed());
+
+    // A plain text password, and an unknown user, are given a salt that
+    // stays the same, and authorization to act as another user is checked.
+    let challenge = |user: &str| {
+        let mut scram = ScramSha256::new(credentials.clone());
+        scram.server_nonce = Some("xyz".to_string());
+        match scram.step(Some(format!("n,a=user,n={},r=abc", user).as_bytes())) {
+            SaslStep::Challenge(challenge) => (scram, String::from_utf8(challenge).unwrap()),
+            step => panic!("{:?}", step),
+        }
+    };
+    let (_, first) = challenge("other");
+    assert!(first.starts_with("r=abcxyz,s=") && first.ends_with(",i=4096"));
+    assert_eq!(challenge("other").1, first);
+    let (mut scram, unknown) = challenge("nobody");
+    assert_ne!(unknown, first);
+    assert_eq!(challenge("nobody").1, unknown);
+    let proof = format!(
+        "c={},r=abcxyz,p={}",
+        BASE64.encode("n,a=user,"),
+        BASE64.encode([0; 32])
+    );
+    assert_eq!(scram.step(Some(proof.as_bytes())), failed());
+    let mut scram = ScramSha256::new(credentials);
+    assert_eq!(scram.step(Some(b"p=tls-unique,,n=user,r=abc")), failed());
+    assert_eq!(saslname("a=2Cb=3D"), Some("a,b=".to_string()));
+    assert_eq!(saslname("a=b"), None);
+}
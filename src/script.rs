@@ -0,0 +1,182 @@
+use std::fmt;
+
+use crate::error::Error;
+use crate::sieve;
+use crate::types::Capability;
+use crate::validator::Diagnostic;
+
+const BOM: char = '\u{feff}';
+
+/// The content of a Sieve script, as sent with PUTSCRIPT and CHECKSCRIPT.
+///
+/// Scripts are sent as given unless [`SieveScript::normalized`] is applied.
+/// [`SieveScript::len`] is the exact size in octets announced in the literal
+/// and used for HAVESPACE.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct SieveScript(String);
+
+impl SieveScript {
+    pub fn new(text: &str) -> SieveScript {
+        SieveScript(text.to_owned())
+    }
+
+    /// Interprets `bytes` as a script. Fails with `Error::InvalidInput` if
+    /// they are not UTF-8.
+    pub fn from_utf8(bytes: Vec<u8>) -> Result<SieveScript, Error> {
+        String::from_utf8(bytes)
+            .map(SieveScript)
+            .map_err(|_| Error::InvalidInput)
+    }
+
+    /// Strips a leading byte order mark and converts bare CR and LF line
+    /// endings to CRLF, as RFC 5228 requires.
+    pub fn normalized(self) -> SieveScript {
+        let text = self.0.strip_prefix(BOM).unwrap_or(&self.0);
+        let mut out = String::with_capacity(text.len() + text.len() / 32);
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\r' => {
+                    chars.next_if_eq(&'\n');
+                    out.push_str("\r\n");
+                }
+                '\n' => out.push_str("\r\n"),
+                c => out.push(c),
+            }
+        }
+        SieveScript(out)
+    }
+
+    /// Reads the script into a syntax tree, checking its syntax as a
+    /// server would before storing it.
+    pub fn parse(&self) -> Result<sieve::Script, sieve::SyntaxError> {
+        sieve::parse(&self.0)
+    }
+
+    /// The script formatted canonically, as by displaying the
+    /// [`sieve::Script`] it parses to, so that scripts can be compared
+    /// before they are sent with PUTSCRIPT.
+    pub fn formatted(&self) -> Result<SieveScript, sieve::SyntaxError> {
+        Ok(SieveScript(self.parse()?.to_string()))
+    }
+
+    /// Checks the script against the capabilities announced by a server,
+    /// as with [`sieve::check`], before it is sent with PUTSCRIPT. A
+    /// syntax error is the only diagnostic if the script cannot be read.
+    pub fn check(&self, capabilities: &[Capability]) -> Vec<Diagnostic> {
+        match self.parse() {
+            Ok(script) => sieve::check(&script, capabilities),
+            Err(e) => vec![e.into()],
+        }
+    }
+
+    /// The size of the script in octets.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+#[test]
+fn test_sieve_script() {
+    let script = SieveScript::new("\u{feff}keep;\nstop;\r\r\n");
+    assert_eq!(script.len(), 17);
+    let script = script.normalized();
+    assert_eq!(script.as_str(), "keep;\r\nstop;\r\n\r\n");
+    assert_eq!(script.len(), 16);
+    assert_eq!(script.clone().normalized(), script);
+
+    assert_eq!(
+        SieveScript::from_utf8(b"keep;".to_vec()).unwrap(),
+        SieveScript::new("keep;")
+    );
+    assert_eq!(
+        SieveScript::from_utf8(b"\xffkeep;".to_vec()),
+        Err(Error::InvalidInput)
+    );
+}
+
+#[test]
+fn test_sieve_script_parse() {
+    let script = SieveScript::new("keep;\r\nstop;\r\n").parse().unwrap();
+    let names: Vec<_> = script.commands.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, ["keep", "stop"]);
+    assert_eq!(
+        SieveScript::new("keep").parse().unwrap_err().to_string(),
+        "line 1, column 5: expected ';' or '{'"
+    );
+}
+
+#[test]
+fn test_sieve_script_check() {
+    let capabilities = [Capability::Sieve(vec!["fileinto".to_string()])];
+    let script = SieveScript::new("require [\"fileinto\", \"editheader\"];\r\n");
+    assert_eq!(
+        script.check(&capabilities)[0].to_string(),
+        "line 1, column 22: extension \"editheader\" is not supported by the server"
+    );
+    assert!(SieveScript::new("require \"fileinto\";")
+        .check(&capabilities)
+        .is_empty());
+    let diagnostics = SieveScript::new("keep").check(&capabilities);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "line 1, column 5: expected ';' or '{'"
+    );
+}
+
+#[test]
+fn test_sieve_script_formatted() {
+    assert_eq!(
+        SieveScript::new("if true{keep;}").formatted().unwrap(),
+        SieveScript::new("if true {\r\n    keep;\r\n}\r\n")
+    );
+    assert!(SieveScript::new("if true {").formatted().is_err());
+}
+
+impl fmt::Display for SieveScript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for SieveScript {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for SieveScript {
+    fn from(text: &str) -> Self {
+        SieveScript::new(text)
+    }
+}
+
+impl From<String> for SieveScript {
+    fn from(text: String) -> Self {
+        SieveScript(text)
+    }
+}
+
+impl From<SieveScript> for String {
+    fn from(script: SieveScript) -> Self {
+        script.0
+    }
+}
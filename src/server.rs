@@ -0,0 +1,1269 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::decoder::literal_len;
+use crate::error::Error;
+use crate::limits::{Limit, ParserLimits};
+use crate::parser as p;
+use crate::sasl::{Authenticator, SaslMechanism, SaslStep};
+use crate::session::SessionState;
+use crate::store::{ScriptStore, StoreError};
+use crate::types::{
+    parse_command_with_limits, to_qs, Capability, Command, OkNoBye, Response, ResponseCode,
+};
+use crate::validator::{check_script, ScriptValidator};
+
+/// How the start of the input received from a client is to be handled.
+pub(crate) enum Received<T> {
+    /// More input is needed to complete the command.
+    Incomplete,
+    /// A command, or other input expected, of this many octets.
+    Complete(usize, T),
+    /// Invalid input of this many octets, and the NO reply to it.
+    Invalid(usize, String),
+    /// Input from which the connection cannot recover, and the BYE reply
+    /// closing it.
+    Fatal(String),
+}
+
+pub(crate) fn next_command(input: &mut CommandReader, limits: &ParserLimits) -> Received<Command> {
+    input.next(limits, parse_command_with_limits)
+}
+
+// The client's response to a SASL challenge.
+fn next_sasl_response(input: &mut CommandReader, limits: &ParserLimits) -> Received<String> {
+    input.next(limits, |text, limits| {
+        let (left, response) =
+            p::sasl_response(text, limits).map_err(|e| Error::parse_command(text, e))?;
+        Ok((left, response.into_owned()))
+    })
+}
+
+/// Accumulates input from a client until a command is complete.
+///
+/// Like [`ResponseDecoder`](crate::ResponseDecoder), it scans bytes only
+/// once as they arrive, following the literals announced, and parses each
+/// command once, when all of it has been received.
+#[derive(Debug, Default)]
+pub(crate) struct CommandReader {
+    buf: Vec<u8>,
+    // Next byte to be scanned, and the start of the current segment of the
+    // command following any literal.
+    pos: usize,
+    segment_start: usize,
+    // Octets of the current literal not yet received.
+    literal: usize,
+    // The rest of a rejected command, including its literals, is being
+    // discarded as it arrives.
+    discarding: bool,
+}
+
+impl CommandReader {
+    pub(crate) fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Input received but not yet consumed.
+    pub(crate) fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Discards the input received so far.
+    pub(crate) fn clear(&mut self) {
+        *self = CommandReader::default();
+    }
+
+    /// Removes the first `len` octets, as given by [`CommandReader::next`].
+    pub(crate) fn consume(&mut self, len: usize) {
+        let len = len.min(self.buf.len());
+        self.buf.drain(..len);
+        self.pos = self.pos.saturating_sub(len);
+        self.segment_start = self.segment_start.saturating_sub(len);
+    }
+
+    /// Parses the next command, or other input such as a SASL response,
+    /// with `parse` once all of it has been received.
+    pub(crate) fn next<T>(
+        &mut self,
+        limits: &ParserLimits,
+        parse: impl for<'a> Fn(&'a str, &ParserLimits) -> Result<(&'a str, T), Error>,
+    ) -> Received<T> {
+        let too_long = || Received::Fatal(reply(OkNoBye::Bye, Some("Command too long")));
+        let too_large = format!("Command exceeds the {} limit", Limit::LiteralSize);
+        // Discarding a literal costs no memory, but one announced far larger
+        // than the limit is not worth reading.
+        let max_discarded = limits.max_literal_size.saturating_mul(2);
+        let end = loop {
+            if self.pos
+                > limits
+                    .max_line_length
+                    .saturating_add(limits.max_literal_size)
+            {
+                return too_long();
+            }
+            if self.literal > 0 {
+                let n = self.literal.min(self.buf.len() - self.pos);
+                if n == 0 {
+                    return Received::Incomplete;
+                }
+                if self.discarding {
+                    self.buf.drain(self.pos..self.pos + n);
+                } else {
+                    self.pos += n;
+                }
+                self.literal -= n;
+                self.segment_start = self.pos;
+                continue;
+            }
+
+            let nl = match self.buf[self.pos..].iter().position(|&b| b == b'\n') {
+                Some(i) => self.pos + i,
+                None => {
+                    self.pos = self.buf.len();
+                    if self.pos - self.segment_start > limits.max_line_length {
+                        return too_long();
+                    }
+                    return Received::Incomplete;
+                }
+            };
+            if nl - self.segment_start > limits.max_line_length {
+                return too_long();
+            }
+            self.pos = nl + 1;
+
+            let segment = &self.buf[self.segment_start..nl];
+            let segment = segment.strip_suffix(b"\r").unwrap_or(segment);
+            let literal = literal_len(segment);
+            if literal.is_some_and(|len| len > max_discarded) {
+                return Received::Fatal(reply(OkNoBye::Bye, Some(&too_large)));
+            }
+            if self.discarding {
+                self.buf.drain(..self.pos);
+                self.pos = 0;
+                self.segment_start = 0;
+                match literal {
+                    Some(len) => self.literal = len,
+                    None => self.discarding = false,
+                }
+                continue;
+            }
+            match literal {
+                // The client sends a literal without waiting for a reply, so
+                // the rest of the command is discarded rather than read as
+                // further commands.
+                Some(len) if len > limits.max_literal_size => {
+                    self.literal = len;
+                    self.discarding = true;
+                    self.segment_start = self.pos;
+                    return Received::Invalid(self.pos, reply(OkNoBye::No, Some(&too_large)));
+                }
+                Some(len) => {
+                    self.literal = len;
+                    self.segment_start = self.pos;
+                }
+                None => break self.pos,
+            }
+        };
+        self.segment_start = end;
+
+        let text = match std::str::from_utf8(&self.buf[..end]) {
+            Ok(text) => text,
+            Err(_) => return Received::Fatal(reply(OkNoBye::Bye, Some("Invalid UTF-8"))),
+        };
+        match parse(text, limits) {
+            Ok((left, value)) => {
+                // Anything left over is scanned again as the next command.
+                let len = end - left.len();
+                self.pos = len;
+                self.segment_start = len;
+                Received::Complete(len, value)
+            }
+            Err(e) => Received::Invalid(end, reply(OkNoBye::No, Some(&e.to_string()))),
+        }
+    }
+}
+
+pub(crate) fn reply(tag: OkNoBye, human: Option<&str>) -> String {
+    let response = Response {
+        tag,
+        code: None,
+        human: human.map(str::to_owned),
+    };
+    format!("{}\r\n", response)
+}
+
+// NOOP echoes any tag it is given in a TAG response code.
+pub(crate) fn noop_reply(tag: Option<&str>) -> String {
+    let response = Response {
+        tag: OkNoBye::Ok,
+        code: tag.map(|tag| (ResponseCode::Tag, Some(tag.to_owned()))),
+        human: None,
+    };
+    format!("{}\r\n", response)
+}
+
+pub(crate) fn capabilities_reply(capabilities: &[Capability]) -> String {
+    let mut reply: String = capabilities
+        .iter()
+        .map(|capability| format!("{}\r\n", capability))
+        .collect();
+    reply.push_str("OK\r\n");
+    reply
+}
+
+// Carries out a command that operates on scripts, returning the reply.
+// Scripts sent with PUTSCRIPT and CHECKSCRIPT are checked by `validator`.
+pub(crate) fn dispatch<S: ScriptStore + ?Sized>(
+    store: &mut S,
+    validator: Option<&dyn ScriptValidator>,
+    command: &Command,
+) -> String {
+    let mut warnings = None;
+    let result = match command {
+        Command::HaveSpace(name, size) => store.have_space(name, *size).map(|_| String::new()),
+        Command::PutScript(name, script) => check_script(validator, script)
+            .and_then(|checked| {
+                warnings = checked;
+                store.put(name, script)
+            })
+            .map(|_| String::new()),
+        Command::GetScript(name) => store
+            .get(name)
+            .map(|script| format!("{{{}}}\r\n{}\r\n", script.len(), script)),
+        Command::ListScripts => store.list().map(|scripts| {
+            scripts
+                .iter()
+                .map(|(name, active)| match active {
+                    true => format!("{} ACTIVE\r\n", to_qs(name.as_str())),
+                    false => format!("{}\r\n", to_qs(name.as_str())),
+                })
+                .collect()
+        }),
+        Command::SetActive(name) => store.set_active(name.as_ref()).map(|_| String::new()),
+        Command::DeleteScript(name) => store.delete(name).map(|_| String::new()),
+        Command::RenameScript(old_name, new_name) => {
+            store.rename(old_name, new_name).map(|_| String::new())
+        }
+        Command::CheckScript(script) => store
+            .check(script)
+            .and_then(|_| check_script(validator, script))
+            .map(|checked| {
+                warnings = checked;
+                String::new()
+            }),
+        _ => Err(StoreError::Other("Not a script command".to_string())),
+    };
+    match (result, warnings) {
+        (Ok(data), None) => data + "OK\r\n",
+        (Ok(data), Some(warnings)) => {
+            let response = Response {
+                tag: OkNoBye::Ok,
+                code: Some((ResponseCode::Warnings, None)),
+                human: Some(warnings),
+            };
+            format!("{}{}\r\n", data, response)
+        }
+        (Err(e), _) => format!("{}\r\n", e.to_response()),
+    }
+}
+
+/// A managesieve server, holding the configuration shared by its
+/// connections.
+///
+/// Each connection starts unauthenticated. Once it is authenticated as a
+/// user, with the [`Authenticator`] given or by [`ServerConnection::login`],
+/// script commands are carried out on the [`ScriptStore`] opened for that
+/// user.
+pub struct Server<S> {
+    capabilities: Vec<Capability>,
+    limits: ParserLimits,
+    unauthenticated_limits: ParserLimits,
+    authenticator: Option<Authenticator>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+    validator: Option<Arc<dyn ScriptValidator>>,
+    open_store: Arc<OpenStore<S>>,
+}
+
+// The limits applied until a client authenticates, which leave room for
+// SASL exchanges but not scripts.
+const UNAUTHENTICATED_LIMITS: ParserLimits = ParserLimits {
+    max_literal_size: 64 * 1024,
+    ..ParserLimits::new()
+};
+
+// Opens the store of an authenticated user.
+type OpenStore<S> = dyn Fn(&str) -> Result<S, StoreError> + Send + Sync;
+
+impl<S> Clone for Server<S> {
+    fn clone(&self) -> Self {
+        Server {
+            capabilities: self.capabilities.clone(),
+            limits: self.limits,
+            unauthenticated_limits: self.unauthenticated_limits,
+            authenticator: self.authenticator.clone(),
+            #[cfg(feature = "tls")]
+            tls: self.tls.clone(),
+            validator: self.validator.clone(),
+            open_store: self.open_store.clone(),
+        }
+    }
+}
+
+impl<S> fmt::Debug for Server<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("capabilities", &self.capabilities)
+            .field("limits", &self.limits)
+            .field("unauthenticated_limits", &self.unauthenticated_limits)
+            .field("authenticator", &self.authenticator)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: ScriptStore> Server<S> {
+    /// Creates a server that opens the store of each authenticated user
+    /// with `open_store`.
+    pub fn new<F>(open_store: F) -> Server<S>
+    where
+        F: Fn(&str) -> Result<S, StoreError> + Send + Sync + 'static,
+    {
+        Server {
+            capabilities: vec![
+                Capability::Implementation(
+                    concat!("managesieve ", env!("CARGO_PKG_VERSION")).to_string(),
+                ),
+                Capability::Sieve(Vec::new()),
+                Capability::Version("1.0".to_string()),
+            ],
+            limits: ParserLimits::default(),
+            unauthenticated_limits: UNAUTHENTICATED_LIMITS,
+            authenticator: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            validator: None,
+            open_store: Arc::new(open_store),
+        }
+    }
+
+    /// Announces `capabilities` in the greeting and in response to
+    /// CAPABILITY. Any SASL capability is replaced by the mechanisms of the
+    /// authenticator, if there is one.
+    pub fn with_capabilities(mut self, capabilities: Vec<Capability>) -> Server<S> {
+        self.capabilities = capabilities;
+        self.announce_mechanisms();
+        self
+    }
+
+    /// Authenticates clients with `authenticator`, announcing its
+    /// mechanisms in the SASL capability.
+    pub fn with_authenticator(mut self, authenticator: Authenticator) -> Server<S> {
+        self.authenticator = Some(authenticator);
+        self.announce_mechanisms();
+        self
+    }
+
+    fn announce_mechanisms(&mut self) {
+        let authenticator = match &self.authenticator {
+            Some(authenticator) => authenticator,
+            None => return,
+        };
+        let sasl = Capability::Sasl(authenticator.mechanisms().to_vec());
+        match self
+            .capabilities
+            .iter_mut()
+            .find(|capability| matches!(capability, Capability::Sasl(_)))
+        {
+            Some(capability) => *capability = sasl,
+            None => self
+                .capabilities
+                .insert(self.capabilities.len().min(1), sasl),
+        }
+    }
+
+    /// Offers STARTTLS, upgrading connections with `config`. Only
+    /// [`Server::serve`] performs the upgrade, and [`Server::serve_async`]
+    /// with the `tokio-tls` feature.
+    ///
+    /// Mechanisms sending the password in the clear, such as PLAIN, are
+    /// then not announced until the connection is encrypted. They are only
+    /// refused before that with
+    /// [`Authenticator::with_plaintext_requires_encryption`].
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: Arc<rustls::ServerConfig>) -> Server<S> {
+        self.tls = Some(config);
+        if !self.capabilities.contains(&Capability::StartTls) {
+            self.capabilities.push(Capability::StartTls);
+        }
+        self
+    }
+
+    fn offers_starttls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        return false;
+    }
+
+    /// Checks scripts sent with PUTSCRIPT and CHECKSCRIPT with `validator`
+    /// before they reach the store. Scripts are otherwise only checked by
+    /// [`ScriptStore::check`], for CHECKSCRIPT.
+    pub fn with_validator(mut self, validator: impl ScriptValidator + 'static) -> Server<S> {
+        self.validator = Some(Arc::new(validator));
+        self
+    }
+
+    /// Rejects commands from authenticated clients exceeding `limits`, in
+    /// place of the defaults.
+    pub fn with_limits(mut self, limits: ParserLimits) -> Server<S> {
+        self.limits = limits;
+        self
+    }
+
+    /// Rejects commands from clients not yet authenticated exceeding
+    /// `limits`, in place of the defaults, which allow literals of up to
+    /// 64 KiB.
+    pub fn with_unauthenticated_limits(mut self, limits: ParserLimits) -> Server<S> {
+        self.unauthenticated_limits = limits;
+        self
+    }
+
+    /// The capabilities announced to a new connection. Those announced
+    /// after STARTTLS leave out STARTTLS, and may offer more SASL
+    /// mechanisms.
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    /// Starts a connection, whose greeting is ready to be sent.
+    pub fn connect(&self) -> ServerConnection<S> {
+        let mut connection = ServerConnection {
+            server: self.clone(),
+            state: SessionState::NonAuthenticated,
+            user: None,
+            store: None,
+            encrypted: false,
+            sasl: None,
+            tls_requested: false,
+            input: CommandReader::default(),
+            output: Vec::new(),
+        };
+        connection.output = capabilities_reply(&connection.capabilities()).into_bytes();
+        connection
+    }
+
+    /// Serves a connection over `stream` until the client logs out, the
+    /// server closes the connection, or the stream is closed.
+    pub fn serve<T: Read + Write>(&self, mut stream: T) -> io::Result<()> {
+        let mut connection = self.connect();
+        run(&mut connection, &mut stream)?;
+        #[cfg(feature = "tls")]
+        if let (true, Some(config)) = (connection.wants_tls(), &self.tls) {
+            let mut stream = crate::tls::accept(config.clone(), stream)?;
+            connection.tls_established();
+            run(&mut connection, &mut stream)?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Server::serve`], for an asynchronous stream.
+    #[cfg(feature = "async")]
+    pub async fn serve_async<T>(&self, mut stream: T) -> io::Result<()>
+    where
+        T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let mut connection = self.connect();
+        run_async(&mut connection, &mut stream).await?;
+        #[cfg(feature = "tokio-tls")]
+        if let (true, Some(config)) = (connection.wants_tls(), &self.tls) {
+            let acceptor = tokio_rustls::TlsAcceptor::from(config.clone());
+            let mut stream = acceptor.accept(stream).await?;
+            connection.tls_established();
+            run_async(&mut connection, &mut stream).await?;
+        }
+        #[cfg(not(feature = "tokio-tls"))]
+        if connection.wants_tls() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "STARTTLS over an asynchronous stream needs the tokio-tls feature",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Serves `connection` until it is closed, the stream is closed, or the
+// stream is to be upgraded to TLS.
+fn run<S: ScriptStore, T: Read + Write>(
+    connection: &mut ServerConnection<S>,
+    stream: &mut T,
+) -> io::Result<()> {
+    let mut buf = [0; 4096];
+    loop {
+        stream.write_all(&connection.take_output())?;
+        stream.flush()?;
+        if connection.is_closed() || connection.wants_tls() {
+            return Ok(());
+        }
+        match stream.read(&mut buf)? {
+            0 => return Ok(()),
+            n => connection.feed(&buf[..n]),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+async fn run_async<S, T>(connection: &mut ServerConnection<S>, stream: &mut T) -> io::Result<()>
+where
+    S: ScriptStore,
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut buf = [0; 4096];
+    loop {
+        stream.write_all(&connection.take_output()).await?;
+        stream.flush().await?;
+        if connection.is_closed() || connection.wants_tls() {
+            return Ok(());
+        }
+        match stream.read(&mut buf).await? {
+            0 => return Ok(()),
+            n => connection.feed(&buf[..n]),
+        }
+    }
+}
+
+/// The server side of one managesieve connection, without performing any
+/// IO.
+///
+/// Bytes received from the client are passed to [`ServerConnection::feed`],
+/// which answers each complete command. The replies, starting with the
+/// greeting, are collected with [`ServerConnection::take_output`] and sent
+/// to the client. Once [`ServerConnection::is_closed`], the last replies
+/// should be sent and the connection closed.
+pub struct ServerConnection<S> {
+    server: Server<S>,
+    state: SessionState,
+    user: Option<String>,
+    store: Option<S>,
+    encrypted: bool,
+    // The SASL exchange in progress, during which input is the client's
+    // responses rather than commands.
+    sasl: Option<Box<dyn SaslMechanism>>,
+    // STARTTLS has been accepted, and no further input is read until the
+    // TLS handshake.
+    tls_requested: bool,
+    input: CommandReader,
+    output: Vec<u8>,
+}
+
+impl<S: fmt::Debug> fmt::Debug for ServerConnection<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerConnection")
+            .field("server", &self.server)
+            .field("state", &self.state)
+            .field("user", &self.user)
+            .field("store", &self.store)
+            .field("encrypted", &self.encrypted)
+            .field("authenticating", &self.sasl.is_some())
+            .field("tls_requested", &self.tls_requested)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: ScriptStore> ServerConnection<S> {
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// The user the connection is authenticated as, if any.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// Records that the connection is encrypted, as where the listener
+    /// provides TLS. Mechanisms sending passwords in the clear may require
+    /// it.
+    pub fn set_encrypted(&mut self, encrypted: bool) {
+        self.encrypted = encrypted;
+    }
+
+    /// The capabilities announced on this connection, which depend on
+    /// whether it is encrypted.
+    pub fn capabilities(&self) -> Vec<Capability> {
+        let offers_starttls = self.server.offers_starttls() && !self.encrypted;
+        let announced =
+            |mechanism: &String| !(offers_starttls && Authenticator::is_plaintext(mechanism));
+        self.server
+            .capabilities
+            .iter()
+            .filter(|capability| **capability != Capability::StartTls || offers_starttls)
+            .map(
+                |capability| match (capability, &self.server.authenticator) {
+                    (Capability::Sasl(_), Some(authenticator)) => {
+                        let mut mechanisms = authenticator.mechanisms_for(self.encrypted);
+                        mechanisms.retain(announced);
+                        Capability::Sasl(mechanisms)
+                    }
+                    (capability, _) => capability.clone(),
+                },
+            )
+            .collect()
+    }
+
+    /// Whether STARTTLS has been accepted. Once the replies have been sent,
+    /// the TLS handshake is to be performed and
+    /// [`ServerConnection::tls_established`] called.
+    pub fn wants_tls(&self) -> bool {
+        self.tls_requested
+    }
+
+    /// Records that TLS has been established after STARTTLS, discarding any
+    /// input received before it, and queues the capabilities that RFC 5804
+    /// requires be sent again.
+    pub fn tls_established(&mut self) {
+        self.tls_requested = false;
+        self.encrypted = true;
+        self.input.clear();
+        self.output
+            .extend_from_slice(capabilities_reply(&self.capabilities()).as_bytes());
+    }
+
+    /// Whether the connection is to be closed, after LOGOUT or BYE.
+    pub fn is_closed(&self) -> bool {
+        self.state == SessionState::LoggedOut
+    }
+
+    /// Authenticates the connection as `user` by means other than
+    /// AUTHENTICATE, for example where the client has already been
+    /// authenticated by a proxy, and opens the user's store.
+    pub fn login(&mut self, user: &str) -> Result<(), StoreError> {
+        self.store = Some((self.server.open_store)(user)?);
+        self.user = Some(user.to_owned());
+        self.state = SessionState::Authenticated;
+        Ok(())
+    }
+
+    /// Takes the bytes to be sent to the client.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Processes bytes received from the client, answering each complete
+    /// command.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.feed(bytes);
+        while !self.is_closed() && !self.tls_requested {
+            let (len, reply) = match self.next_reply() {
+                Some(next) => next,
+                None => return,
+            };
+            self.input.consume(len);
+            self.output.extend_from_slice(reply.as_bytes());
+        }
+    }
+
+    // Clients are held to the smaller limits until they authenticate.
+    fn limits(&self) -> ParserLimits {
+        match self.state {
+            SessionState::Authenticated => self.server.limits,
+            _ => self.server.unauthenticated_limits,
+        }
+    }
+
+    // Answers the next complete command or SASL response, returning its
+    // length and the reply.
+    fn next_reply(&mut self) -> Option<(usize, String)> {
+        let limits = self.limits();
+        let received = match self.sasl {
+            Some(_) => match next_sasl_response(&mut self.input, &limits) {
+                Received::Complete(len, response) => {
+                    Received::Complete(len, self.continue_sasl(&response))
+                }
+                Received::Invalid(len, reply) => {
+                    self.sasl = None;
+                    Received::Invalid(len, reply)
+                }
+                Received::Incomplete => Received::Incomplete,
+                Received::Fatal(reply) => Received::Fatal(reply),
+            },
+            None => match next_command(&mut self.input, &limits) {
+                Received::Complete(len, command) => Received::Complete(len, self.execute(&command)),
+                Received::Invalid(len, reply) => Received::Invalid(len, reply),
+                Received::Incomplete => Received::Incomplete,
+                Received::Fatal(reply) => Received::Fatal(reply),
+            },
+        };
+        match received {
+            Received::Incomplete => None,
+            Received::Complete(len, reply) | Received::Invalid(len, reply) => Some((len, reply)),
+            Received::Fatal(reply) => {
+                self.state = SessionState::LoggedOut;
+                Some((self.input.buffered().len(), reply))
+            }
+        }
+    }
+
+    fn authenticate(&mut self, mechanism: &str, initial_response: Option<&str>) -> String {
+        let authenticator = match &self.server.authenticator {
+            Some(authenticator) => authenticator,
+            None => return reply(OkNoBye::No, Some("No SASL mechanisms are available")),
+        };
+        match authenticator.start(mechanism, self.encrypted) {
+            Ok(mechanism) => self.sasl = Some(mechanism),
+            Err(response) => return format!("{}\r\n", response),
+        }
+        match initial_response.map(|r| BASE64.decode(r)) {
+            None => self.sasl_step(None),
+            Some(Ok(response)) => self.sasl_step(Some(&response)),
+            Some(Err(_)) => {
+                self.sasl = None;
+                reply(OkNoBye::No, Some("Invalid base64"))
+            }
+        }
+    }
+
+    fn continue_sasl(&mut self, response: &str) -> String {
+        if response == "*" {
+            self.sasl = None;
+            return reply(OkNoBye::No, Some("Authentication aborted"));
+        }
+        match BASE64.decode(response) {
+            Ok(response) => self.sasl_step(Some(&response)),
+            Err(_) => {
+                self.sasl = None;
+                reply(OkNoBye::No, Some("Invalid base64"))
+            }
+        }
+    }
+
+    fn sasl_step(&mut self, response: Option<&[u8]>) -> String {
+        let step = match &mut self.sasl {
+            Some(mechanism) => mechanism.step(response),
+            None => return reply(OkNoBye::No, None),
+        };
+        if !matches!(step, SaslStep::Challenge(_)) {
+            self.sasl = None;
+        }
+        match step {
+            SaslStep::Challenge(challenge) => {
+                let challenge = BASE64.encode(challenge);
+                format!("{{{}}}\r\n{}\r\n", challenge.len(), challenge)
+            }
+            SaslStep::Success { user, data } => match self.login(&user) {
+                Ok(()) => {
+                    let response = Response {
+                        tag: OkNoBye::Ok,
+                        code: data.map(|data| (ResponseCode::Sasl, Some(BASE64.encode(data)))),
+                        human: None,
+                    };
+                    format!("{}\r\n", response)
+                }
+                Err(e) => format!("{}\r\n", e.to_response()),
+            },
+            SaslStep::Failure(response) => format!("{}\r\n", response),
+        }
+    }
+
+    fn execute(&mut self, command: &Command) -> String {
+        let authenticated = self.state == SessionState::Authenticated;
+        match command {
+            Command::Capability => capabilities_reply(&self.capabilities()),
+            Command::Noop(tag) => noop_reply(tag.as_deref()),
+            Command::Logout => {
+                self.state = SessionState::LoggedOut;
+                reply(OkNoBye::Ok, None)
+            }
+            Command::Authenticate(..) if authenticated => {
+                reply(OkNoBye::No, Some("Already authenticated"))
+            }
+            Command::Authenticate(mechanism, initial_response) => {
+                self.authenticate(mechanism, initial_response.as_deref())
+            }
+            Command::StartTls if authenticated || self.encrypted => {
+                reply(OkNoBye::No, Some("STARTTLS is not permitted now"))
+            }
+            Command::StartTls if self.server.offers_starttls() => {
+                self.tls_requested = true;
+                reply(OkNoBye::Ok, None)
+            }
+            Command::StartTls => reply(OkNoBye::No, Some("STARTTLS is not available")),
+            Command::UnAuthenticate if authenticated => {
+                self.store = None;
+                self.user = None;
+                self.state = SessionState::NonAuthenticated;
+                reply(OkNoBye::Ok, None)
+            }
+            command => match &mut self.store {
+                Some(store) if authenticated => {
+                    dispatch(store, self.server.validator.as_deref(), command)
+                }
+                _ => reply(OkNoBye::No, Some("Authenticate first")),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+fn exchange<S: ScriptStore>(connection: &mut ServerConnection<S>, input: &str) -> String {
+    connection.feed(input.as_bytes());
+    String::from_utf8(connection.take_output()).unwrap()
+}
+
+#[test]
+fn test_server() {
+    use crate::store::MemoryStore;
+
+    let server = Server::new(|user| match user {
+        "alice" => Ok(MemoryStore::new().with_max_script_size(10)),
+        _ => Err(StoreError::Other("Unknown user".to_string())),
+    });
+    let mut connection = server.connect();
+    let greeting = String::from_utf8(connection.take_output()).unwrap();
+    assert_eq!(
+        crate::response_greeting(&greeting).unwrap().1,
+        server.capabilities()
+    );
+
+    assert_eq!(
+        exchange(&mut connection, "LISTSCRIPTS\r\n"),
+        "NO \"Authenticate first\"\r\n"
+    );
+    assert!(connection.login("bob").is_err());
+    connection.login("alice").unwrap();
+    assert_eq!(connection.user(), Some("alice"));
+
+    let put = Command::put_script("a", "keep;").unwrap().to_string();
+    assert_eq!(exchange(&mut connection, &put), "OK\r\n");
+    assert_eq!(
+        exchange(&mut connection, "PUTSCRIPT \"b\" {11+}\r\nkeep; keep;\r\n"),
+        "NO (QUOTA/MAXSIZE)\r\n"
+    );
+    assert_eq!(
+        exchange(&mut connection, "SETACTIVE \"b\"\r\n"),
+        "NO (NONEXISTENT)\r\n"
+    );
+    assert_eq!(
+        exchange(&mut connection, "SETACTIVE \"a\"\r\nDELETESCRIPT \"a\"\r\n"),
+        "OK\r\nNO (ACTIVE)\r\n"
+    );
+    assert_eq!(
+        exchange(&mut connection, "LISTSCRIPTS\r\n"),
+        "\"a\" ACTIVE\r\nOK\r\n"
+    );
+    let (_, script, _) =
+        crate::response_getscript(&exchange(&mut connection, "GETSCRIPT \"a\"\r\n")).unwrap();
+    assert_eq!(script, "keep;");
+
+    assert!(exchange(&mut connection, "FROB\r\n").starts_with("NO \"invalid command"));
+    // Commands are answered once complete.
+    assert_eq!(exchange(&mut connection, "NOOP\r"), "");
+    assert_eq!(
+        exchange(&mut connection, "\nNOOP \"sync\"\r\n"),
+        "OK\r\nOK (TAG \"sync\")\r\n"
+    );
+    assert_eq!(exchange(&mut connection, "UNAUTHENTICATE\r\n"), "OK\r\n");
+    assert_eq!(connection.state(), SessionState::NonAuthenticated);
+    assert_eq!(exchange(&mut connection, "LOGOUT\r\nNOOP\r\n"), "OK\r\n");
+    assert!(connection.is_closed());
+}
+
+#[test]
+fn test_server_limits() {
+    use crate::store::MemoryStore;
+
+    let server =
+        Server::new(|_| Ok(MemoryStore::new())).with_unauthenticated_limits(ParserLimits {
+            max_literal_size: 4,
+            max_line_length: 32,
+            ..ParserLimits::new()
+        });
+    let mut connection = server.connect();
+    connection.take_output();
+    assert_eq!(
+        exchange(&mut connection, "NOOP {5+}\r\nNOOP\r\n"),
+        "NO \"Command exceeds the literal size limit\"\r\n"
+    );
+
+    connection.login("alice").unwrap();
+    let put = Command::put_script("a", "keep;\r\n".repeat(100))
+        .unwrap()
+        .to_string();
+    let (last, rest) = put.as_bytes().split_last().unwrap();
+    for byte in rest {
+        connection.feed(&[*byte]);
+    }
+    assert!(connection.take_output().is_empty());
+    connection.feed(&[*last]);
+    assert_eq!(connection.take_output(), b"OK\r\n");
+
+    // The literal of a rejected command is not read as commands.
+    let server = server.with_limits(ParserLimits {
+        max_literal_size: 10,
+        ..ParserLimits::new()
+    });
+    let mut connection = server.connect();
+    connection.take_output();
+    connection.login("alice").unwrap();
+    assert_eq!(
+        exchange(
+            &mut connection,
+            "PUTSCRIPT \"y\" {14+}\r\nSETACTIVE \"\"\r\n"
+        ),
+        "NO \"Command exceeds the literal size limit\"\r\n"
+    );
+    assert_eq!(exchange(&mut connection, "\r\nNOOP\r\n"), "OK\r\n");
+    let mut connection = server.connect();
+    connection.take_output();
+    connection.login("alice").unwrap();
+    assert_eq!(
+        exchange(
+            &mut connection,
+            "PUTSCRIPT \"y\" {40+}\r\nSETACTIVE \"\"\r\nDELETESCRIPT \"x\"\r\n"
+        ),
+        "BYE \"Command exceeds the literal size limit\"\r\n"
+    );
+    assert!(connection.is_closed());
+
+    let mut connection = server.connect();
+    connection.take_output();
+    assert_eq!(
+        exchange(&mut connection, &"NOOP ".repeat(10)),
+        "BYE \"Command too long\"\r\n"
+    );
+    assert!(connection.is_closed());
+}
+
+#[test]
+fn test_server_validator() {
+    use crate::store::MemoryStore;
+    use crate::validator::{Diagnostic, Position};
+
+    struct NoDiscard;
+
+    impl ScriptValidator for NoDiscard {
+        fn validate(&self, script: &crate::SieveScript) -> io::Result<Vec<Diagnostic>> {
+            let mut diagnostics = Vec::new();
+            for (i, line) in script.as_str().lines().enumerate() {
+                if let Some(column) = line.find("reject") {
+                    let position = Some(Position {
+                        line: i + 1,
+                        column: Some(column + 1),
+                    });
+                    diagnostics.push(Diagnostic::error(position, "reject is not supported"));
+                }
+                if line.contains("discard") {
+                    let position = Some(Position {
+                        line: i + 1,
+                        column: None,
+                    });
+                    diagnostics.push(Diagnostic::warning(position, "mail will be lost"));
+                }
+            }
+            Ok(diagnostics)
+        }
+    }
+
+    let server = Server::new(|_| Ok(MemoryStore::new())).with_validator(NoDiscard);
+    let mut connection = server.connect();
+    connection.take_output();
+    connection.login("alice").unwrap();
+
+    let put = |name, script| Command::put_script(name, script).unwrap().to_string();
+    assert_eq!(
+        exchange(&mut connection, &put("a", "keep;\r\nreject \"no\";\r\n")),
+        "NO \"line 2, column 1: reject is not supported\"\r\n"
+    );
+    assert_eq!(
+        exchange(&mut connection, &put("b", "keep;\r\ndiscard;\r\n")),
+        "OK (WARNINGS) \"line 2: mail will be lost\"\r\n"
+    );
+    assert_eq!(
+        exchange(&mut connection, "LISTSCRIPTS\r\n"),
+        "\"b\"\r\nOK\r\n"
+    );
+    let check = Command::checkscript("if true { reject; }").to_string();
+    assert_eq!(
+        exchange(&mut connection, &check),
+        "NO \"line 1, column 11: reject is not supported\"\r\n"
+    );
+    let check = Command::checkscript("keep;").to_string();
+    assert_eq!(exchange(&mut connection, &check), "OK\r\n");
+}
+
+#[test]
+fn test_server_authenticate() {
+    use crate::sasl::StaticCredentials;
+    use crate::store::MemoryStore;
+
+    let credentials = StaticCredentials::parse("alice:secret\nbob:hunter2\n").unwrap();
+    let server = Server::new(|user| match user {
+        "alice" => Ok(MemoryStore::new()),
+        _ => Err(StoreError::TryLater),
+    })
+    .with_authenticator(Authenticator::new(credentials).with_mechanisms(&["PLAIN"]));
+    assert!(server
+        .capabilities()
+        .contains(&Capability::Sasl(vec!["PLAIN".to_string()])));
+
+    let mut connection = server.connect();
+    connection.take_output();
+    assert_eq!(
+        exchange(&mut connection, "AUTHENTICATE \"DIGEST-MD5\"\r\n"),
+        "NO \"Unsupported SASL mechanism\"\r\n"
+    );
+    assert_eq!(
+        exchange(
+            &mut connection,
+            "AUTHENTICATE \"PLAIN\" \"AGFsaWNlAHdyb25n\"\r\n"
+        ),
+        "NO \"Authentication failed\"\r\n"
+    );
+    assert_eq!(
+        exchange(&mut connection, "AUTHENTICATE \"PLAIN\"\r\n"),
+        "{0}\r\n\r\n"
+    );
+    assert_eq!(
+        exchange(&mut connection, "\"*\"\r\n"),
+        "NO \"Authentication aborted\"\r\n"
+    );
+    assert_eq!(
+        exchange(
+            &mut connection,
+            "AUTHENTICATE \"PLAIN\" \"AGJvYgBodW50ZXIy\"\r\n"
+        ),
+        "NO (TRYLATER)\r\n"
+    );
+    assert_eq!(connection.state(), SessionState::NonAuthenticated);
+
+    // The challenge is answered in a later read.
+    assert_eq!(
+        exchange(&mut connection, "AUTHENTICATE \"PLAIN\"\r\n{20+}\r\n"),
+        "{0}\r\n\r\n"
+    );
+    assert_eq!(
+        exchange(&mut connection, "AGFsaWNlAHNlY3JldA==\r\nNOOP\r\n"),
+        "OK\r\nOK\r\n"
+    );
+    assert_eq!(connection.user(), Some("alice"));
+    assert_eq!(
+        exchange(&mut connection, "AUTHENTICATE \"PLAIN\"\r\n"),
+        "NO \"Already authenticated\"\r\n"
+    );
+}
+
+#[cfg(feature = "scram")]
+#[test]
+fn test_server_scram() {
+    use crate::sasl::StaticCredentials;
+    use crate::store::MemoryStore;
+
+    let credentials = StaticCredentials::new().with_user(
+        "user",
+        crate::sasl::Credential::Password("pencil".to_string()),
+    );
+    let server = Server::new(|_| Ok(MemoryStore::new())).with_authenticator(
+        Authenticator::new(credentials).with_plaintext_requires_encryption(true),
+    );
+    let mut connection = server.connect();
+    connection.take_output();
+    assert_eq!(
+        exchange(&mut connection, "AUTHENTICATE \"PLAIN\"\r\n"),
+        "NO (ENCRYPT-NEEDED) \"Encryption required\"\r\n"
+    );
+
+    // Plays the client's part, following RFC 5802.
+    let client_first_bare = "n=user,r=rOprNGfwEbeRWgbNEkqO";
+    let command = format!(
+        "AUTHENTICATE \"SCRAM-SHA-256\" \"{}\"\r\n",
+        BASE64.encode(format!("n,,{}", client_first_bare))
+    );
+    let challenge = exchange(&mut connection, &command);
+    let (_, challenge) = crate::response_authenticate(&challenge).unwrap();
+    let server_first = BASE64.decode(challenge.unwrap_left()).unwrap();
+    let server_first = String::from_utf8(server_first).unwrap();
+    let fields: Vec<&str> = server_first.split(',').collect();
+    let salt = BASE64.decode(&fields[1][2..]).unwrap();
+    let keys = crate::ScramKeys::derive("pencil", &salt, fields[2][2..].parse().unwrap());
+    let without_proof = format!("c=biws,{}", fields[0]);
+    let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+    let mut salted_password = [0; 32];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(b"pencil", &salt, keys.iterations, &mut salted_password);
+    let mac = |key: &[u8], message: &[u8]| {
+        use hmac::Mac;
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key).unwrap();
+        mac.update(message);
+        mac.finalize().into_bytes()
+    };
+    let client_key = mac(&salted_password, b"Client Key");
+    let signature = mac(&keys.stored_key, auth_message.as_bytes());
+    let proof: Vec<u8> = client_key
+        .iter()
+        .zip(signature)
+        .map(|(k, s)| k ^ s)
+        .collect();
+    let client_final = format!("{},p={}", without_proof, BASE64.encode(proof));
+
+    let reply = exchange(
+        &mut connection,
+        &format!("\"{}\"\r\n", BASE64.encode(client_final)),
+    );
+    let response = crate::response_authenticate(&reply)
+        .unwrap()
+        .1
+        .unwrap_right();
+    let expected = mac(&keys.server_key, auth_message.as_bytes());
+    assert_eq!(
+        response.code,
+        Some((
+            ResponseCode::Sasl,
+            Some(BASE64.encode(format!("v={}", BASE64.encode(expected))))
+        ))
+    );
+    assert_eq!(connection.user(), Some("user"));
+}
+
+#[cfg(all(test, feature = "tls"))]
+fn test_tls_config() -> Arc<rustls::ServerConfig> {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    crate::tls::load_tls_config(
+        concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.crt"),
+        concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.key"),
+    )
+    .unwrap()
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn test_server_starttls() {
+    use crate::sasl::{Credential, StaticCredentials};
+    use crate::store::MemoryStore;
+
+    let credentials =
+        StaticCredentials::new().with_user("alice", Credential::Password("secret".to_string()));
+    let authenticator = Authenticator::new(credentials)
+        .with_mechanisms(&["PLAIN"])
+        .with_plaintext_requires_encryption(true);
+    let server = Server::new(|_| Ok(MemoryStore::new()))
+        .with_authenticator(authenticator)
+        .with_tls(test_tls_config());
+    let mut connection = server.connect();
+    let greeting = String::from_utf8(connection.take_output()).unwrap();
+    let (_, capabilities, _) = crate::response_greeting(&greeting).unwrap();
+    assert!(capabilities.contains(&Capability::StartTls));
+    assert!(capabilities.contains(&Capability::Sasl(Vec::new())));
+
+    let authenticate = "AUTHENTICATE \"PLAIN\" \"AGFsaWNlAHNlY3JldA==\"\r\n";
+    assert_eq!(
+        exchange(&mut connection, authenticate),
+        "NO (ENCRYPT-NEEDED) \"Encryption required\"\r\n"
+    );
+    // Input sent before the handshake is not answered.
+    assert_eq!(
+        exchange(&mut connection, "STARTTLS\r\nCAPABILITY\r\n"),
+        "OK\r\n"
+    );
+    assert!(connection.wants_tls());
+    connection.tls_established();
+    assert!(connection.is_encrypted());
+    let capabilities = String::from_utf8(connection.take_output()).unwrap();
+    let (left, capabilities, _) = crate::response_capability(&capabilities).unwrap();
+    assert_eq!(left, "");
+    assert!(!capabilities.contains(&Capability::StartTls));
+    assert!(capabilities.contains(&Capability::Sasl(vec!["PLAIN".to_string()])));
+
+    assert_eq!(
+        exchange(&mut connection, "STARTTLS\r\n"),
+        "NO \"STARTTLS is not permitted now\"\r\n"
+    );
+    assert_eq!(exchange(&mut connection, authenticate), "OK\r\n");
+
+    // PLAIN is hidden until TLS is up even when it is not refused.
+    let credentials =
+        StaticCredentials::new().with_user("alice", Credential::Password("secret".to_string()));
+    let server = Server::new(|_| Ok(MemoryStore::new()))
+        .with_authenticator(Authenticator::new(credentials).with_mechanisms(&["PLAIN"]))
+        .with_tls(test_tls_config());
+    let mut connection = server.connect();
+    connection.take_output();
+    assert!(connection
+        .capabilities()
+        .contains(&Capability::Sasl(Vec::new())));
+    assert_eq!(exchange(&mut connection, authenticate), "OK\r\n");
+    let mut connection = server.connect();
+    connection.set_encrypted(true);
+    assert!(connection
+        .capabilities()
+        .contains(&Capability::Sasl(vec!["PLAIN".to_string()])));
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn test_server_serve_starttls() {
+    use std::net::{TcpListener, TcpStream};
+
+    use rustls::pki_types::pem::PemObject;
+
+    use crate::store::MemoryStore;
+
+    // Reads until `parse` finds a complete response.
+    fn read<T: Read, R>(stream: &mut T, parse: impl Fn(&str) -> Result<R, crate::Error>) -> R {
+        let mut input = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let n = stream.read(&mut buf).unwrap();
+            assert_ne!(n, 0);
+            input.extend_from_slice(&buf[..n]);
+            match parse(std::str::from_utf8(&input).unwrap()) {
+                Err(e) if e.is_incomplete() => continue,
+                result => return result.unwrap(),
+            }
+        }
+    }
+
+    let server = Server::new(|_| Ok(MemoryStore::new())).with_tls(test_tls_config());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let served = std::thread::spawn(move || server.serve(listener.accept().unwrap().0));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let (_, capabilities, _) = read(&mut stream, |s| {
+        crate::response_greeting(s).map(|(_, c, r)| ((), c, r))
+    });
+    assert!(capabilities.contains(&Capability::StartTls));
+    stream.write_all(b"STARTTLS\r\n").unwrap();
+    read(&mut stream, |s| crate::response_noop(s).map(|(_, r)| r));
+
+    let mut roots = rustls::RootCertStore::empty();
+    let cert = include_bytes!("../testdata/localhost.crt");
+    roots
+        .add(rustls::pki_types::CertificateDer::from_pem_slice(cert).unwrap())
+        .unwrap();
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    let tls =
+        rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+    let mut stream = rustls::StreamOwned::new(tls, stream);
+
+    let (_, capabilities, _) = read(&mut stream, |s| {
+        crate::response_capability(s).map(|(_, c, r)| ((), c, r))
+    });
+    assert!(!capabilities.contains(&Capability::StartTls));
+    stream.write_all(b"LOGOUT\r\n").unwrap();
+    read(&mut stream, |s| crate::response_logout(s).map(|(_, r)| r));
+    served.join().unwrap().unwrap();
+}
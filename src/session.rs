@@ -0,0 +1,485 @@
+use std::collections::VecDeque;
+
+use crate::error::Error;
+use crate::limits::ParserLimits;
+use crate::parser as p;
+use crate::types::{
+    into_owned_scripts, parse_authenticate, parse_capability, parse_listscripts, parse_oknobye,
+    Capability, CapabilityRef, Command, CommandResponse, OkNoBye, Response,
+};
+
+/// Identifies the `response_` function that parses the next response from the
+/// server.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ResponseKind {
+    /// [`response_greeting`](crate::response_greeting)
+    Greeting,
+    /// [`response_authenticate`](crate::response_authenticate)
+    Authenticate,
+    /// The OK, NO or BYE response to STARTTLS. After a successful TLS
+    /// handshake the server sends its capabilities again, which are expected
+    /// as `ResponseKind::Capability`. Use
+    /// [`response_starttls`](crate::response_starttls) when both have been
+    /// received together.
+    StartTls,
+    /// [`response_logout`](crate::response_logout)
+    Logout,
+    /// [`response_capability`](crate::response_capability)
+    Capability,
+    /// [`response_havespace`](crate::response_havespace)
+    HaveSpace,
+    /// [`response_putscript`](crate::response_putscript)
+    PutScript,
+    /// [`response_getscript`](crate::response_getscript)
+    GetScript,
+    /// [`response_listscripts`](crate::response_listscripts)
+    ListScripts,
+    /// [`response_setactive`](crate::response_setactive)
+    SetActive,
+    /// [`response_deletescript`](crate::response_deletescript)
+    DeleteScript,
+    /// [`response_renamescript`](crate::response_renamescript)
+    RenameScript,
+    /// [`response_checkscript`](crate::response_checkscript)
+    CheckScript,
+    /// [`response_noop`](crate::response_noop)
+    Noop,
+    /// [`response_unauthenticate`](crate::response_unauthenticate)
+    UnAuthenticate,
+}
+
+impl From<&Command> for ResponseKind {
+    fn from(command: &Command) -> Self {
+        match command {
+            Command::Authenticate(..) => ResponseKind::Authenticate,
+            Command::StartTls => ResponseKind::StartTls,
+            Command::Logout => ResponseKind::Logout,
+            Command::Capability => ResponseKind::Capability,
+            Command::HaveSpace(..) => ResponseKind::HaveSpace,
+            Command::PutScript(..) => ResponseKind::PutScript,
+            Command::GetScript(_) => ResponseKind::GetScript,
+            Command::ListScripts => ResponseKind::ListScripts,
+            Command::SetActive(_) => ResponseKind::SetActive,
+            Command::DeleteScript(_) => ResponseKind::DeleteScript,
+            Command::RenameScript(..) => ResponseKind::RenameScript,
+            Command::CheckScript(_) => ResponseKind::CheckScript,
+            Command::Noop(_) => ResponseKind::Noop,
+            Command::UnAuthenticate => ResponseKind::UnAuthenticate,
+        }
+    }
+}
+
+impl ResponseKind {
+    /// Whether the response carries a capability listing, and so should be
+    /// passed to [`Session::receive_capabilities`].
+    pub fn has_capabilities(self) -> bool {
+        matches!(self, ResponseKind::Greeting | ResponseKind::Capability)
+    }
+
+    /// Parses a response of this kind with the corresponding `response_`
+    /// function. A BYE response is returned as `CommandResponse::Bye` rather
+    /// than as an error.
+    pub fn parse(self, input: &str) -> Result<(&str, CommandResponse), Error> {
+        self.parse_with_limits(input, &ParserLimits::default())
+    }
+
+    /// Like [`ResponseKind::parse`], but with the given limits in place of
+    /// the defaults.
+    pub fn parse_with_limits<'a>(
+        self,
+        input: &'a str,
+        limits: &ParserLimits,
+    ) -> Result<(&'a str, CommandResponse), Error> {
+        if let Ok((left, r)) = p::response_bye(input, limits) {
+            return Ok((left, CommandResponse::Bye(r.into_owned())));
+        }
+
+        let (left, response) = match self {
+            ResponseKind::Greeting | ResponseKind::Capability => {
+                let (left, caps, r) = parse_capability(input, limits)?;
+                if self == ResponseKind::Greeting && r.tag == OkNoBye::No {
+                    return Err(Error::Refused(r.into_owned()));
+                }
+                let caps = caps.into_iter().map(CapabilityRef::into_owned).collect();
+                (left, CommandResponse::Capabilities(caps, r.into_owned()))
+            }
+            ResponseKind::Authenticate => {
+                let (left, r) = parse_authenticate(input, limits)?;
+                (
+                    left,
+                    r.either(
+                        |s| CommandResponse::Challenge(s.into_owned()),
+                        |r| CommandResponse::from(r.into_owned()),
+                    ),
+                )
+            }
+            // Unlike response_getscript, a NO response is not an error here.
+            ResponseKind::GetScript => {
+                match p::response_getscript(input, limits).map_err(|e| Error::parse(input, e))? {
+                    (left, (Some(s), r)) => (
+                        left,
+                        CommandResponse::Script(s.into_owned(), r.into_owned()),
+                    ),
+                    (left, (None, r)) => (left, CommandResponse::from(r.into_owned())),
+                }
+            }
+            ResponseKind::ListScripts => {
+                let (left, s, r) = parse_listscripts(input, limits)?;
+                (
+                    left,
+                    CommandResponse::ScriptList(into_owned_scripts(s), r.into_owned()),
+                )
+            }
+            // Unlike response_noop, a NO response to NOOP is not an error
+            // here either.
+            _ => {
+                let (left, r) = parse_oknobye(input, limits)?;
+                (left, CommandResponse::from(r.into_owned()))
+            }
+        };
+
+        // BYE may also end a response that had already begun.
+        match response {
+            CommandResponse::Capabilities(_, r) | CommandResponse::ScriptList(_, r)
+                if r.tag == OkNoBye::Bye =>
+            {
+                Ok((left, CommandResponse::Bye(r)))
+            }
+            response => Ok((left, response)),
+        }
+    }
+
+    // The session state after these depends on the response, so nothing may
+    // be pipelined behind them.
+    fn is_barrier(self) -> bool {
+        matches!(
+            self,
+            ResponseKind::Greeting
+                | ResponseKind::Authenticate
+                | ResponseKind::StartTls
+                | ResponseKind::Logout
+                | ResponseKind::UnAuthenticate
+        )
+    }
+}
+
+/// Parses the response to `command`, whichever command it is, as
+/// [`ResponseKind::parse`] does for the kind of response it expects.
+pub fn parse_response_for<'a>(
+    command: &Command,
+    input: &'a str,
+) -> Result<(&'a str, CommandResponse), Error> {
+    ResponseKind::from(command).parse(input)
+}
+
+#[test]
+fn test_parse_response_for() {
+    let referral = "NO (REFERRAL \"sieve://b.example.com\") \"Moved\"\r\n";
+    assert!(matches!(
+        parse_response_for(&Command::put_script("a", "keep;").unwrap(), referral),
+        Ok(("", CommandResponse::Referral(url, _))) if url == "sieve://b.example.com"
+    ));
+    assert!(matches!(
+        parse_response_for(&Command::set_active("a").unwrap(), "OK\r\n"),
+        Ok(("", CommandResponse::Ok(_)))
+    ));
+    assert!(matches!(
+        parse_response_for(&Command::deletescript("a").unwrap(), "NO (ACTIVE)\r\n"),
+        Ok(("", CommandResponse::No(_)))
+    ));
+    assert!(matches!(
+        parse_response_for(&Command::ListScripts, "\"a\" ACTIVE\r\nOK\r\n"),
+        Ok(("", CommandResponse::ScriptList(s, _))) if s.len() == 1
+    ));
+    assert!(matches!(
+        parse_response_for(&Command::Capability, "\"STARTTLS\"\r\nOK\r\n"),
+        Ok(("", CommandResponse::Capabilities(caps, _))) if caps == vec![Capability::StartTls]
+    ));
+}
+
+/// Protocol states of a managesieve connection (RFC 5804 section 2).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SessionState {
+    NonAuthenticated,
+    Authenticated,
+    LoggedOut,
+}
+
+/// Tracks the state of a managesieve connection, without performing any IO.
+///
+/// Pass each [`Command`] to [`Session::send`] before writing it, which fails
+/// with `Error::InvalidState` if the command is not permitted. Responses must
+/// be passed back in the order they arrive, using the `response_` function
+/// given by [`Session::expected`] to parse them.
+#[derive(Debug)]
+pub struct Session {
+    state: SessionState,
+    tls: bool,
+    capabilities: Vec<Capability>,
+    pending: VecDeque<ResponseKind>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new()
+    }
+}
+
+impl Session {
+    /// Creates a session for a newly opened connection, which expects the
+    /// server's greeting first.
+    pub fn new() -> Session {
+        Session {
+            state: SessionState::NonAuthenticated,
+            tls: false,
+            capabilities: Vec::new(),
+            pending: vec![ResponseKind::Greeting].into(),
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// Whether STARTTLS has completed successfully.
+    pub fn is_tls(&self) -> bool {
+        self.tls
+    }
+
+    /// The capabilities most recently advertised by the server.
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    /// The kind of the next response expected from the server, if any.
+    pub fn expected(&self) -> Option<ResponseKind> {
+        self.pending.front().copied()
+    }
+
+    /// Checks that `command` may be sent in the current state, and records
+    /// that a response to it is expected.
+    pub fn send(&mut self, command: &Command) -> Result<ResponseKind, Error> {
+        if self.state == SessionState::LoggedOut || self.pending.iter().any(|k| k.is_barrier()) {
+            return Err(Error::InvalidState);
+        }
+
+        let authenticated = self.state == SessionState::Authenticated;
+        let permitted = match command {
+            Command::Capability | Command::Noop(_) | Command::Logout => true,
+            Command::StartTls => {
+                !authenticated
+                    && !self.tls
+                    && self.pending.is_empty()
+                    && self.capabilities.contains(&Capability::StartTls)
+            }
+            Command::Authenticate(..) => !authenticated && self.pending.is_empty(),
+            Command::UnAuthenticate => authenticated && self.pending.is_empty(),
+            Command::HaveSpace(..)
+            | Command::PutScript(..)
+            | Command::GetScript(_)
+            | Command::ListScripts
+            | Command::SetActive(_)
+            | Command::DeleteScript(_)
+            | Command::RenameScript(..)
+            | Command::CheckScript(_) => authenticated,
+        };
+        if !permitted {
+            return Err(Error::InvalidState);
+        }
+
+        let kind = ResponseKind::from(command);
+        self.pending.push_back(kind);
+        Ok(kind)
+    }
+
+    /// Records a SASL challenge sent in response to AUTHENTICATE. The
+    /// authentication exchange remains in progress.
+    pub fn receive_challenge(&mut self) -> Result<(), Error> {
+        match self.expected() {
+            Some(ResponseKind::Authenticate) => Ok(()),
+            _ => Err(Error::InvalidState),
+        }
+    }
+
+    /// Records a response that includes a capability listing: the greeting,
+    /// the response to CAPABILITY, or the listing sent after STARTTLS.
+    pub fn receive_capabilities(
+        &mut self,
+        capabilities: Vec<Capability>,
+        response: &Response,
+    ) -> Result<(), Error> {
+        match self.expected() {
+            Some(kind) if kind.has_capabilities() => {
+                if response.tag == OkNoBye::Ok {
+                    self.capabilities = capabilities;
+                }
+                self.complete(response)
+            }
+            _ => Err(Error::InvalidState),
+        }
+    }
+
+    /// Records the response that completes the oldest outstanding command.
+    /// This also accepts the response carried by `Error::UnsolicitedBye`,
+    /// whatever response was expected.
+    pub fn receive(&mut self, response: &Response) -> Result<(), Error> {
+        match self.expected() {
+            _ if response.tag == OkNoBye::Bye => self.complete(response),
+            Some(kind) if !kind.has_capabilities() => self.complete(response),
+            _ => Err(Error::InvalidState),
+        }
+    }
+
+    fn complete(&mut self, response: &Response) -> Result<(), Error> {
+        let kind = self.pending.pop_front();
+        match (kind, response.tag) {
+            (_, OkNoBye::Bye) | (Some(ResponseKind::Logout), _) => {
+                self.state = SessionState::LoggedOut;
+                self.pending.clear();
+            }
+            (Some(ResponseKind::StartTls), OkNoBye::Ok) => {
+                self.tls = true;
+                self.pending.push_front(ResponseKind::Capability);
+            }
+            (Some(ResponseKind::Authenticate), OkNoBye::Ok) => {
+                self.state = SessionState::Authenticated;
+            }
+            (Some(ResponseKind::UnAuthenticate), OkNoBye::Ok) => {
+                self.state = SessionState::NonAuthenticated;
+            }
+            (Some(ResponseKind::Greeting), OkNoBye::No) => {
+                return Err(Error::Refused(response.clone()))
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_response_kind_parse() {
+    assert_eq!(
+        ResponseKind::GetScript.parse("{5}\r\nkeep;\r\nOK\r\nNO\r\n"),
+        Ok(("NO\r\n", CommandResponse::Script("keep;".to_string(), ok())))
+    );
+    assert!(matches!(
+        ResponseKind::GetScript.parse("NO (NONEXISTENT)\r\n"),
+        Ok(("", CommandResponse::No(_)))
+    ));
+    assert!(matches!(
+        ResponseKind::ListScripts.parse("\"a\"\r\nBYE\r\n"),
+        Ok(("", CommandResponse::Bye(_)))
+    ));
+    assert!(matches!(
+        ResponseKind::Noop.parse("BYE (TRYLATER)\r\n"),
+        Ok(("", CommandResponse::Bye(_)))
+    ));
+    assert!(matches!(
+        ResponseKind::Noop.parse("NO (TRYLATER)\r\n"),
+        Ok(("", CommandResponse::No(_)))
+    ));
+    assert_eq!(
+        ResponseKind::Authenticate.parse("\"abcd\"\r\n"),
+        Ok(("", CommandResponse::Challenge("abcd".to_string())))
+    );
+    assert!(matches!(
+        ResponseKind::Greeting.parse("NO\r\n"),
+        Err(Error::Refused(_))
+    ));
+    assert!(matches!(
+        ResponseKind::PutScript.parse("OK"),
+        Err(Error::IncompleteResponse(_))
+    ));
+}
+
+#[cfg(test)]
+fn ok() -> Response {
+    Response {
+        tag: OkNoBye::Ok,
+        code: None,
+        human: None,
+    }
+}
+
+#[test]
+fn test_session_login() {
+    let mut session = Session::new();
+    assert_eq!(session.expected(), Some(ResponseKind::Greeting));
+    assert_eq!(session.send(&Command::Capability), Err(Error::InvalidState));
+
+    session
+        .receive_capabilities(vec![Capability::StartTls], &ok())
+        .unwrap();
+    assert_eq!(session.expected(), None);
+    assert_eq!(
+        session.send(&Command::put_script("a", "keep;").unwrap()),
+        Err(Error::InvalidState)
+    );
+
+    assert_eq!(session.send(&Command::StartTls), Ok(ResponseKind::StartTls));
+    assert_eq!(session.send(&Command::noop()), Err(Error::InvalidState));
+    session.receive(&ok()).unwrap();
+    assert!(session.is_tls());
+    assert_eq!(session.expected(), Some(ResponseKind::Capability));
+    session
+        .receive_capabilities(vec![Capability::Sasl(vec!["PLAIN".into()])], &ok())
+        .unwrap();
+    assert_eq!(session.capabilities().len(), 1);
+    assert_eq!(session.send(&Command::StartTls), Err(Error::InvalidState));
+
+    session.send(&Command::authenticate("PLAIN", None)).unwrap();
+    session.receive_challenge().unwrap();
+    session.receive(&ok()).unwrap();
+    assert_eq!(session.state(), SessionState::Authenticated);
+    assert_eq!(
+        session.send(&Command::authenticate("PLAIN", None)),
+        Err(Error::InvalidState)
+    );
+}
+
+#[test]
+fn test_session_pipelining() {
+    let mut session = Session::new();
+    session.receive_capabilities(Vec::new(), &ok()).unwrap();
+    session.send(&Command::authenticate("PLAIN", None)).unwrap();
+    session.receive(&ok()).unwrap();
+
+    assert_eq!(
+        session.send(&Command::ListScripts),
+        Ok(ResponseKind::ListScripts)
+    );
+    assert_eq!(
+        session.send(&Command::get_script("a").unwrap()),
+        Ok(ResponseKind::GetScript)
+    );
+    assert_eq!(session.send(&Command::Logout), Ok(ResponseKind::Logout));
+    assert_eq!(session.send(&Command::noop()), Err(Error::InvalidState));
+
+    assert_eq!(session.expected(), Some(ResponseKind::ListScripts));
+    assert_eq!(
+        session.receive_capabilities(Vec::new(), &ok()),
+        Err(Error::InvalidState)
+    );
+    session.receive(&ok()).unwrap();
+    assert_eq!(session.expected(), Some(ResponseKind::GetScript));
+    session.receive(&ok()).unwrap();
+    session.receive(&ok()).unwrap();
+    assert_eq!(session.state(), SessionState::LoggedOut);
+    assert_eq!(session.send(&Command::noop()), Err(Error::InvalidState));
+}
+
+#[test]
+fn test_session_bye() {
+    let mut session = Session::new();
+    session.receive_capabilities(Vec::new(), &ok()).unwrap();
+    session.send(&Command::noop()).unwrap();
+    session
+        .receive(&Response {
+            tag: OkNoBye::Bye,
+            code: None,
+            human: None,
+        })
+        .unwrap();
+    assert_eq!(session.state(), SessionState::LoggedOut);
+    assert_eq!(session.expected(), None);
+}
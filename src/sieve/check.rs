@@ -0,0 +1,207 @@
+use super::{Argument, Command, Script, Span, Str};
+use crate::types::Capability;
+use crate::validator::Diagnostic;
+
+// Comparators every implementation supports, whether announced or not.
+const IMPLICIT_EXTENSIONS: [&str; 2] = ["comparator-i;octet", "comparator-i;ascii-casemap"];
+
+// Tags of the enotify `notify` command followed by a value.
+const NOTIFY_TAGS: [&str; 4] = ["from", "importance", "message", "options"];
+
+impl Script {
+    /// The extensions named by the script's `require` commands.
+    pub fn requirements(&self) -> Vec<&Str> {
+        let mut requirements = Vec::new();
+        visit(&self.commands, &mut |command| {
+            if command.name.eq_ignore_ascii_case("require") {
+                for argument in &command.arguments {
+                    match argument {
+                        Argument::String(string) => requirements.push(string),
+                        Argument::StringList(strings, _) => requirements.extend(strings),
+                        _ => {}
+                    }
+                }
+            }
+        });
+        requirements
+    }
+}
+
+/// Checks `script` against the capabilities announced by a server,
+/// reporting every extension required but not in `Capability::Sieve`,
+/// notification method not in `Capability::Notify`, and redirect beyond
+/// `Capability::MaxRedirects`, in the order they appear in the script.
+pub fn check(script: &Script, capabilities: &[Capability]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let extensions = capabilities
+        .iter()
+        .find_map(|capability| match capability {
+            Capability::Sieve(extensions) => Some(&extensions[..]),
+            _ => None,
+        })
+        .unwrap_or_default();
+    for requirement in script.requirements() {
+        let name = requirement.value.as_str();
+        if !extensions.iter().any(|e| e == name) && !IMPLICIT_EXTENSIONS.contains(&name) {
+            diagnostics.push(Diagnostic::error(
+                Some(requirement.span.position()),
+                format!("extension \"{}\" is not supported by the server", name),
+            ));
+        }
+    }
+
+    let methods = capabilities.iter().find_map(|capability| match capability {
+        Capability::Notify(methods) => Some(methods),
+        _ => None,
+    });
+    if let Some(methods) = methods {
+        visit(&script.commands, &mut |command| {
+            let method = match notify_method(command) {
+                Some(method) if !method.value.contains("${") => method,
+                _ => return,
+            };
+            let scheme = method.value.split(':').next().unwrap_or_default();
+            if !methods.iter().any(|m| m.eq_ignore_ascii_case(scheme)) {
+                diagnostics.push(Diagnostic::error(
+                    Some(method.span.position()),
+                    format!(
+                        "notification method \"{}\" is not supported by the server",
+                        scheme
+                    ),
+                ));
+            }
+        });
+    }
+
+    let max_redirects = capabilities.iter().find_map(|capability| match capability {
+        Capability::MaxRedirects(max) => Some(*max),
+        _ => None,
+    });
+    if let Some(max) = max_redirects {
+        let path = redirects(&script.commands);
+        if let Some(span) = path.get(max) {
+            diagnostics.push(Diagnostic::error(
+                Some(span.position()),
+                format!(
+                    "the script may redirect {} times, but the server allows {}",
+                    path.len(),
+                    max
+                ),
+            ));
+        }
+    }
+
+    diagnostics.sort_by_key(|diagnostic| diagnostic.position);
+    diagnostics
+}
+
+// Calls `f` with each command, including those in blocks.
+fn visit<'a>(commands: &'a [Command], f: &mut impl FnMut(&'a Command)) {
+    for command in commands {
+        f(command);
+        if let Some(block) = &command.block {
+            visit(block, f);
+        }
+    }
+}
+
+// The method of an enotify `notify` command: its positional argument.
+fn notify_method(command: &Command) -> Option<&Str> {
+    if !command.name.eq_ignore_ascii_case("notify") {
+        return None;
+    }
+    let mut arguments = command.arguments.iter();
+    while let Some(argument) = arguments.next() {
+        match argument {
+            Argument::Tag(tag) if NOTIFY_TAGS.iter().any(|t| tag.name.eq_ignore_ascii_case(t)) => {
+                arguments.next();
+            }
+            Argument::String(method) => return Some(method),
+            _ => {}
+        }
+    }
+    None
+}
+
+// The redirects on the path through `commands` that redirects most often,
+// taking the branch of each `if` chain with the most.
+fn redirects(commands: &[Command]) -> Vec<Span> {
+    let mut path = Vec::new();
+    let mut chain: Option<Vec<Span>> = None;
+    for command in commands {
+        let is = |name: &str| command.name.eq_ignore_ascii_case(name);
+        let branch = command.block.as_deref().map(redirects).unwrap_or_default();
+        match &mut chain {
+            Some(longest) if is("elsif") || is("else") => {
+                if branch.len() > longest.len() {
+                    *longest = branch;
+                }
+                continue;
+            }
+            _ => path.extend(chain.take().unwrap_or_default()),
+        }
+        if is("if") {
+            chain = Some(branch);
+        } else {
+            if is("redirect") {
+                path.push(command.span);
+            }
+            path.extend(branch);
+        }
+    }
+    path.extend(chain.unwrap_or_default());
+    path
+}
+
+#[test]
+fn test_check() {
+    let capabilities = [
+        Capability::Sieve(vec!["fileinto".to_string(), "enotify".to_string()]),
+        Capability::Notify(vec!["mailto".to_string()]),
+        Capability::MaxRedirects(2),
+    ];
+    let violations = |text: &str| {
+        check(&super::parse(text).unwrap(), &capabilities)
+            .iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let valid = "require [\"fileinto\", \"enotify\", \"comparator-i;octet\"];\r\n\
+                 notify :options [\"a\"] :message \"hi\" \"MAILTO:a@example.com\";\r\n\
+                 notify \"${method}\";\r\n\
+                 if true { redirect \"a@example.com\"; redirect \"b@example.com\"; }\r\n\
+                 elsif false { redirect \"c@example.com\"; }\r\n\
+                 else { fileinto \"x\"; }\r\n";
+    assert_eq!(violations(valid), Vec::<String>::new());
+
+    assert_eq!(
+        violations(
+            "require \"editheader\";\r\n\
+             require [\"fileinto\", \"vacation\"];\r\n\
+             notify :from \"tel:1\" \"xmpp:a@example.com\";\r\n\
+             if true { redirect \"a\"; }\r\n\
+             redirect \"b\";\r\n\
+             if false { keep; } else { redirect \"c\"; }\r\n"
+        ),
+        [
+            "line 1, column 9: extension \"editheader\" is not supported by the server",
+            "line 2, column 22: extension \"vacation\" is not supported by the server",
+            "line 3, column 22: notification method \"xmpp\" is not supported by the server",
+            "line 6, column 27: the script may redirect 3 times, but the server allows 2",
+        ]
+    );
+
+    // Without the capabilities, only implicit extensions are supported,
+    // and notification methods and redirects go unchecked.
+    let script = super::parse("require \"fileinto\"; notify \"xmpp:a\"; redirect \"a\";").unwrap();
+    assert_eq!(check(&script, &[]).len(), 1);
+    assert_eq!(
+        script
+            .requirements()
+            .iter()
+            .map(|s| s.value.as_str())
+            .collect::<Vec<_>>(),
+        ["fileinto"]
+    );
+}
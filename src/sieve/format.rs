@@ -0,0 +1,292 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::slice;
+
+use super::{Argument, Command, Comment, Quantifier, Script, Str, Test, Tests};
+
+const INDENT: &str = "    ";
+
+/// Renders the script in a canonical form: one command to a line, blocks
+/// indented by four spaces, `elsif` and `else` following the closing brace,
+/// single spaces between arguments, and CRLF line endings. Strings are
+/// quoted with only `"` and `\` escaped, and multi-line strings stay
+/// multi-line. Comments keep their text and order, each on its own line or
+/// after the command or `text:` it followed on the same line.
+///
+/// Parsing the result gives the same script, apart from the spans.
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut writer = Writer {
+            out: String::new(),
+            comments: self.comments.iter().peekable(),
+            depth: 0,
+            line_open: false,
+        };
+        writer.commands(&self.commands, usize::MAX);
+        writer.comments_before(usize::MAX);
+        f.write_str(&writer.out)
+    }
+}
+
+struct Writer<'a> {
+    out: String,
+    // The comments yet to be written.
+    comments: Peekable<slice::Iter<'a, Comment>>,
+    depth: usize,
+    // Whether anything has been written to the current line.
+    line_open: bool,
+}
+
+impl Writer<'_> {
+    // Writes `s`, after a space if the line is open, otherwise after the
+    // indentation.
+    fn item(&mut self, s: &str) {
+        if self.line_open {
+            self.out.push(' ');
+        }
+        self.word(s);
+    }
+
+    // Writes `s` directly after whatever the line holds.
+    fn word(&mut self, s: &str) {
+        if !self.line_open {
+            for _ in 0..self.depth {
+                self.out.push_str(INDENT);
+            }
+            self.line_open = true;
+        }
+        self.out.push_str(s);
+    }
+
+    fn end_line(&mut self) {
+        if self.line_open {
+            self.out.push_str("\r\n");
+            self.line_open = false;
+        }
+    }
+
+    fn comment(&mut self, comment: &Comment) {
+        match comment.bracketed {
+            true => self.item(&format!("/*{}*/", comment.text)),
+            false => self.item(&format!("#{}", comment.text)),
+        }
+        self.end_line();
+    }
+
+    // Writes the comments starting before `offset`, each on its own line.
+    fn comments_before(&mut self, offset: usize) {
+        while let Some(comment) = self.comments.next_if(|c| c.span.start < offset) {
+            self.end_line();
+            self.comment(comment);
+        }
+    }
+
+    // Writes `commands`, which lie in a block ending at offset `end`.
+    fn commands(&mut self, commands: &[Command], end: usize) {
+        let mut previous: Option<&Command> = None;
+        for command in commands {
+            let follows_block = self.line_open
+                && previous.is_some_and(|previous| previous.block.is_some())
+                && ["elsif", "else"]
+                    .iter()
+                    .any(|name| command.name.eq_ignore_ascii_case(name))
+                && self
+                    .comments
+                    .peek()
+                    .is_none_or(|c| c.span.start >= command.span.start);
+            if !follows_block {
+                self.end_line();
+                self.comments_before(command.span.start);
+            }
+            self.command(command);
+            // A comment that followed the command on the same line, within
+            // the block, stays there.
+            if let Some(comment) = self.comments.next_if(|c| {
+                c.span.start >= command.span.end
+                    && c.span.start < end
+                    && c.span.line == command.span.end_line
+            }) {
+                self.comment(comment);
+            }
+            previous = Some(command);
+        }
+        self.end_line();
+    }
+
+    fn command(&mut self, command: &Command) {
+        self.item(&command.name);
+        self.arguments(&command.arguments, &command.tests);
+        match &command.block {
+            None => self.word(";"),
+            Some(block) => {
+                self.item("{");
+                self.end_line();
+                self.depth += 1;
+                let end = command.span.end.saturating_sub(1);
+                self.commands(block, end);
+                self.comments_before(end);
+                self.depth -= 1;
+                self.word("}");
+            }
+        }
+    }
+
+    fn arguments(&mut self, arguments: &[Argument], tests: &Option<Tests>) {
+        for argument in arguments {
+            match argument {
+                Argument::String(string) => self.string(string, true),
+                Argument::StringList(strings, _) => {
+                    self.item("[");
+                    for (i, string) in strings.iter().enumerate() {
+                        if i > 0 {
+                            self.word(",");
+                        }
+                        self.string(string, i > 0);
+                    }
+                    self.word("]");
+                }
+                Argument::Number(number) => {
+                    let quantifier = match number.quantifier {
+                        None => "",
+                        Some(Quantifier::K) => "K",
+                        Some(Quantifier::M) => "M",
+                        Some(Quantifier::G) => "G",
+                    };
+                    self.item(&format!("{}{}", number.value, quantifier));
+                }
+                Argument::Tag(tag) => self.item(&format!(":{}", tag.name)),
+            }
+        }
+        match tests {
+            Some(Tests::Single(test)) => self.test(test, true),
+            Some(Tests::List(tests, _)) => {
+                self.item("(");
+                for (i, test) in tests.iter().enumerate() {
+                    if i > 0 {
+                        self.word(",");
+                    }
+                    self.test(test, i > 0);
+                }
+                self.word(")");
+            }
+            None => {}
+        }
+    }
+
+    fn test(&mut self, test: &Test, spaced: bool) {
+        match spaced {
+            true => self.item(&test.name),
+            false => self.word(&test.name),
+        }
+        self.arguments(&test.arguments, &test.tests);
+    }
+
+    fn string(&mut self, string: &Str, spaced: bool) {
+        if !string.multiline {
+            let quoted = quote(&string.value);
+            return match spaced {
+                true => self.item(&quoted),
+                false => self.word(&quoted),
+            };
+        }
+        match spaced {
+            true => self.item("text:"),
+            false => self.word("text:"),
+        }
+        if let Some(comment) = self
+            .comments
+            .next_if(|c| c.span.start > string.span.start && c.span.end < string.span.end)
+        {
+            self.comment(comment);
+        }
+        self.end_line();
+        for line in string.value.split_inclusive('\n') {
+            if line.starts_with('.') {
+                self.out.push('.');
+            }
+            self.out.push_str(line);
+        }
+        if !string.value.is_empty() && !string.value.ends_with('\n') {
+            self.out.push_str("\r\n");
+        }
+        self.out.push_str(".\r\n");
+    }
+}
+
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[test]
+fn test_format() {
+    let text = "# Filters\r\n\
+                require [\"fileinto\",\"envelope\"];  /* b */\r\n\
+                if anyof(header :contains [\"subject\"] \"a\\\\b\",size :over 10K){fileinto\r\n\
+                text: # x\r\n\
+                ..dot\r\n\
+                Lists\r\n\
+                .\r\n\
+                ;  # after\r\n\
+                # inside\r\n\
+                }\r\n\
+                # between\r\n\
+                elsif not exists \"x\" {} ELSE { if true { stop; } else { keep; } }\r\n\
+                /* last\r\n*/";
+    let script = super::parse(text).unwrap();
+    let formatted = script.to_string();
+    assert_eq!(
+        formatted,
+        "# Filters\r\n\
+         require [\"fileinto\", \"envelope\"]; /* b */\r\n\
+         if anyof (header :contains [\"subject\"] \"a\\\\b\", size :over 10K) {\r\n\
+         \x20   fileinto text: # x\r\n\
+         ..dot\r\n\
+         Lists\r\n\
+         .\r\n\
+         \x20   ; # after\r\n\
+         \x20   # inside\r\n\
+         }\r\n\
+         # between\r\n\
+         elsif not exists \"x\" {\r\n\
+         } ELSE {\r\n\
+         \x20   if true {\r\n\
+         \x20       stop;\r\n\
+         \x20   } else {\r\n\
+         \x20       keep;\r\n\
+         \x20   }\r\n\
+         }\r\n\
+         /* last\r\n*/\r\n"
+    );
+    let reparsed = super::parse(&formatted).unwrap();
+    assert_eq!(reparsed.clone().without_spans(), script.without_spans());
+    assert_eq!(reparsed.to_string(), formatted);
+
+    for text in [
+        "if true {} # x\r\nelsif false {}",
+        "if true {} /* b */ else {}",
+        "if true { stop; } # t\r\nkeep;",
+        "if true {\r\n  stop; # s\r\n} # t\r\n",
+        "keep; /* a */ # b\r\n",
+    ] {
+        let once = super::parse(text).unwrap().to_string();
+        let twice = super::parse(&once).unwrap().to_string();
+        assert_eq!(twice, once, "{:?}", text);
+    }
+    assert_eq!(
+        super::parse("if true { stop; } # t").unwrap().to_string(),
+        "if true {\r\n    stop;\r\n} # t\r\n"
+    );
+
+    assert_eq!(super::parse("").unwrap().to_string(), "");
+    let empty = super::parse("reject text:\r\n.\r\n;").unwrap();
+    assert_eq!(empty.to_string(), "reject text:\r\n.\r\n;\r\n");
+}
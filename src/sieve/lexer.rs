@@ -0,0 +1,350 @@
+use super::{Quantifier, Span, SyntaxError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    Identifier(String),
+    /// A tag, without its leading colon.
+    Tag(String),
+    Number(u64, Option<Quantifier>),
+    /// A quoted string, with escapes removed.
+    QuotedString(String),
+    /// A `text:` string, with dot-stuffing removed.
+    MultiLineString(String),
+    /// The text of a `#` comment, up to the end of the line.
+    HashComment(String),
+    /// The text between `/*` and `*/`.
+    BracketComment(String),
+    Semicolon,
+    Comma,
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    LeftBrace,
+    RightBrace,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// Splits a script into [`Token`]s, including comments. Ends after the
+/// first error.
+#[derive(Debug, Clone)]
+pub struct Lexer<'a> {
+    text: &'a str,
+    offset: usize,
+    line: usize,
+    column: usize,
+    // A comment following `text:`, returned after the string.
+    pending: Option<Token>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(text: &'a str) -> Lexer<'a> {
+        Lexer {
+            text,
+            offset: 0,
+            line: 1,
+            column: 1,
+            pending: None,
+        }
+    }
+
+    /// The empty span at the current position, which is the end of the
+    /// script once all tokens have been read.
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.offset,
+            end: self.offset,
+            line: self.line,
+            column: self.column,
+            end_line: self.line,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.offset..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let next = self.peek() == Some(c);
+        if next {
+            self.bump();
+        }
+        next
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let start = self.offset;
+        while self.peek().is_some_and(&f) {
+            self.bump();
+        }
+        &self.text[start..self.offset]
+    }
+
+    // Reads the rest of the line, consuming the line break but returning
+    // the line without it, unless the script has ended.
+    fn line(&mut self) -> Option<&'a str> {
+        let line = self.take_while(|c| c != '\n');
+        match self.bump() {
+            Some(_) => Some(line.strip_suffix('\r').unwrap_or(line)),
+            None => None,
+        }
+    }
+
+    fn token(&mut self) -> Result<Option<Token>, SyntaxError> {
+        self.take_while(|c| matches!(c, ' ' | '\t' | '\r' | '\n'));
+        let start = self.span();
+        let c = match self.bump() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let kind = match c {
+            '#' => {
+                let text = self.take_while(|c| c != '\n');
+                TokenKind::HashComment(text.strip_suffix('\r').unwrap_or(text).to_owned())
+            }
+            '/' if self.eat('*') => match self.rest().find("*/") {
+                Some(i) => {
+                    let text = &self.rest()[..i];
+                    let end = self.offset + i + 2;
+                    while self.offset < end {
+                        self.bump();
+                    }
+                    TokenKind::BracketComment(text.to_owned())
+                }
+                None => return Err(SyntaxError::new(start, "unterminated comment")),
+            },
+            '"' => TokenKind::QuotedString(self.quoted_string(start)?),
+            ':' => match self.identifier() {
+                "" => return Err(SyntaxError::new(start, "expected a tag after ':'")),
+                name => TokenKind::Tag(name.to_owned()),
+            },
+            '0'..='9' => self.number(start)?,
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                self.identifier();
+                let name = &self.text[start.start..self.offset];
+                if name.eq_ignore_ascii_case("text") && self.eat(':') {
+                    TokenKind::MultiLineString(self.multi_line_string(start)?)
+                } else {
+                    TokenKind::Identifier(name.to_owned())
+                }
+            }
+            ';' => TokenKind::Semicolon,
+            ',' => TokenKind::Comma,
+            '(' => TokenKind::LeftParen,
+            ')' => TokenKind::RightParen,
+            '[' => TokenKind::LeftBracket,
+            ']' => TokenKind::RightBracket,
+            '{' => TokenKind::LeftBrace,
+            '}' => TokenKind::RightBrace,
+            c => return Err(SyntaxError::new(start, format!("unexpected {:?}", c))),
+        };
+        Ok(Some(Token {
+            kind,
+            span: Span {
+                end: self.offset,
+                end_line: self.line,
+                ..start
+            },
+        }))
+    }
+
+    fn identifier(&mut self) -> &'a str {
+        self.take_while(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    fn number(&mut self, start: Span) -> Result<TokenKind, SyntaxError> {
+        self.take_while(|c| c.is_ascii_digit());
+        let value = self.text[start.start..self.offset]
+            .parse()
+            .map_err(|_| SyntaxError::new(start, "number too large"))?;
+        let quantifier = match self.peek() {
+            Some('K' | 'k') => Some(Quantifier::K),
+            Some('M' | 'm') => Some(Quantifier::M),
+            Some('G' | 'g') => Some(Quantifier::G),
+            _ => None,
+        };
+        if quantifier.is_some() {
+            self.bump();
+        }
+        if self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(SyntaxError::new(start, "invalid number"));
+        }
+        Ok(TokenKind::Number(value, quantifier))
+    }
+
+    // Reads the rest of a quoted string. A backslash makes the following
+    // character literal.
+    fn quoted_string(&mut self, start: Span) -> Result<String, SyntaxError> {
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.bump() {
+                    Some(c) => value.push(c),
+                    None => break,
+                },
+                Some(c) => value.push(c),
+                None => break,
+            }
+        }
+        Err(SyntaxError::new(start, "unterminated string"))
+    }
+
+    // Reads the rest of a multi-line string following `text:`, which ends
+    // with a line holding only a dot. A dot starting any other line is
+    // doubled.
+    fn multi_line_string(&mut self, start: Span) -> Result<String, SyntaxError> {
+        self.take_while(|c| c == ' ' || c == '\t');
+        if self.peek() == Some('#') {
+            let start = self.span();
+            self.bump();
+            let text = self.take_while(|c| c != '\n');
+            self.pending = Some(Token {
+                kind: TokenKind::HashComment(text.strip_suffix('\r').unwrap_or(text).to_owned()),
+                span: Span {
+                    end: self.offset,
+                    end_line: self.line,
+                    ..start
+                },
+            });
+            self.bump();
+        } else if !(self.eat('\n')
+            || (self.rest().starts_with("\r\n") && self.eat('\r') && self.eat('\n')))
+        {
+            return Err(SyntaxError::new(
+                self.span(),
+                "expected a line break after 'text:'",
+            ));
+        }
+        let mut value = String::new();
+        loop {
+            let line_start = self.offset;
+            let line = self.line();
+            let with_break = &self.text[line_start..self.offset];
+            match line {
+                Some(".") => return Ok(value),
+                Some(line) if line.starts_with("..") => value.push_str(&with_break[1..]),
+                Some(_) => value.push_str(with_break),
+                None if with_break == "." => return Ok(value),
+                None => return Err(SyntaxError::new(start, "unterminated multi-line string")),
+            }
+        }
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Result<Token, SyntaxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(token) = self.pending.take() {
+            return Some(Ok(token));
+        }
+        let token = self.token();
+        if token.is_err() {
+            self.offset = self.text.len();
+            self.pending = None;
+        }
+        token.transpose()
+    }
+}
+
+#[test]
+fn test_lexer() {
+    let kinds = |text| {
+        Lexer::new(text)
+            .map(|token| token.map(|token| token.kind))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    assert_eq!(
+        kinds("if size :over 10K { discard; } # done\r\n").unwrap(),
+        vec![
+            TokenKind::Identifier("if".to_string()),
+            TokenKind::Identifier("size".to_string()),
+            TokenKind::Tag("over".to_string()),
+            TokenKind::Number(10, Some(Quantifier::K)),
+            TokenKind::LeftBrace,
+            TokenKind::Identifier("discard".to_string()),
+            TokenKind::Semicolon,
+            TokenKind::RightBrace,
+            TokenKind::HashComment(" done".to_string()),
+        ]
+    );
+    assert_eq!(
+        kinds("[\"a\\\"\\\\\\b\", /* x\r\n* */ \"\"]").unwrap(),
+        vec![
+            TokenKind::LeftBracket,
+            TokenKind::QuotedString("a\"\\b".to_string()),
+            TokenKind::Comma,
+            TokenKind::BracketComment(" x\r\n* ".to_string()),
+            TokenKind::QuotedString(String::new()),
+            TokenKind::RightBracket,
+        ]
+    );
+    assert_eq!(
+        kinds("TEXT: # note\r\nline\r\n..dot\r\n.\r\n;").unwrap(),
+        vec![
+            TokenKind::MultiLineString("line\r\n.dot\r\n".to_string()),
+            TokenKind::HashComment(" note".to_string()),
+            TokenKind::Semicolon,
+        ]
+    );
+    assert_eq!(
+        kinds("text:\n\n.").unwrap(),
+        vec![TokenKind::MultiLineString("\n".to_string())]
+    );
+    assert_eq!(
+        kinds("text").unwrap()[0],
+        TokenKind::Identifier("text".into())
+    );
+
+    let error = |text| kinds(text).unwrap_err().to_string();
+    assert_eq!(error("keep;\r\n  @"), "line 2, column 3: unexpected '@'");
+    assert_eq!(error("a : b"), "line 1, column 3: expected a tag after ':'");
+    assert_eq!(error("10X"), "line 1, column 1: invalid number");
+    assert_eq!(
+        error("99999999999999999999"),
+        "line 1, column 1: number too large"
+    );
+    assert_eq!(
+        error("text: x\r\n.\r\n"),
+        "line 1, column 7: expected a line break after 'text:'"
+    );
+    assert_eq!(
+        error("x text:\r\n.x\r\n"),
+        "line 1, column 3: unterminated multi-line string"
+    );
+
+    let mut lexer = Lexer::new("\"ü\"\n  x");
+    let string = lexer.next().unwrap().unwrap().span;
+    assert_eq!(
+        (string.start, string.end, string.line, string.column),
+        (0, 4, 1, 1)
+    );
+    let x = lexer.next().unwrap().unwrap().span;
+    assert_eq!((x.start, x.end, x.line, x.column), (7, 8, 2, 3));
+    assert_eq!(lexer.next(), None);
+}
@@ -0,0 +1,264 @@
+//! Sieve scripts (RFC 5228) read into a syntax tree.
+//!
+//! [`parse`] reads a script into a [`Script`], each element of which
+//! carries the [`Span`] of the text it was read from. Only the syntax is
+//! checked: commands, tests and tags are not interpreted. [`check`]
+//! compares the extensions a script requires, and the notification methods
+//! and redirects it uses, with the capabilities announced by a server.
+//! Displaying a [`Script`] formats it canonically.
+
+use std::error;
+use std::fmt;
+
+use crate::validator::{Diagnostic, Position};
+
+mod check;
+mod format;
+mod lexer;
+mod parser;
+
+pub use check::check;
+pub use lexer::{Lexer, Token, TokenKind};
+pub use parser::parse;
+
+/// Where an element lies in a script: its byte offsets, the line and
+/// column at which it starts, and the line at which it ends. Lines and
+/// columns count from 1, and columns count characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+}
+
+impl Span {
+    /// The position of the start, for a [`Diagnostic`].
+    pub fn position(&self) -> Position {
+        Position {
+            line: self.line,
+            column: Some(self.column),
+        }
+    }
+
+    /// The span from the start of this one to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            end_line: other.end_line,
+            ..self
+        }
+    }
+}
+
+/// A parsed script: its commands, and the comments found between and
+/// within them.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Script {
+    pub commands: Vec<Command>,
+    pub comments: Vec<Comment>,
+}
+
+impl Script {
+    /// The script with every span reset to the default, so that scripts
+    /// can be compared by content, as before and after formatting.
+    pub fn without_spans(mut self) -> Script {
+        fn clear_arguments(arguments: &mut [Argument], tests: &mut Option<Tests>) {
+            for argument in arguments {
+                match argument {
+                    Argument::String(string) => string.span = Span::default(),
+                    Argument::StringList(strings, span) => {
+                        *span = Span::default();
+                        for string in strings {
+                            string.span = Span::default();
+                        }
+                    }
+                    Argument::Number(number) => number.span = Span::default(),
+                    Argument::Tag(tag) => tag.span = Span::default(),
+                }
+            }
+            let tests = match tests {
+                Some(Tests::Single(test)) => std::slice::from_mut(&mut **test),
+                Some(Tests::List(tests, span)) => {
+                    *span = Span::default();
+                    tests
+                }
+                None => return,
+            };
+            for test in tests {
+                test.span = Span::default();
+                clear_arguments(&mut test.arguments, &mut test.tests);
+            }
+        }
+        fn clear_commands(commands: &mut [Command]) {
+            for command in commands {
+                command.span = Span::default();
+                clear_arguments(&mut command.arguments, &mut command.tests);
+                if let Some(block) = &mut command.block {
+                    clear_commands(block);
+                }
+            }
+        }
+        clear_commands(&mut self.commands);
+        for comment in &mut self.comments {
+            comment.span = Span::default();
+        }
+        self
+    }
+}
+
+/// A command, such as `keep;` or `if true { stop; }`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    /// The identifier naming the command, as written.
+    pub name: String,
+    pub arguments: Vec<Argument>,
+    pub tests: Option<Tests>,
+    /// The commands between braces, if the command ends with a block
+    /// rather than a semicolon.
+    pub block: Option<Vec<Command>>,
+    pub span: Span,
+}
+
+/// A test, such as `header :is "from" "a@example.com"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Test {
+    /// The identifier naming the test, as written.
+    pub name: String,
+    pub arguments: Vec<Argument>,
+    pub tests: Option<Tests>,
+    pub span: Span,
+}
+
+/// The tests following the other arguments of a command or test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tests {
+    /// A single test, as taken by `if`.
+    Single(Box<Test>),
+    /// A parenthesized list of tests, as taken by `anyof`.
+    List(Vec<Test>, Span),
+}
+
+impl Tests {
+    pub fn as_slice(&self) -> &[Test] {
+        match self {
+            Tests::Single(test) => std::slice::from_ref(test),
+            Tests::List(tests, _) => tests,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Tests::Single(test) => test.span,
+            Tests::List(_, span) => *span,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Argument {
+    String(Str),
+    /// A bracketed list of strings, which may hold only one.
+    StringList(Vec<Str>, Span),
+    Number(Number),
+    Tag(Tag),
+}
+
+impl Argument {
+    pub fn span(&self) -> Span {
+        match self {
+            Argument::String(string) => string.span,
+            Argument::StringList(_, span) => *span,
+            Argument::Number(number) => number.span,
+            Argument::Tag(tag) => tag.span,
+        }
+    }
+}
+
+/// A string, with escapes or dot-stuffing removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Str {
+    pub value: String,
+    /// Whether it was written as a multi-line `text:` string rather than
+    /// quoted.
+    pub multiline: bool,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Number {
+    /// The number as written, before the quantifier is applied.
+    pub value: u64,
+    pub quantifier: Option<Quantifier>,
+    pub span: Span,
+}
+
+impl Number {
+    /// The value with the quantifier applied, unless that overflows.
+    pub fn scaled(&self) -> Option<u64> {
+        let factor = match self.quantifier {
+            None => 1,
+            Some(Quantifier::K) => 1 << 10,
+            Some(Quantifier::M) => 1 << 20,
+            Some(Quantifier::G) => 1 << 30,
+        };
+        self.value.checked_mul(factor)
+    }
+}
+
+/// The suffix multiplying a number by 2^10, 2^20 or 2^30.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantifier {
+    K,
+    M,
+    G,
+}
+
+/// A tagged argument, such as `:contains`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    /// The identifier following the colon, as written.
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    /// The text after `#` up to the end of the line, or between `/*` and
+    /// `*/`.
+    pub text: String,
+    /// Whether it was written between `/*` and `*/`.
+    pub bracketed: bool,
+    pub span: Span,
+}
+
+/// Why a script could not be read, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl SyntaxError {
+    pub(crate) fn new(span: Span, message: impl Into<String>) -> SyntaxError {
+        SyntaxError {
+            message: message.into(),
+            span,
+        }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.span.position(), self.message)
+    }
+}
+
+impl error::Error for SyntaxError {}
+
+impl From<SyntaxError> for Diagnostic {
+    fn from(e: SyntaxError) -> Self {
+        Diagnostic::error(Some(e.span.position()), e.message)
+    }
+}
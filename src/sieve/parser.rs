@@ -0,0 +1,365 @@
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+use super::lexer::{Lexer, Token, TokenKind};
+use super::{Argument, Command, Comment, Number, Script, Span, Str, SyntaxError, Tag, Test, Tests};
+
+// Deepest nesting of blocks and tests accepted, so that a hostile script
+// cannot exhaust the stack.
+const MAX_NESTING: usize = 64;
+
+/// Reads a script, failing with the first syntax error.
+pub fn parse(text: &str) -> Result<Script, SyntaxError> {
+    let mut lexer = Lexer::new(text);
+    let mut tokens = Vec::new();
+    let mut comments = Vec::new();
+    for token in &mut lexer {
+        let Token { kind, span } = token?;
+        match kind {
+            TokenKind::HashComment(text) => comments.push(Comment {
+                text,
+                bracketed: false,
+                span,
+            }),
+            TokenKind::BracketComment(text) => comments.push(Comment {
+                text,
+                bracketed: true,
+                span,
+            }),
+            kind => tokens.push(Token { kind, span }),
+        }
+    }
+    let mut parser = Parser {
+        tokens: tokens.into_iter().peekable(),
+        end: lexer.span(),
+    };
+    let commands = parser.commands(0)?;
+    match parser.tokens.next() {
+        None => Ok(Script { commands, comments }),
+        Some(Token {
+            kind: TokenKind::RightBrace,
+            span,
+        }) => Err(SyntaxError::new(span, "unexpected '}'")),
+        Some(token) => Err(SyntaxError::new(token.span, "expected a command")),
+    }
+}
+
+struct Parser {
+    tokens: Peekable<IntoIter<Token>>,
+    // The end of the script.
+    end: Span,
+}
+
+impl Parser {
+    // The span of the next token, or the end of the script.
+    fn next_span(&mut self) -> Span {
+        self.tokens.peek().map_or(self.end, |token| token.span)
+    }
+
+    fn commands(&mut self, depth: usize) -> Result<Vec<Command>, SyntaxError> {
+        let mut commands = Vec::new();
+        while let Some(Token {
+            kind: TokenKind::Identifier(name),
+            span,
+        }) = self
+            .tokens
+            .next_if(|token| matches!(token.kind, TokenKind::Identifier(_)))
+        {
+            commands.push(self.command(name, span, depth)?);
+        }
+        Ok(commands)
+    }
+
+    fn command(&mut self, name: String, start: Span, depth: usize) -> Result<Command, SyntaxError> {
+        let (arguments, tests) = self.arguments(depth)?;
+        let mut command = Command {
+            name,
+            arguments,
+            tests,
+            block: None,
+            span: start,
+        };
+        match self.tokens.next() {
+            Some(Token {
+                kind: TokenKind::Semicolon,
+                span,
+            }) => command.span = start.to(span),
+            Some(Token {
+                kind: TokenKind::LeftBrace,
+                span: open,
+            }) => {
+                if depth >= MAX_NESTING {
+                    return Err(SyntaxError::new(open, "blocks are nested too deeply"));
+                }
+                command.block = Some(self.commands(depth + 1)?);
+                match self.tokens.next() {
+                    Some(Token {
+                        kind: TokenKind::RightBrace,
+                        span,
+                    }) => command.span = start.to(span),
+                    Some(token) => {
+                        return Err(SyntaxError::new(token.span, "expected a command or '}'"))
+                    }
+                    None => return Err(SyntaxError::new(open, "'{' is not closed")),
+                }
+            }
+            Some(token) => return Err(SyntaxError::new(token.span, "expected ';' or '{'")),
+            None => return Err(SyntaxError::new(self.end, "expected ';' or '{'")),
+        }
+        Ok(command)
+    }
+
+    // Reads the arguments of a command or test, ending with any tests.
+    fn arguments(&mut self, depth: usize) -> Result<(Vec<Argument>, Option<Tests>), SyntaxError> {
+        let mut arguments = Vec::new();
+        while let Some(Token { kind, span }) = self.tokens.next_if(|token| {
+            matches!(
+                token.kind,
+                TokenKind::QuotedString(_)
+                    | TokenKind::MultiLineString(_)
+                    | TokenKind::LeftBracket
+                    | TokenKind::Number(..)
+                    | TokenKind::Tag(_)
+            )
+        }) {
+            arguments.push(match kind {
+                TokenKind::LeftBracket => self.string_list(span)?,
+                TokenKind::Number(value, quantifier) => Argument::Number(Number {
+                    value,
+                    quantifier,
+                    span,
+                }),
+                TokenKind::Tag(name) => Argument::Tag(Tag { name, span }),
+                kind => Argument::String(string(Token { kind, span })?),
+            });
+        }
+        let tests = match self.tokens.peek().map(|token| &token.kind) {
+            Some(TokenKind::Identifier(_)) => Some(Tests::Single(Box::new(self.test(depth + 1)?))),
+            Some(TokenKind::LeftParen) => Some(self.test_list(depth + 1)?),
+            _ => None,
+        };
+        Ok((arguments, tests))
+    }
+
+    fn string_list(&mut self, open: Span) -> Result<Argument, SyntaxError> {
+        let mut strings = Vec::new();
+        loop {
+            match self.tokens.next() {
+                Some(token) => strings.push(string(token)?),
+                None => return Err(SyntaxError::new(open, "'[' is not closed")),
+            }
+            match self.tokens.next() {
+                Some(Token {
+                    kind: TokenKind::Comma,
+                    ..
+                }) => {}
+                Some(Token {
+                    kind: TokenKind::RightBracket,
+                    span,
+                }) => return Ok(Argument::StringList(strings, open.to(span))),
+                Some(token) => return Err(SyntaxError::new(token.span, "expected ',' or ']'")),
+                None => return Err(SyntaxError::new(open, "'[' is not closed")),
+            }
+        }
+    }
+
+    fn test(&mut self, depth: usize) -> Result<Test, SyntaxError> {
+        let (name, start) = match self.tokens.next() {
+            Some(Token {
+                kind: TokenKind::Identifier(name),
+                span,
+            }) => (name, span),
+            Some(token) => return Err(SyntaxError::new(token.span, "expected a test")),
+            None => return Err(SyntaxError::new(self.end, "expected a test")),
+        };
+        if depth > MAX_NESTING {
+            return Err(SyntaxError::new(start, "tests are nested too deeply"));
+        }
+        let (arguments, tests) = self.arguments(depth)?;
+        let end = match (&tests, arguments.last()) {
+            (Some(tests), _) => tests.span(),
+            (None, Some(argument)) => argument.span(),
+            (None, None) => start,
+        };
+        Ok(Test {
+            name,
+            arguments,
+            tests,
+            span: start.to(end),
+        })
+    }
+
+    fn test_list(&mut self, depth: usize) -> Result<Tests, SyntaxError> {
+        let open = self.next_span();
+        self.tokens.next();
+        let mut tests = Vec::new();
+        loop {
+            if self.tokens.peek().is_none() {
+                return Err(SyntaxError::new(open, "'(' is not closed"));
+            }
+            tests.push(self.test(depth)?);
+            match self.tokens.next() {
+                Some(Token {
+                    kind: TokenKind::Comma,
+                    ..
+                }) => {}
+                Some(Token {
+                    kind: TokenKind::RightParen,
+                    span,
+                }) => return Ok(Tests::List(tests, open.to(span))),
+                Some(token) => return Err(SyntaxError::new(token.span, "expected ',' or ')'")),
+                None => return Err(SyntaxError::new(open, "'(' is not closed")),
+            }
+        }
+    }
+}
+
+fn string(Token { kind, span }: Token) -> Result<Str, SyntaxError> {
+    let (value, multiline) = match kind {
+        TokenKind::QuotedString(value) => (value, false),
+        TokenKind::MultiLineString(value) => (value, true),
+        _ => return Err(SyntaxError::new(span, "expected a string")),
+    };
+    Ok(Str {
+        value,
+        multiline,
+        span,
+    })
+}
+
+#[test]
+fn test_parse() {
+    let script = parse(
+        "require [\"fileinto\", \"envelope\"];\r\n\
+         # Sort mail\r\n\
+         if anyof (header :contains \"subject\" \"[list]\", size :over 1M) {\r\n\
+         \x20   fileinto text:\r\n\
+         Lists\r\n\
+         .\r\n\
+         ;\r\n\
+         } elsif not exists \"x-spam\" { keep; /* end */ }\r\n",
+    )
+    .unwrap();
+    let names: Vec<_> = script.commands.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, ["require", "if", "elsif"]);
+
+    let require = &script.commands[0];
+    assert_eq!(require.span.start, 0);
+    assert_eq!(require.span.end, 33);
+    match &require.arguments[..] {
+        [Argument::StringList(strings, _)] => {
+            let values: Vec<_> = strings.iter().map(|s| s.value.as_str()).collect();
+            assert_eq!(values, ["fileinto", "envelope"]);
+        }
+        arguments => panic!("{:?}", arguments),
+    }
+
+    let if_ = &script.commands[1];
+    assert_eq!(if_.span.position().to_string(), "line 3, column 1");
+    let anyof = match &if_.tests {
+        Some(Tests::Single(test)) => test,
+        tests => panic!("{:?}", tests),
+    };
+    assert_eq!(anyof.name, "anyof");
+    let tests = anyof.tests.as_ref().unwrap().as_slice();
+    assert_eq!(tests.len(), 2);
+    assert_eq!(tests[0].name, "header");
+    assert_eq!(
+        tests[0].arguments[0],
+        Argument::Tag(Tag {
+            name: "contains".to_string(),
+            span: Span {
+                start: 65,
+                end: 74,
+                line: 3,
+                column: 18,
+                end_line: 3
+            }
+        })
+    );
+    match &tests[1].arguments[..] {
+        [Argument::Tag(_), Argument::Number(number)] => {
+            assert_eq!(number.scaled(), Some(1 << 20))
+        }
+        arguments => panic!("{:?}", arguments),
+    }
+    let fileinto = &if_.block.as_ref().unwrap()[0];
+    match &fileinto.arguments[..] {
+        [Argument::String(s)] => {
+            assert_eq!(s.value, "Lists\r\n");
+            assert!(s.multiline);
+        }
+        arguments => panic!("{:?}", arguments),
+    }
+
+    let elsif = &script.commands[2];
+    let not = elsif.tests.as_ref().unwrap().as_slice();
+    assert_eq!(not[0].name, "not");
+    assert_eq!(not[0].tests.as_ref().unwrap().as_slice()[0].name, "exists");
+    assert_eq!(elsif.block.as_ref().unwrap()[0].name, "keep");
+
+    let comments: Vec<_> = script
+        .comments
+        .iter()
+        .map(|c| (c.text.as_str(), c.bracketed, c.span.line))
+        .collect();
+    assert_eq!(comments, [(" Sort mail", false, 2), (" end ", true, 8)]);
+
+    assert_eq!(parse("").unwrap(), Script::default());
+    assert!(parse("if true {}").unwrap().commands[0]
+        .block
+        .as_ref()
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_parse_errors() {
+    let error = |text| parse(text).unwrap_err().to_string();
+    assert_eq!(error("keep"), "line 1, column 5: expected ';' or '{'");
+    assert_eq!(error("keep;\r\n}"), "line 2, column 1: unexpected '}'");
+    assert_eq!(error("\"keep\";"), "line 1, column 1: expected a command");
+    assert_eq!(
+        error("if true {\r\n  stop;\r\n"),
+        "line 1, column 9: '{' is not closed"
+    );
+    assert_eq!(
+        error("if true { stop; ]"),
+        "line 1, column 17: expected a command or '}'"
+    );
+    assert_eq!(
+        error("require [\"a\" \"b\"];"),
+        "line 1, column 14: expected ',' or ']'"
+    );
+    assert_eq!(
+        error("require [\"a\", 1];"),
+        "line 1, column 15: expected a string"
+    );
+    assert_eq!(
+        error("require [\"a\""),
+        "line 1, column 9: '[' is not closed"
+    );
+    assert_eq!(
+        error("if anyof (true; false) {}"),
+        "line 1, column 15: expected ',' or ')'"
+    );
+    assert_eq!(
+        error("if anyof (true, ) {}"),
+        "line 1, column 17: expected a test"
+    );
+    assert_eq!(
+        error("if anyof (true,"),
+        "line 1, column 10: '(' is not closed"
+    );
+    assert_eq!(
+        error("keep \"a\";\r\n  \"b"),
+        "line 2, column 3: unterminated string"
+    );
+
+    let nested = "else {".repeat(MAX_NESTING + 1);
+    assert!(error(&nested).ends_with("blocks are nested too deeply"));
+    let not = format!("if {}true {{}}", "not ".repeat(MAX_NESTING + 1));
+    assert!(error(&not).ends_with("tests are nested too deeply"));
+    let not = format!("if {}true {{}}", "not ".repeat(MAX_NESTING - 1));
+    assert!(parse(&not).is_ok());
+}
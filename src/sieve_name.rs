@@ -0,0 +1,147 @@
+use std::borrow::Borrow;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+use crate::error::Error;
+use crate::parser as p;
+
+/// A script name conforming to section 1.6 of RFC 5804: non-empty, free of
+/// control characters and line or paragraph separators, and in Unicode
+/// Normalization Form C.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct SieveName(String);
+
+impl SieveName {
+    /// Longest name accepted, in octets. RFC 5804 requires servers to accept
+    /// names of at least 128 characters.
+    pub const MAX_LEN: usize = 1024;
+
+    /// Checks that `name` is a valid script name. Fails with
+    /// `Error::InvalidInput` if it is not, including if it is not in NFC.
+    pub fn new(name: &str) -> Result<SieveName, Error> {
+        if is_valid(name) {
+            Ok(SieveName(name.to_owned()))
+        } else {
+            Err(Error::InvalidInput)
+        }
+    }
+
+    /// Like [`SieveName::new`], but first converts `name` to NFC.
+    pub fn new_normalized(name: &str) -> Result<SieveName, Error> {
+        SieveName::new(&name.nfc().collect::<String>())
+    }
+
+    // Wraps a name already checked with is_valid.
+    pub(crate) fn from_valid(name: String) -> SieveName {
+        debug_assert!(is_valid(&name));
+        SieveName(name)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+pub(crate) fn is_valid(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= SieveName::MAX_LEN
+        && !name.chars().any(p::is_bad_sieve_name_char)
+        && is_nfc(name)
+}
+
+#[test]
+fn test_sieve_name() {
+    assert_eq!(SieveName::new("vacation").unwrap().as_str(), "vacation");
+    SieveName::new("r\u{e9}sum\u{e9} \u{1337}").unwrap();
+    assert_eq!(SieveName::new(""), Err(Error::InvalidInput));
+    assert_eq!(SieveName::new("a\r\nb"), Err(Error::InvalidInput));
+    assert_eq!(SieveName::new("a\u{85}"), Err(Error::InvalidInput));
+    assert_eq!(SieveName::new("a\u{2028}"), Err(Error::InvalidInput));
+    assert_eq!(
+        SieveName::new(&"a".repeat(SieveName::MAX_LEN + 1)),
+        Err(Error::InvalidInput)
+    );
+
+    let decomposed = "re\u{301}sume\u{301}";
+    assert_eq!(SieveName::new(decomposed), Err(Error::InvalidInput));
+    assert_eq!(
+        SieveName::new_normalized(decomposed).unwrap(),
+        "r\u{e9}sum\u{e9}"
+    );
+}
+
+impl fmt::Display for SieveName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for SieveName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for SieveName {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<SieveName> for String {
+    fn from(name: SieveName) -> Self {
+        name.0
+    }
+}
+
+impl TryFrom<&str> for SieveName {
+    type Error = Error;
+
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        SieveName::new(name)
+    }
+}
+
+impl TryFrom<String> for SieveName {
+    type Error = Error;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        if is_valid(&name) {
+            Ok(SieveName(name))
+        } else {
+            Err(Error::InvalidInput)
+        }
+    }
+}
+
+impl FromStr for SieveName {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        SieveName::new(name)
+    }
+}
+
+impl PartialEq<str> for SieveName {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for SieveName {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
@@ -0,0 +1,387 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, TransactionBehavior};
+
+use crate::script::SieveScript;
+use crate::sieve_name::SieveName;
+use crate::store::{ScriptStore, StoreError};
+use crate::types::{QuotaVariant, ScriptEntry};
+
+// The schema, as the statements bringing each version up from the one
+// before. The version reached is recorded in `PRAGMA user_version`, so a
+// change to the schema is made by appending a migration, never by editing
+// one already released.
+const MIGRATIONS: &[&str] = &[
+    // 1: scripts, with at most one active per user, and per-user quotas.
+    "CREATE TABLE scripts (
+         user TEXT NOT NULL,
+         name TEXT NOT NULL,
+         content TEXT NOT NULL,
+         active INTEGER NOT NULL DEFAULT 0 CHECK (active IN (0, 1)),
+         PRIMARY KEY (user, name)
+     );
+     CREATE UNIQUE INDEX scripts_one_active ON scripts (user) WHERE active;
+     CREATE TABLE quotas (
+         user TEXT PRIMARY KEY,
+         max_scripts INTEGER,
+         max_script_size INTEGER
+     );",
+];
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e.sqlite_error_code() {
+            Some(ErrorCode::DatabaseBusy) | Some(ErrorCode::DatabaseLocked) => StoreError::TryLater,
+            _ => StoreError::Other(e.to_string()),
+        }
+    }
+}
+
+/// A SQLite database holding the scripts of many users, each seen through
+/// the [`SqliteStore`] returned by [`SqliteDatabase::store`].
+///
+/// Opening a database creates the schema, or migrates one created by an
+/// earlier version of this crate. A database migrated by a later version
+/// is refused.
+#[derive(Debug, Clone)]
+pub struct SqliteDatabase {
+    conn: Arc<Mutex<Connection>>,
+    max_scripts: Option<usize>,
+    max_script_size: Option<usize>,
+}
+
+impl SqliteDatabase {
+    /// The schema version this crate creates and migrates to.
+    pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+    pub fn open(path: impl AsRef<Path>) -> Result<SqliteDatabase, StoreError> {
+        SqliteDatabase::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteDatabase, StoreError> {
+        SqliteDatabase::from_connection(Connection::open_in_memory()?)
+    }
+
+    /// Uses a connection opened by the caller, such as one with a busy
+    /// timeout set.
+    pub fn from_connection(mut conn: Connection) -> Result<SqliteDatabase, StoreError> {
+        migrate(&mut conn)?;
+        Ok(SqliteDatabase {
+            conn: Arc::new(Mutex::new(conn)),
+            max_scripts: None,
+            max_script_size: None,
+        })
+    }
+
+    /// Refuses to store more than `max` scripts for a user without a quota
+    /// of their own.
+    pub fn with_max_scripts(mut self, max: usize) -> SqliteDatabase {
+        self.max_scripts = Some(max);
+        self
+    }
+
+    /// Refuses to store scripts larger than `size` octets for a user
+    /// without a quota of their own.
+    pub fn with_max_script_size(mut self, size: usize) -> SqliteDatabase {
+        self.max_script_size = Some(size);
+        self
+    }
+
+    /// Sets the quotas of `user`. Where `None`, the database's default
+    /// applies.
+    pub fn set_quota(
+        &self,
+        user: &str,
+        max_scripts: Option<usize>,
+        max_script_size: Option<usize>,
+    ) -> Result<(), StoreError> {
+        self.lock().execute(
+            "INSERT INTO quotas (user, max_scripts, max_script_size) VALUES (?1, ?2, ?3)
+             ON CONFLICT (user) DO UPDATE SET
+                 max_scripts = excluded.max_scripts,
+                 max_script_size = excluded.max_script_size",
+            params![user, max_scripts, max_script_size],
+        )?;
+        Ok(())
+    }
+
+    /// The scripts of `user`.
+    pub fn store(&self, user: &str) -> SqliteStore {
+        SqliteStore {
+            database: self.clone(),
+            user: user.to_owned(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        // A panic cannot leave a transaction open, as dropping one rolls
+        // it back.
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), StoreError> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version: usize = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(StoreError::Other(format!(
+            "Database schema version {} is newer than supported",
+            version
+        )));
+    }
+    for migration in &MIGRATIONS[version..] {
+        tx.execute_batch(migration)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// The [`ScriptStore`] of one user of a [`SqliteDatabase`].
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    database: SqliteDatabase,
+    user: String,
+}
+
+impl SqliteStore {
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+}
+
+// Whether the user has a script of this name, and if so, whether it is
+// active.
+fn script_active(
+    conn: &Connection,
+    user: &str,
+    name: &SieveName,
+) -> Result<Option<bool>, StoreError> {
+    Ok(conn
+        .query_row(
+            "SELECT active FROM scripts WHERE user = ?1 AND name = ?2",
+            params![user, name.as_str()],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+fn check_space(
+    conn: &Connection,
+    database: &SqliteDatabase,
+    user: &str,
+    name: &SieveName,
+    size: usize,
+) -> Result<(), StoreError> {
+    let (max_scripts, max_script_size): (Option<usize>, Option<usize>) = conn
+        .query_row(
+            "SELECT max_scripts, max_script_size FROM quotas WHERE user = ?1",
+            params![user],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?
+        .unwrap_or_default();
+    let max_scripts = max_scripts.or(database.max_scripts);
+    let max_script_size = max_script_size.or(database.max_script_size);
+
+    if max_script_size.is_some_and(|max| size > max) {
+        return Err(StoreError::Quota(QuotaVariant::MaxSize));
+    }
+    if let Some(max) = max_scripts {
+        let count: usize = conn.query_row(
+            "SELECT count(*) FROM scripts WHERE user = ?1",
+            params![user],
+            |row| row.get(0),
+        )?;
+        if script_active(conn, user, name)?.is_none() && count >= max {
+            return Err(StoreError::Quota(QuotaVariant::MaxScripts));
+        }
+    }
+    Ok(())
+}
+
+impl ScriptStore for SqliteStore {
+    fn list(&mut self) -> Result<Vec<ScriptEntry>, StoreError> {
+        let conn = self.database.lock();
+        let mut statement =
+            conn.prepare("SELECT name, active FROM scripts WHERE user = ?1 ORDER BY name")?;
+        let rows = statement.query_map(params![self.user], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?))
+        })?;
+        rows.map(|row| {
+            let (name, active) = row?;
+            let name = SieveName::new(&name)
+                .map_err(|_| StoreError::Other("Invalid script name in database".to_string()))?;
+            Ok((name, active))
+        })
+        .collect()
+    }
+
+    fn get(&mut self, name: &SieveName) -> Result<SieveScript, StoreError> {
+        self.database
+            .lock()
+            .query_row(
+                "SELECT content FROM scripts WHERE user = ?1 AND name = ?2",
+                params![self.user, name.as_str()],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+            .map(SieveScript::from)
+            .ok_or(StoreError::Nonexistent)
+    }
+
+    fn put(&mut self, name: &SieveName, script: &SieveScript) -> Result<(), StoreError> {
+        let mut conn = self.database.lock();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        check_space(&tx, &self.database, &self.user, name, script.len())?;
+        tx.execute(
+            "INSERT INTO scripts (user, name, content) VALUES (?1, ?2, ?3)
+             ON CONFLICT (user, name) DO UPDATE SET content = excluded.content",
+            params![self.user, name.as_str(), script.as_str()],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete(&mut self, name: &SieveName) -> Result<(), StoreError> {
+        let mut conn = self.database.lock();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        match script_active(&tx, &self.user, name)? {
+            None => return Err(StoreError::Nonexistent),
+            Some(true) => return Err(StoreError::Active),
+            Some(false) => {}
+        }
+        tx.execute(
+            "DELETE FROM scripts WHERE user = ?1 AND name = ?2",
+            params![self.user, name.as_str()],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn rename(&mut self, old_name: &SieveName, new_name: &SieveName) -> Result<(), StoreError> {
+        let mut conn = self.database.lock();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if script_active(&tx, &self.user, old_name)?.is_none() {
+            return Err(StoreError::Nonexistent);
+        }
+        if script_active(&tx, &self.user, new_name)?.is_some() {
+            return Err(StoreError::AlreadyExists);
+        }
+        // The active flag is renamed with the script.
+        tx.execute(
+            "UPDATE scripts SET name = ?3 WHERE user = ?1 AND name = ?2",
+            params![self.user, old_name.as_str(), new_name.as_str()],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn set_active(&mut self, name: Option<&SieveName>) -> Result<(), StoreError> {
+        let mut conn = self.database.lock();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute(
+            "UPDATE scripts SET active = 0 WHERE user = ?1 AND active",
+            params![self.user],
+        )?;
+        if let Some(name) = name {
+            let updated = tx.execute(
+                "UPDATE scripts SET active = 1 WHERE user = ?1 AND name = ?2",
+                params![self.user, name.as_str()],
+            )?;
+            if updated == 0 {
+                return Err(StoreError::Nonexistent);
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn have_space(&mut self, name: &SieveName, size: usize) -> Result<(), StoreError> {
+        let conn = self.database.lock();
+        check_space(&conn, &self.database, &self.user, name, size)
+    }
+}
+
+#[test]
+fn test_sqlite_store() {
+    let database = SqliteDatabase::open_in_memory()
+        .unwrap()
+        .with_max_scripts(2);
+    database.set_quota("bob", None, Some(10)).unwrap();
+    let mut alice = database.store("alice");
+    let mut bob = database.store("bob");
+    let name = |s| SieveName::new(s).unwrap();
+    let script = SieveScript::new("keep;");
+
+    alice.put(&name("a"), &script).unwrap();
+    alice.put(&name("b"), &script).unwrap();
+    alice.put(&name("a"), &script).unwrap();
+    assert_eq!(
+        alice.put(&name("c"), &script),
+        Err(StoreError::Quota(QuotaVariant::MaxScripts))
+    );
+    bob.put(&name("a"), &SieveScript::new("discard;")).unwrap();
+    assert_eq!(
+        bob.put(&name("b"), &SieveScript::new("discard; stop;")),
+        Err(StoreError::Quota(QuotaVariant::MaxSize))
+    );
+    assert_eq!(alice.get(&name("a")).unwrap(), script);
+    assert_eq!(bob.get(&name("a")).unwrap(), "discard;".into());
+
+    alice.set_active(Some(&name("a"))).unwrap();
+    bob.set_active(Some(&name("a"))).unwrap();
+    assert_eq!(alice.delete(&name("a")), Err(StoreError::Active));
+    assert_eq!(
+        alice.rename(&name("a"), &name("b")),
+        Err(StoreError::AlreadyExists)
+    );
+    alice.rename(&name("a"), &name("c")).unwrap();
+    assert_eq!(
+        alice.set_active(Some(&name("a"))),
+        Err(StoreError::Nonexistent)
+    );
+    assert_eq!(
+        alice.list().unwrap(),
+        vec![(name("b"), false), (name("c"), true)]
+    );
+    alice.set_active(Some(&name("b"))).unwrap();
+    assert_eq!(
+        alice.list().unwrap(),
+        vec![(name("b"), true), (name("c"), false)]
+    );
+    assert_eq!(bob.list().unwrap(), vec![(name("a"), true)]);
+
+    // The schema itself refuses a second active script.
+    assert!(database
+        .lock()
+        .execute("UPDATE scripts SET active = 1 WHERE user = 'alice'", [])
+        .is_err());
+
+    alice.set_active(None).unwrap();
+    alice.delete(&name("b")).unwrap();
+    assert_eq!(alice.list().unwrap(), vec![(name("c"), false)]);
+}
+
+#[test]
+fn test_sqlite_migration() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sieve.db");
+    let name = SieveName::new("a").unwrap();
+
+    let mut store = SqliteDatabase::open(&path).unwrap().store("alice");
+    store.put(&name, &SieveScript::new("keep;")).unwrap();
+    drop(store);
+
+    // Reopening leaves a current schema and its data alone.
+    let mut store = SqliteDatabase::open(&path).unwrap().store("alice");
+    assert_eq!(store.list().unwrap(), vec![(name, false)]);
+    drop(store);
+
+    let conn = Connection::open(&path).unwrap();
+    conn.pragma_update(None, "user_version", SqliteDatabase::SCHEMA_VERSION + 1)
+        .unwrap();
+    drop(conn);
+    assert!(SqliteDatabase::open(&path).is_err());
+}
@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::script::SieveScript;
+use crate::sieve_name::SieveName;
+use crate::types::{OkNoBye, QuotaVariant, Response, ResponseCode, ScriptEntry};
+
+/// Why a [`ScriptStore`] operation failed. Each is sent to the client as a
+/// NO response, with the corresponding response code if there is one.
+#[derive(Debug, PartialEq, Clone)]
+pub enum StoreError {
+    /// There is no script of that name: `NONEXISTENT`.
+    Nonexistent,
+    /// The active script cannot be deleted: `ACTIVE`.
+    Active,
+    /// A script of the new name already exists: `ALREADYEXISTS`.
+    AlreadyExists,
+    /// A quota would be exceeded: `QUOTA`, `QUOTA/MAXSCRIPTS` or
+    /// `QUOTA/MAXSIZE`.
+    Quota(QuotaVariant),
+    /// The store is temporarily unavailable: `TRYLATER`.
+    TryLater,
+    /// The script is invalid, for the reason given.
+    InvalidScript(String),
+    /// Any other failure, described for the client.
+    Other(String),
+}
+
+impl StoreError {
+    /// The NO response reporting the failure to the client.
+    pub fn to_response(&self) -> Response {
+        let (code, human) = match self {
+            StoreError::Nonexistent => (Some(ResponseCode::Nonexistent), None),
+            StoreError::Active => (Some(ResponseCode::Active), None),
+            StoreError::AlreadyExists => (Some(ResponseCode::AlreadyExists), None),
+            StoreError::Quota(variant) => (Some(ResponseCode::Quota(*variant)), None),
+            StoreError::TryLater => (Some(ResponseCode::TryLater), None),
+            StoreError::InvalidScript(s) | StoreError::Other(s) => (None, Some(s.clone())),
+        };
+        Response {
+            tag: OkNoBye::No,
+            code: code.map(|code| (code, None)),
+            human,
+        }
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Nonexistent => write!(f, "no such script"),
+            StoreError::Active => write!(f, "script is active"),
+            StoreError::AlreadyExists => write!(f, "script already exists"),
+            StoreError::Quota(QuotaVariant::MaxScripts) => write!(f, "too many scripts"),
+            StoreError::Quota(QuotaVariant::MaxSize) => write!(f, "script too large"),
+            StoreError::Quota(QuotaVariant::None) => write!(f, "quota exceeded"),
+            StoreError::TryLater => write!(f, "temporarily unavailable"),
+            StoreError::InvalidScript(s) => write!(f, "invalid script: {}", s),
+            StoreError::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl error::Error for StoreError {}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        StoreError::Other(e.to_string())
+    }
+}
+
+/// Storage for the scripts of one user, as managed by a
+/// [`Server`](crate::Server).
+pub trait ScriptStore {
+    /// Lists the scripts, and whether each is the active script.
+    fn list(&mut self) -> Result<Vec<ScriptEntry>, StoreError>;
+
+    fn get(&mut self, name: &SieveName) -> Result<SieveScript, StoreError>;
+
+    /// Stores a script, replacing any of the same name. A replaced active
+    /// script remains active.
+    fn put(&mut self, name: &SieveName, script: &SieveScript) -> Result<(), StoreError>;
+
+    /// Deletes a script, which must not be the active script.
+    fn delete(&mut self, name: &SieveName) -> Result<(), StoreError>;
+
+    /// Renames a script, which remains active if it was.
+    fn rename(&mut self, old_name: &SieveName, new_name: &SieveName) -> Result<(), StoreError>;
+
+    /// Makes the named script the active script, or deactivates the active
+    /// script if `None`.
+    fn set_active(&mut self, name: Option<&SieveName>) -> Result<(), StoreError>;
+
+    /// Checks whether a script of `size` octets could be stored under
+    /// `name`. Accepts any by default.
+    fn have_space(&mut self, _name: &SieveName, _size: usize) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Checks a script without storing it. Accepts any by default.
+    fn check(&mut self, _script: &SieveScript) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// A [`ScriptStore`] holding scripts in memory, with optional quotas.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    scripts: BTreeMap<SieveName, SieveScript>,
+    active: Option<SieveName>,
+    max_scripts: Option<usize>,
+    max_script_size: Option<usize>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    /// Refuses to store more than `max` scripts.
+    pub fn with_max_scripts(mut self, max: usize) -> MemoryStore {
+        self.max_scripts = Some(max);
+        self
+    }
+
+    /// Refuses to store scripts larger than `size` octets.
+    pub fn with_max_script_size(mut self, size: usize) -> MemoryStore {
+        self.max_script_size = Some(size);
+        self
+    }
+
+    /// The name of the active script, if any.
+    pub fn active(&self) -> Option<&SieveName> {
+        self.active.as_ref()
+    }
+
+    /// The script stored under `name`, if any.
+    pub fn script(&self, name: &str) -> Option<&SieveScript> {
+        self.scripts.get(name)
+    }
+
+    fn exists(&self, name: &SieveName) -> Result<(), StoreError> {
+        match self.scripts.contains_key(name) {
+            true => Ok(()),
+            false => Err(StoreError::Nonexistent),
+        }
+    }
+}
+
+impl ScriptStore for MemoryStore {
+    fn list(&mut self) -> Result<Vec<ScriptEntry>, StoreError> {
+        Ok(self
+            .scripts
+            .keys()
+            .map(|name| (name.clone(), self.active.as_ref() == Some(name)))
+            .collect())
+    }
+
+    fn get(&mut self, name: &SieveName) -> Result<SieveScript, StoreError> {
+        self.scripts
+            .get(name)
+            .cloned()
+            .ok_or(StoreError::Nonexistent)
+    }
+
+    fn put(&mut self, name: &SieveName, script: &SieveScript) -> Result<(), StoreError> {
+        self.have_space(name, script.len())?;
+        self.scripts.insert(name.clone(), script.clone());
+        Ok(())
+    }
+
+    fn delete(&mut self, name: &SieveName) -> Result<(), StoreError> {
+        self.exists(name)?;
+        if self.active.as_ref() == Some(name) {
+            return Err(StoreError::Active);
+        }
+        self.scripts.remove(name);
+        Ok(())
+    }
+
+    fn rename(&mut self, old_name: &SieveName, new_name: &SieveName) -> Result<(), StoreError> {
+        self.exists(old_name)?;
+        if self.scripts.contains_key(new_name) {
+            return Err(StoreError::AlreadyExists);
+        }
+        if let Some(script) = self.scripts.remove(old_name) {
+            self.scripts.insert(new_name.clone(), script);
+        }
+        if self.active.as_ref() == Some(old_name) {
+            self.active = Some(new_name.clone());
+        }
+        Ok(())
+    }
+
+    fn set_active(&mut self, name: Option<&SieveName>) -> Result<(), StoreError> {
+        if let Some(name) = name {
+            self.exists(name)?;
+        }
+        self.active = name.cloned();
+        Ok(())
+    }
+
+    fn have_space(&mut self, name: &SieveName, size: usize) -> Result<(), StoreError> {
+        if self.max_script_size.is_some_and(|max| size > max) {
+            return Err(StoreError::Quota(QuotaVariant::MaxSize));
+        }
+        let replacing = self.scripts.contains_key(name);
+        if !replacing
+            && self
+                .max_scripts
+                .is_some_and(|max| self.scripts.len() >= max)
+        {
+            return Err(StoreError::Quota(QuotaVariant::MaxScripts));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_memory_store() {
+    let name = |s| SieveName::new(s).unwrap();
+    let script = SieveScript::new("keep;");
+    let mut store = MemoryStore::new()
+        .with_max_scripts(2)
+        .with_max_script_size(10);
+
+    store.put(&name("a"), &script).unwrap();
+    store.put(&name("b"), &script).unwrap();
+    store.put(&name("a"), &script).unwrap();
+    assert_eq!(
+        store.put(&name("c"), &script),
+        Err(StoreError::Quota(QuotaVariant::MaxScripts))
+    );
+    assert_eq!(
+        store.put(&name("a"), &SieveScript::new("discard; stop;")),
+        Err(StoreError::Quota(QuotaVariant::MaxSize))
+    );
+
+    store.set_active(Some(&name("a"))).unwrap();
+    assert_eq!(store.delete(&name("a")), Err(StoreError::Active));
+    assert_eq!(
+        store.rename(&name("a"), &name("b")),
+        Err(StoreError::AlreadyExists)
+    );
+    store.rename(&name("a"), &name("c")).unwrap();
+    assert_eq!(
+        store.list().unwrap(),
+        vec![(name("b"), false), (name("c"), true)]
+    );
+    assert_eq!(store.get(&name("a")), Err(StoreError::Nonexistent));
+    assert_eq!(
+        store.set_active(Some(&name("a"))),
+        Err(StoreError::Nonexistent)
+    );
+    store.set_active(None).unwrap();
+    store.delete(&name("c")).unwrap();
+
+    assert_eq!(
+        StoreError::Quota(QuotaVariant::MaxSize)
+            .to_response()
+            .to_string(),
+        "NO (QUOTA/MAXSIZE)"
+    );
+}
@@ -0,0 +1,469 @@
+use std::io::{self, BufRead, Read, Write};
+use std::str;
+
+use crate::error::{from_utf8, Error, ParseFailure};
+use crate::limits::{Limit, ParserLimits};
+use crate::parser as p;
+use crate::sieve_name::SieveName;
+#[cfg(test)]
+use crate::types::Command;
+use crate::types::{to_qs, OkNoBye, Response};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum State {
+    // Reading the line holding the literal's length, or a quoted script or
+    // NO response.
+    Header,
+    // Copying the literal to the writer, with this many octets to go.
+    Literal(usize),
+    // Reading the CRLF after the literal and the final response.
+    Trailer,
+    Done,
+}
+
+/// Receives the response to GETSCRIPT, copying the script to a writer as it
+/// arrives instead of holding all of it in memory.
+///
+/// Bytes received from the server are passed to [`GetScriptStream::feed`].
+/// Only the line announcing the literal and the final response are buffered;
+/// the octets of the literal are written straight to the writer.
+#[derive(Debug)]
+pub struct GetScriptStream<W> {
+    writer: W,
+    state: State,
+    buf: Vec<u8>,
+    limits: ParserLimits,
+}
+
+impl<W: Write> GetScriptStream<W> {
+    pub fn new(writer: W) -> GetScriptStream<W> {
+        GetScriptStream::with_limits(writer, ParserLimits::default())
+    }
+
+    /// Creates a stream that rejects responses exceeding `limits`.
+    pub fn with_limits(writer: W, limits: ParserLimits) -> GetScriptStream<W> {
+        GetScriptStream {
+            writer,
+            state: State::Header,
+            buf: Vec::new(),
+            limits,
+        }
+    }
+
+    /// Returns the writer the script was copied to.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Processes bytes received from the server. Once the final response has
+    /// been received, returns it along with any bytes that follow it. A NO
+    /// response is returned in the same way, in which case nothing has been
+    /// written.
+    pub fn feed<'a>(&mut self, mut data: &'a [u8]) -> Result<Option<(&'a [u8], Response)>, Error> {
+        while !data.is_empty() {
+            match self.state {
+                State::Header | State::Trailer => {
+                    let (line, rest) = match data.iter().position(|&b| b == b'\n') {
+                        Some(i) => data.split_at(i + 1),
+                        None => (data, &data[data.len()..]),
+                    };
+                    self.buf.extend_from_slice(line);
+                    data = rest;
+                    if self.buf.ends_with(b"\n") {
+                        if let Some(response) = self.parse_line()? {
+                            return Ok(Some((data, response)));
+                        }
+                    } else if self.partial_line_len() > self.limits.max_line_length {
+                        return Err(Error::LimitExceeded(Limit::LineLength));
+                    }
+                }
+                State::Literal(remaining) => {
+                    let n = remaining.min(data.len());
+                    self.writer.write_all(&data[..n])?;
+                    data = &data[n..];
+                    self.state = match remaining - n {
+                        0 => State::Trailer,
+                        remaining => State::Literal(remaining),
+                    };
+                }
+                State::Done => return Err(Error::InvalidState),
+            }
+        }
+        Ok(None)
+    }
+
+    // Length of the incomplete line at the end of the buffer.
+    fn partial_line_len(&self) -> usize {
+        match self.buf.iter().rposition(|&b| b == b'\n') {
+            Some(i) => self.buf.len() - i - 1,
+            None => self.buf.len(),
+        }
+    }
+
+    // Examines the buffered input once it ends with a complete line,
+    // returning the final response if it has been received.
+    fn parse_line(&mut self) -> Result<Option<Response>, Error> {
+        let input = from_utf8(&self.buf)?;
+
+        if self.state == State::Header {
+            match p::literal_s2c_len(input, &self.limits) {
+                Ok((_, len)) => {
+                    self.state = State::Literal(len);
+                    self.buf.clear();
+                    return Ok(None);
+                }
+                Err(e @ nom::Err::Failure(p::ParseError { limit: Some(_), .. })) => {
+                    return Err(Error::parse(input, e))
+                }
+                Err(_) => {}
+            }
+            if let Ok((_, r)) = p::response_bye(input, &self.limits) {
+                return Err(Error::UnsolicitedBye(r.into_owned()));
+            }
+        }
+
+        // A quoted script, the final response, or a NO response in place of
+        // the script. These are small enough to parse once complete.
+        let parsed = match self.state {
+            State::Header => p::response_getscript(input, &self.limits),
+            _ => match p::response_getscript_trailer(input, &self.limits) {
+                Ok((left, r)) if r.tag != OkNoBye::No => Ok((left, (None, r))),
+                // A NO after the CRLF ending the literal.
+                Ok(_) => {
+                    return Err(Error::InvalidResponse(ParseFailure::new(
+                        input,
+                        2,
+                        "response-getscript",
+                    )))
+                }
+                Err(e) => Err(e),
+            },
+        };
+        let (script, response) = match parsed {
+            Ok(("", r)) => r,
+            Ok((left, _)) => {
+                let offset = input.len() - left.len();
+                return Err(Error::InvalidResponse(ParseFailure::new(
+                    input, offset, "response",
+                )));
+            }
+            Err(nom::Err::Incomplete(_)) => return Ok(None),
+            Err(e) => return Err(Error::parse(input, e)),
+        };
+        let response = response.into_owned();
+        if response.tag == OkNoBye::Bye {
+            return Err(Error::UnsolicitedBye(response));
+        }
+        if let Some(script) = script {
+            self.writer.write_all(script.as_bytes())?;
+        }
+        self.state = State::Done;
+        self.buf.clear();
+        Ok(Some(response))
+    }
+}
+
+/// Reads the response to GETSCRIPT from `reader`, copying the script to
+/// `writer` as it arrives. Only the bytes making up the response are
+/// consumed from `reader`.
+pub fn read_getscript<R: BufRead, W: Write>(reader: &mut R, writer: W) -> io::Result<Response> {
+    let mut stream = GetScriptStream::new(writer);
+    loop {
+        let data = reader.fill_buf()?;
+        if data.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let len = data.len();
+        match stream.feed(data)? {
+            Some((left, response)) => {
+                let consumed = len - left.len();
+                reader.consume(consumed);
+                stream.writer.flush()?;
+                return Ok(response);
+            }
+            None => reader.consume(len),
+        }
+    }
+}
+
+#[test]
+fn test_getscript_stream() {
+    let fixture = include_bytes!("test_input/response_getscript-1.txt");
+    let (_, expected, _) = crate::response_getscript(str::from_utf8(fixture).unwrap()).unwrap();
+
+    for i in 0..fixture.len() {
+        let mut stream = GetScriptStream::new(Vec::new());
+        assert_eq!(stream.feed(&fixture[..i]), Ok(None));
+        let (left, response) = stream.feed(&fixture[i..]).unwrap().unwrap();
+        assert_eq!(left, b"");
+        assert_eq!(response.tag, OkNoBye::Ok);
+        assert_eq!(stream.into_inner(), expected.as_bytes());
+    }
+}
+
+#[test]
+fn test_getscript_stream_short() {
+    let mut stream = GetScriptStream::new(Vec::new());
+    let (left, response) = stream
+        .feed(b"\"keep;\"\r\nOK (TAG {1}\r\na)\r\nNOOP")
+        .unwrap()
+        .unwrap();
+    assert_eq!(left, b"NOOP");
+    assert_eq!(response.code.unwrap().1.as_deref(), Some("a"));
+    assert_eq!(stream.into_inner(), b"keep;");
+
+    let mut stream = GetScriptStream::new(Vec::new());
+    let (_, response) = stream.feed(b"NO (NONEXISTENT)\r\n").unwrap().unwrap();
+    assert_eq!(response.tag, OkNoBye::No);
+    assert!(stream.into_inner().is_empty());
+
+    let mut stream = GetScriptStream::new(Vec::new());
+    assert!(matches!(
+        stream.feed(b"BYE\r\n"),
+        Err(Error::UnsolicitedBye(_))
+    ));
+
+    let mut stream = GetScriptStream::new(Vec::new());
+    assert!(matches!(
+        stream.feed(b"{2}\r\nabNO\r\n"),
+        Err(Error::InvalidResponse(_))
+    ));
+
+    let mut stream = GetScriptStream::new(Vec::new());
+    assert!(matches!(
+        stream.feed(b"{2}\r\nab\r\nNO\r\n"),
+        Err(Error::InvalidResponse(_))
+    ));
+}
+
+#[test]
+fn test_getscript_stream_limits() {
+    let limits = ParserLimits {
+        max_literal_size: 4,
+        max_line_length: 8,
+        ..ParserLimits::default()
+    };
+
+    let mut stream = GetScriptStream::with_limits(Vec::new(), limits);
+    assert_eq!(
+        stream.feed(b"{5}\r\n"),
+        Err(Error::LimitExceeded(Limit::LiteralSize))
+    );
+
+    let mut stream = GetScriptStream::with_limits(Vec::new(), limits);
+    assert_eq!(stream.feed(b"{4}\r\nkeep\r\nOK \"Do"), Ok(None));
+    assert_eq!(
+        stream.feed(b"ne already\""),
+        Err(Error::LimitExceeded(Limit::LineLength))
+    );
+}
+
+#[test]
+fn test_read_getscript() {
+    let mut input = include_bytes!("test_input/response_getscript-1.txt").to_vec();
+    input.extend_from_slice(b"OK\r\n");
+    let mut reader = io::BufReader::with_capacity(7, &input[..]);
+    let mut script = Vec::new();
+    let response = read_getscript(&mut reader, &mut script).unwrap();
+    assert_eq!(response.tag, OkNoBye::Ok);
+    assert_eq!(script.len(), 100);
+    assert_eq!(reader.fill_buf().unwrap(), b"OK\r\n");
+}
+
+fn putscript_header(name: &str, len: u64) -> Result<String, Error> {
+    Ok(format!(
+        "PUTSCRIPT {} {{{}+}}\r\n",
+        to_qs(SieveName::new(name)?.as_str()),
+        len
+    ))
+}
+
+fn length_mismatch() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "script length differs from the length announced",
+    )
+}
+
+/// Writes a PUTSCRIPT command whose script is supplied in pieces, without
+/// holding all of it in memory.
+///
+/// [`PutScriptWriter::new`] writes the command up to the literal announcing
+/// the script's length. Exactly that many octets must then be written before
+/// calling [`PutScriptWriter::finish`]. Writing more fails without sending
+/// the excess. If fewer are written, the server is still waiting for the
+/// rest of the literal, and the connection must be closed.
+#[derive(Debug)]
+pub struct PutScriptWriter<W: Write> {
+    inner: W,
+    remaining: u64,
+}
+
+impl<W: Write> PutScriptWriter<W> {
+    pub fn new(mut inner: W, name: &str, len: u64) -> Result<PutScriptWriter<W>, Error> {
+        inner.write_all(putscript_header(name, len)?.as_bytes())?;
+        Ok(PutScriptWriter {
+            inner,
+            remaining: len,
+        })
+    }
+
+    /// Octets still to be written.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Completes the command, checking that the whole script was written.
+    pub fn finish(mut self) -> Result<W, Error> {
+        if self.remaining != 0 {
+            return Err(Error::InvalidInput);
+        }
+        self.inner.write_all(b"\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for PutScriptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(length_mismatch());
+        }
+        let n = self.inner.write(buf)?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes a PUTSCRIPT command, copying the script from `script`, which must
+/// supply exactly `len` octets. As with [`PutScriptWriter`], the connection
+/// cannot be used further if it does not.
+pub fn write_putscript<W: Write, R: Read>(
+    writer: W,
+    name: &str,
+    script: R,
+    len: u64,
+) -> Result<W, Error> {
+    let mut writer = PutScriptWriter::new(writer, name, len)?;
+    let mut script = script.take(len + 1);
+    io::copy(&mut script, &mut writer)?;
+    writer.finish()
+}
+
+/// Writes a PUTSCRIPT command, copying the script from an asynchronous
+/// reader, which must supply exactly `len` octets.
+#[cfg(feature = "async")]
+pub async fn write_putscript_async<W, R>(
+    writer: &mut W,
+    name: &str,
+    script: R,
+    len: u64,
+) -> Result<(), Error>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    writer
+        .write_all(putscript_header(name, len)?.as_bytes())
+        .await?;
+    let mut script = script.take(len + 1);
+    let copied = tokio::io::copy(&mut script, &mut LimitedAsyncWriter(writer, len)).await?;
+    if copied != len {
+        return Err(Error::InvalidInput);
+    }
+    writer.write_all(b"\r\n").await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+// Refuses writes beyond the announced length, so that an overlong script
+// does not corrupt the command stream.
+#[cfg(feature = "async")]
+struct LimitedAsyncWriter<'a, W>(&'a mut W, u64);
+
+#[cfg(feature = "async")]
+impl<W: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for LimitedAsyncWriter<'_, W> {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<io::Result<usize>> {
+        if buf.len() as u64 > self.1 {
+            return std::task::Poll::Ready(Err(length_mismatch()));
+        }
+        let poll = std::pin::Pin::new(&mut *self.0).poll_write(cx, buf);
+        if let std::task::Poll::Ready(Ok(n)) = poll {
+            self.1 -= n as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::pin::Pin::new(&mut *self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::pin::Pin::new(&mut *self.0).poll_shutdown(cx)
+    }
+}
+
+#[test]
+fn test_write_putscript() {
+    let script = "require \"fileinto\";\r\nfileinto \"Junk\";\r\n";
+    let expected = Command::put_script("junk", script).unwrap().to_string();
+
+    let written = write_putscript(Vec::new(), "junk", script.as_bytes(), script.len() as u64);
+    assert_eq!(written.unwrap(), expected.as_bytes());
+
+    let mut writer = PutScriptWriter::new(Vec::new(), "junk", script.len() as u64).unwrap();
+    for chunk in script.as_bytes().chunks(5) {
+        writer.write_all(chunk).unwrap();
+    }
+    assert_eq!(writer.finish().unwrap(), expected.as_bytes());
+}
+
+#[test]
+fn test_write_putscript_length() {
+    assert!(write_putscript(Vec::new(), "a", &b"keep;"[..], 6).is_err());
+    assert!(write_putscript(Vec::new(), "a", &b"keep;"[..], 4).is_err());
+    assert_eq!(
+        write_putscript(Vec::new(), "a\x01", &b""[..], 0).unwrap_err(),
+        Error::InvalidInput
+    );
+
+    let mut writer = PutScriptWriter::new(Vec::new(), "a", 3).unwrap();
+    assert!(writer.write_all(b"abcd").is_err());
+    writer.write_all(b"ab").unwrap();
+    assert_eq!(writer.remaining(), 1);
+    assert_eq!(writer.finish().unwrap_err(), Error::InvalidInput);
+}
+
+#[cfg(all(test, feature = "async"))]
+#[tokio::test]
+async fn test_write_putscript_async() {
+    let mut out = Vec::new();
+    write_putscript_async(&mut out, "a", &b"keep;"[..], 5)
+        .await
+        .unwrap();
+    assert_eq!(out, b"PUTSCRIPT \"a\" {5+}\r\nkeep;\r\n");
+
+    let mut out = Vec::new();
+    assert!(write_putscript_async(&mut out, "a", &b"keep;"[..], 4)
+        .await
+        .is_err());
+    assert!(write_putscript_async(&mut out, "a", &b"keep;"[..], 6)
+        .await
+        .is_err());
+}
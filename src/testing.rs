@@ -0,0 +1,438 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+use crate::error::Error;
+use crate::limits::ParserLimits;
+use crate::script::SieveScript;
+use crate::server::{
+    capabilities_reply, dispatch, next_command, noop_reply, reply, CommandReader, Received,
+};
+use crate::session::ResponseKind;
+use crate::sieve_name::SieveName;
+use crate::store::{MemoryStore, ScriptStore};
+#[cfg(test)]
+use crate::types::ResponseCode;
+use crate::types::{Capability, Command, OkNoBye, Response};
+
+/// A command received by a [`MockServer`] and the server's reply.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Exchange {
+    /// The command as sent by the client.
+    pub request: String,
+    /// The parsed command, or `None` if it could not be parsed.
+    pub command: Option<Command>,
+    /// The reply as sent by the server.
+    pub reply: String,
+}
+
+#[derive(Debug)]
+struct MockState {
+    capabilities: Vec<Capability>,
+    store: MemoryStore,
+    failures: VecDeque<(ResponseKind, Response)>,
+    transcript: Vec<Exchange>,
+}
+
+/// A managesieve server for tests, which keeps scripts in memory.
+///
+/// Connections can be served in memory with [`MockServer::connect`], over
+/// any stream with [`MockServer::serve`], or on a localhost socket with
+/// [`MockServer::listen`]. Clones share the same scripts, failures and
+/// transcript, so a test can inspect them while a client uses the server.
+///
+/// Any AUTHENTICATE command is accepted, and STARTTLS is refused.
+#[derive(Debug, Clone)]
+pub struct MockServer {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl Default for MockServer {
+    fn default() -> Self {
+        MockServer::new()
+    }
+}
+
+impl MockServer {
+    /// Creates a server with no scripts, announcing SASL PLAIN and the
+    /// `fileinto` and `vacation` extensions.
+    pub fn new() -> MockServer {
+        MockServer {
+            state: Arc::new(Mutex::new(MockState {
+                capabilities: vec![
+                    Capability::Implementation("managesieve mock".to_string()),
+                    Capability::Sasl(vec!["PLAIN".to_string()]),
+                    Capability::Sieve(vec!["fileinto".to_string(), "vacation".to_string()]),
+                    Capability::Version("1.0".to_string()),
+                ],
+                store: MemoryStore::new(),
+                failures: VecDeque::new(),
+                transcript: Vec::new(),
+            })),
+        }
+    }
+
+    /// Announces `capabilities` in the greeting and in response to
+    /// CAPABILITY.
+    pub fn with_capabilities(self, capabilities: Vec<Capability>) -> MockServer {
+        self.state().capabilities = capabilities;
+        self
+    }
+
+    /// Serves scripts from `store`, which may set quotas.
+    pub fn with_store(self, store: MemoryStore) -> MockServer {
+        self.state().store = store;
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Stores a script, as if it had been sent with PUTSCRIPT. Fails with
+    /// `Error::InvalidInput` if the name is invalid or a quota is exceeded.
+    pub fn put_script(&self, name: &str, script: impl Into<SieveScript>) -> Result<(), Error> {
+        self.state()
+            .store
+            .put(&SieveName::new(name)?, &script.into())
+            .map_err(|_| Error::InvalidInput)
+    }
+
+    /// The script stored under `name`, if any.
+    pub fn script(&self, name: &str) -> Option<SieveScript> {
+        self.state().store.script(name).cloned()
+    }
+
+    /// The name of the active script, if any.
+    pub fn active_script(&self) -> Option<SieveName> {
+        self.state().store.active().cloned()
+    }
+
+    /// Answers the next command of the given kind with `response` instead of
+    /// carrying it out. A BYE response closes the connection.
+    pub fn fail_next(&self, kind: ResponseKind, response: Response) {
+        self.state().failures.push_back((kind, response));
+    }
+
+    /// The commands received so far on all connections, and the replies to
+    /// them.
+    pub fn transcript(&self) -> Vec<Exchange> {
+        self.state().transcript.clone()
+    }
+
+    /// Opens a connection held in memory.
+    pub fn connect(&self) -> MockConnection {
+        let greeting = capabilities_reply(&self.state().capabilities);
+        MockConnection {
+            server: self.clone(),
+            input: CommandReader::default(),
+            output: greeting.into_bytes().into(),
+            authenticated: false,
+            closed: false,
+        }
+    }
+
+    /// Serves a connection over `stream` until the client logs out, the
+    /// server sends BYE, or the stream is closed.
+    pub fn serve<S: Read + Write>(&self, mut stream: S) -> io::Result<()> {
+        let mut connection = self.connect();
+        let mut buf = [0; 4096];
+        loop {
+            stream.write_all(&connection.take_output())?;
+            stream.flush()?;
+            if connection.closed {
+                return Ok(());
+            }
+            match stream.read(&mut buf)? {
+                0 => return Ok(()),
+                n => connection.receive(&buf[..n]),
+            }
+        }
+    }
+
+    /// Like [`MockServer::serve`], for an asynchronous stream such as one
+    /// end of `tokio::io::duplex`.
+    #[cfg(feature = "async")]
+    pub async fn serve_async<S>(&self, mut stream: S) -> io::Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut connection = self.connect();
+        let mut buf = [0; 4096];
+        loop {
+            stream.write_all(&connection.take_output()).await?;
+            stream.flush().await?;
+            if connection.closed {
+                return Ok(());
+            }
+            match stream.read(&mut buf).await? {
+                0 => return Ok(()),
+                n => connection.receive(&buf[..n]),
+            }
+        }
+    }
+
+    /// Listens on a localhost port, serving each connection on its own
+    /// thread. The server runs until the process exits.
+    pub fn listen(&self) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let server = server.clone();
+                thread::spawn(move || server.serve(stream));
+            }
+        });
+        Ok(addr)
+    }
+}
+
+/// A connection to a [`MockServer`] held in memory. Commands written to it
+/// are answered once complete, and the replies, starting with the greeting,
+/// can then be read from it.
+///
+/// Reading when no reply is waiting fails with `io::ErrorKind::WouldBlock`,
+/// or returns 0 once the connection has been closed.
+#[derive(Debug)]
+pub struct MockConnection {
+    server: MockServer,
+    input: CommandReader,
+    output: VecDeque<u8>,
+    authenticated: bool,
+    closed: bool,
+}
+
+impl MockConnection {
+    /// Whether the server has closed the connection, after LOGOUT or BYE.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        self.output.drain(..).collect()
+    }
+
+    fn receive(&mut self, bytes: &[u8]) {
+        self.input.feed(bytes);
+        while !self.closed {
+            let (len, command, reply) =
+                match next_command(&mut self.input, &ParserLimits::default()) {
+                    Received::Incomplete => return,
+                    Received::Complete(len, command) => {
+                        let reply = self.execute(&command);
+                        (len, Some(command), reply)
+                    }
+                    Received::Invalid(len, reply) => (len, None, reply),
+                    Received::Fatal(reply) => {
+                        self.closed = true;
+                        (self.input.buffered().len(), None, reply)
+                    }
+                };
+            let request = String::from_utf8_lossy(&self.input.buffered()[..len]).into_owned();
+            self.input.consume(len);
+            self.output.extend(reply.as_bytes());
+            self.server.state().transcript.push(Exchange {
+                request,
+                command,
+                reply,
+            });
+        }
+    }
+
+    fn execute(&mut self, command: &Command) -> String {
+        let mut state = self.server.state();
+        let kind = ResponseKind::from(command);
+        if let Some(i) = state.failures.iter().position(|(k, _)| *k == kind) {
+            let (_, response) = state.failures.remove(i).unwrap();
+            self.closed = response.tag == OkNoBye::Bye;
+            return format!("{}\r\n", response);
+        }
+
+        match command {
+            Command::Authenticate(..) => {
+                self.authenticated = true;
+                reply(OkNoBye::Ok, None)
+            }
+            Command::StartTls => reply(OkNoBye::No, Some("STARTTLS is not supported")),
+            Command::Logout => {
+                self.closed = true;
+                reply(OkNoBye::Ok, None)
+            }
+            Command::Capability => capabilities_reply(&state.capabilities),
+            Command::Noop(tag) => noop_reply(tag.as_deref()),
+            _ if !self.authenticated => reply(OkNoBye::No, Some("Authenticate first")),
+            Command::UnAuthenticate => {
+                self.authenticated = false;
+                reply(OkNoBye::Ok, None)
+            }
+            command => dispatch(&mut state.store, None, command),
+        }
+    }
+}
+
+impl Read for MockConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.output.is_empty() && !self.closed {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.output.read(buf)
+    }
+}
+
+impl Write for MockConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.receive(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn send(connection: &mut MockConnection, command: &Command) -> String {
+    command.encode(connection).unwrap();
+    let mut reply = Vec::new();
+    let _ = connection.read_to_end(&mut reply);
+    String::from_utf8(reply).unwrap()
+}
+
+#[test]
+fn test_mock_server() {
+    let server = MockServer::new().with_store(MemoryStore::new().with_max_script_size(10));
+    server.put_script("old", "discard;").unwrap();
+    let mut connection = server.connect();
+    let mut greeting = Vec::new();
+    let _ = connection.read_to_end(&mut greeting);
+    let (_, caps, _) = crate::response_greeting(std::str::from_utf8(&greeting).unwrap()).unwrap();
+    assert_eq!(caps.len(), 4);
+
+    let put = Command::put_script("a", "keep;").unwrap();
+    let reply = send(&mut connection, &put);
+    assert_eq!(
+        crate::response_putscript(&reply).unwrap().1.tag,
+        OkNoBye::No
+    );
+    send(
+        &mut connection,
+        &Command::authenticate("PLAIN", Some("AGFsaWNlAHNlY3JldA==")),
+    );
+    assert_eq!(send(&mut connection, &put), "OK\r\n");
+    let reply = send(
+        &mut connection,
+        &Command::put_script("b", "x".repeat(11)).unwrap(),
+    );
+    assert_eq!(reply, "NO (QUOTA/MAXSIZE)\r\n");
+
+    send(&mut connection, &Command::set_active("a").unwrap());
+    let reply = send(&mut connection, &Command::ListScripts);
+    assert_eq!(reply, "\"a\" ACTIVE\r\n\"old\"\r\nOK\r\n");
+    let reply = send(&mut connection, &Command::get_script("a").unwrap());
+    assert_eq!(crate::response_getscript(&reply).unwrap().1, "keep;");
+    let reply = send(&mut connection, &Command::deletescript("a").unwrap());
+    assert_eq!(reply, "NO (ACTIVE)\r\n");
+    send(&mut connection, &Command::renamescript("a", "c").unwrap());
+    assert_eq!(server.active_script().unwrap(), "c");
+    assert_eq!(server.script("c"), Some(SieveScript::new("keep;")));
+
+    // A command split across writes is answered once complete.
+    connection.write_all(b"NOOP\r").unwrap();
+    let e = connection.read(&mut [0; 8]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+    connection.write_all(b"\n").unwrap();
+    let mut reply = [0; 8];
+    assert_eq!(connection.read(&mut reply).unwrap(), 4);
+    connection.write_all(b"FROB\r\n").unwrap();
+    let transcript = server.transcript();
+    assert_eq!(transcript.last().unwrap().command, None);
+    assert_eq!(transcript[0].request, put.to_string());
+}
+
+#[test]
+fn test_mock_server_failures() {
+    let server = MockServer::new();
+    let mut connection = server.connect();
+    send(
+        &mut connection,
+        &Command::authenticate("PLAIN", Some("AGFsaWNlAHNlY3JldA==")),
+    );
+
+    server.fail_next(
+        ResponseKind::Noop,
+        Response {
+            tag: OkNoBye::No,
+            code: Some((ResponseCode::TryLater, None)),
+            human: None,
+        },
+    );
+    server.fail_next(
+        ResponseKind::Capability,
+        Response {
+            tag: OkNoBye::Bye,
+            code: Some((
+                ResponseCode::Referral("sieve://b.example.com".to_string()),
+                None,
+            )),
+            human: None,
+        },
+    );
+    assert!(matches!(
+        crate::response_noop(&send(&mut connection, &Command::noop())),
+        Err(Error::Refused(Response {
+            code: Some((ResponseCode::TryLater, None)),
+            ..
+        }))
+    ));
+    assert_eq!(send(&mut connection, &Command::noop()), "OK\r\n");
+    assert_eq!(
+        send(&mut connection, &Command::noop_with_tag("a")),
+        "OK (TAG \"a\")\r\n"
+    );
+    let reply = send(&mut connection, &Command::Capability);
+    assert_eq!(reply, "BYE (REFERRAL \"sieve://b.example.com\")\r\n");
+    assert!(connection.is_closed());
+    assert!(Command::noop().encode(&mut connection).is_err());
+}
+
+#[test]
+fn test_mock_server_listen() {
+    use std::net::TcpStream;
+
+    let server = MockServer::new().with_capabilities(vec![Capability::StartTls]);
+    let mut stream = TcpStream::connect(server.listen().unwrap()).unwrap();
+    stream.write_all(b"CAPABILITY\r\nLOGOUT\r\n").unwrap();
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies).unwrap();
+    let (left, caps, _) = crate::response_greeting(&replies).unwrap();
+    assert_eq!(caps, vec![Capability::StartTls]);
+    let (left, caps, _) = crate::response_capability(left).unwrap();
+    assert_eq!(caps, vec![Capability::StartTls]);
+    assert_eq!(crate::response_logout(left).unwrap().0, "");
+}
+
+#[cfg(all(test, feature = "async"))]
+#[tokio::test]
+async fn test_mock_server_duplex() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = MockServer::new();
+    let (mut client, stream) = tokio::io::duplex(64);
+    let client = async {
+        client.write_all(b"LOGOUT\r\n").await.unwrap();
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await.unwrap();
+        replies
+    };
+    let (replies, served) = tokio::join!(client, server.serve_async(stream));
+    served.unwrap();
+    assert!(replies.ends_with("\r\nOK\r\nOK\r\n"));
+}
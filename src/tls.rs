@@ -0,0 +1,51 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, StreamOwned};
+
+/// Builds the TLS configuration for [`Server::with_tls`](crate::Server::with_tls)
+/// from PEM files holding the certificate chain and its private key. Uses
+/// the process's default rustls crypto provider, which must have been
+/// installed.
+pub fn load_tls_config(
+    cert_chain: impl AsRef<Path>,
+    key: impl AsRef<Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let invalid = |e: rustls::pki_types::pem::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+    let cert_chain = CertificateDer::pem_file_iter(cert_chain)
+        .map_err(invalid)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(invalid)?;
+    let provider = CryptoProvider::get_default().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "No default rustls crypto provider is installed",
+        )
+    })?;
+    let config = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| {
+            builder
+                .with_no_client_auth()
+                .with_single_cert(cert_chain, key)
+        })
+        .map_err(io::Error::other)?;
+    Ok(Arc::new(config))
+}
+
+// Performs the server's side of the TLS handshake over `stream`.
+pub(crate) fn accept<T: Read + Write>(
+    config: Arc<ServerConfig>,
+    mut stream: T,
+) -> io::Result<StreamOwned<rustls::ServerConnection, T>> {
+    let mut tls = rustls::ServerConnection::new(config).map_err(io::Error::other)?;
+    while tls.is_handshaking() {
+        tls.complete_io(&mut stream)?;
+    }
+    Ok(StreamOwned::new(tls, stream))
+}
@@ -1,19 +1,18 @@
+use std::borrow::Cow;
 use std::convert::TryFrom;
+use std::fmt::{self, Write as _};
 use std::io::{self, ErrorKind};
-use std::string::ToString;
 
-use nom;
+use either::Either;
 
+use crate::error::{offset_in, Error, ParseFailure};
+use crate::limits::ParserLimits;
 use crate::parser as p;
+use crate::script::SieveScript;
+use crate::sieve_name::{self, SieveName};
 
-#[derive(Debug, PartialEq)]
-pub enum Error {
-    IncompleteResponse,
-    InvalidResponse,
-    InvalidInput,
-}
-
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Capability {
     Implementation(String),
     Sasl(Vec<String>),
@@ -32,51 +31,174 @@ impl TryFrom<(&str, Option<&str>)> for Capability {
 
     fn try_from(s: (&str, Option<&str>)) -> Result<Self, Self::Error> {
         let (cap, rest) = s;
+        CapabilityRef::try_from((Cow::Borrowed(cap), rest.map(Cow::Borrowed)))
+            .map(CapabilityRef::into_owned)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Invalid Capability"))
+    }
+}
 
-        let err = || io::Error::new(ErrorKind::InvalidInput, "Invalid Capability");
-        let unwrap_rest = || rest.map(|o| o.to_owned()).ok_or_else(|| err());
-        let unwrap_rest_vec = || {
-            rest.map(|r| r.split(' ').map(|x| x.to_string()).collect())
-                .ok_or_else(|| err())
+/// Displays the capability as the line sent by the server, without CRLF.
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, value) = match self {
+            Capability::Implementation(s) => ("IMPLEMENTATION", Some(s.clone())),
+            Capability::Sasl(v) => ("SASL", Some(v.join(" "))),
+            Capability::Sieve(v) => ("SIEVE", Some(v.join(" "))),
+            Capability::StartTls => ("STARTTLS", None),
+            Capability::MaxRedirects(n) => ("MAXREDIRECTS", Some(n.to_string())),
+            Capability::Notify(v) => ("NOTIFY", Some(v.join(" "))),
+            Capability::Language(s) => ("LANGUAGE", Some(s.clone())),
+            Capability::Owner(s) => ("OWNER", Some(s.clone())),
+            Capability::Version(s) => ("VERSION", Some(s.clone())),
+            Capability::Unknown(name, value) => (name.as_str(), value.clone()),
         };
+        write!(f, "{}", Quoted(name))?;
+        if let Some(value) = value {
+            write!(f, " {}", Quoted(&value))?;
+        }
+        Ok(())
+    }
+}
+
+/// A capability borrowing its strings from the parsed input where possible.
+#[derive(Debug, PartialEq, Clone)]
+pub enum CapabilityRef<'a> {
+    Implementation(Cow<'a, str>),
+    Sasl(Vec<Cow<'a, str>>),
+    Sieve(Vec<Cow<'a, str>>),
+    StartTls,
+    MaxRedirects(usize),
+    Notify(Vec<Cow<'a, str>>),
+    Language(Cow<'a, str>),
+    Owner(Cow<'a, str>),
+    Version(Cow<'a, str>),
+    Unknown(Cow<'a, str>, Option<Cow<'a, str>>),
+}
 
-        Ok(match cap {
-            "IMPLEMENTATION" => Capability::Implementation(unwrap_rest()?),
-            "SASL" => Capability::Sasl(unwrap_rest_vec()?),
-            "SIEVE" => Capability::Sieve(unwrap_rest_vec()?),
-            "STARTTLS" => Capability::StartTls,
-            "MAXREDIRECTS" => {
-                Capability::MaxRedirects(unwrap_rest()?.parse::<usize>().map_err(|_| err())?)
+impl CapabilityRef<'_> {
+    pub fn into_owned(self) -> Capability {
+        let vec = |v: Vec<Cow<str>>| v.into_iter().map(Cow::into_owned).collect();
+        match self {
+            CapabilityRef::Implementation(s) => Capability::Implementation(s.into_owned()),
+            CapabilityRef::Sasl(v) => Capability::Sasl(vec(v)),
+            CapabilityRef::Sieve(v) => Capability::Sieve(vec(v)),
+            CapabilityRef::StartTls => Capability::StartTls,
+            CapabilityRef::MaxRedirects(n) => Capability::MaxRedirects(n),
+            CapabilityRef::Notify(v) => Capability::Notify(vec(v)),
+            CapabilityRef::Language(s) => Capability::Language(s.into_owned()),
+            CapabilityRef::Owner(s) => Capability::Owner(s.into_owned()),
+            CapabilityRef::Version(s) => Capability::Version(s.into_owned()),
+            CapabilityRef::Unknown(cap, rest) => {
+                Capability::Unknown(cap.into_owned(), rest.map(Cow::into_owned))
             }
-            "NOTIFY" => Capability::Notify(unwrap_rest_vec()?),
-            "LANGUAGE" => Capability::Owner(unwrap_rest()?),
-            "OWNER" => Capability::Owner(unwrap_rest()?),
-            "VERSION" => Capability::Version(unwrap_rest()?),
-            cap => Capability::Unknown(cap.to_owned(), rest.map(|s| s.to_owned())),
+        }
+    }
+}
+
+impl From<CapabilityRef<'_>> for Capability {
+    fn from(c: CapabilityRef<'_>) -> Self {
+        c.into_owned()
+    }
+}
+
+// Splits a space-separated list, borrowing the items if the list is borrowed.
+// An empty list has no items.
+fn split_words(s: Cow<str>) -> Vec<Cow<str>> {
+    match s {
+        Cow::Borrowed(s) => s.split_whitespace().map(Cow::Borrowed).collect(),
+        Cow::Owned(s) => s
+            .split_whitespace()
+            .map(|w| Cow::Owned(w.to_owned()))
+            .collect(),
+    }
+}
+
+impl<'a> TryFrom<(Cow<'a, str>, Option<Cow<'a, str>>)> for CapabilityRef<'a> {
+    type Error = Error;
+
+    /// Interprets a capability name and its value. Fails with
+    /// `Error::InvalidResponse` if a known capability lacks its value or the
+    /// value is malformed.
+    fn try_from(s: (Cow<'a, str>, Option<Cow<'a, str>>)) -> Result<Self, Self::Error> {
+        let (cap, rest) = s;
+        let invalid = || Error::InvalidResponse(ParseFailure::new(&cap, 0, "single-capability"));
+        let rest = || rest.clone().ok_or_else(invalid);
+
+        Ok(match &*cap {
+            "IMPLEMENTATION" => CapabilityRef::Implementation(rest()?),
+            "SASL" => CapabilityRef::Sasl(split_words(rest()?)),
+            "SIEVE" => CapabilityRef::Sieve(split_words(rest()?)),
+            "STARTTLS" => CapabilityRef::StartTls,
+            "MAXREDIRECTS" => CapabilityRef::MaxRedirects(rest()?.parse().map_err(|_| invalid())?),
+            "NOTIFY" => CapabilityRef::Notify(split_words(rest()?)),
+            "LANGUAGE" => CapabilityRef::Language(rest()?),
+            "OWNER" => CapabilityRef::Owner(rest()?),
+            "VERSION" => CapabilityRef::Version(rest()?),
+            _ => CapabilityRef::Unknown(cap.clone(), rest().ok()),
         })
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[test]
+fn test_capability_ref() {
+    let cap = CapabilityRef::try_from((
+        Cow::Borrowed("SIEVE"),
+        Some(Cow::Borrowed("fileinto vacation")),
+    ))
+    .unwrap();
+    assert!(matches!(&cap, CapabilityRef::Sieve(v) if matches!(v[1], Cow::Borrowed("vacation"))));
+    assert_eq!(
+        cap.into_owned(),
+        Capability::Sieve(vec!["fileinto".to_string(), "vacation".to_string()])
+    );
+    assert_eq!(
+        Capability::try_from(("LANGUAGE", Some("fr"))).unwrap(),
+        Capability::Language("fr".to_string())
+    );
+    assert!(matches!(
+        CapabilityRef::try_from((Cow::Borrowed("MAXREDIRECTS"), Some(Cow::Borrowed("x")))),
+        Err(Error::InvalidResponse(_))
+    ));
+    assert!(matches!(
+        CapabilityRef::try_from((Cow::Borrowed("OWNER"), None)),
+        Err(Error::InvalidResponse(_))
+    ));
+    assert_eq!(
+        Capability::Sasl(vec!["PLAIN".to_string(), "SCRAM-SHA-256".to_string()]).to_string(),
+        "\"SASL\" \"PLAIN SCRAM-SHA-256\""
+    );
+    assert_eq!(Capability::StartTls.to_string(), "\"STARTTLS\"");
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Command {
-    Authenticate,
+    /// Starts SASL authentication with the named mechanism, and the
+    /// base64-encoded initial response if the mechanism has one.
+    Authenticate(String, Option<String>),
     StartTls,
     Logout,
     Capability,
-    HaveSpace(String, usize),
-    PutScript(String, String),
+    HaveSpace(SieveName, usize),
+    PutScript(SieveName, SieveScript),
+    GetScript(SieveName),
     ListScripts,
-    SetActive(String),
-    DeleteScript(String),
-    RenameScript(String),
-    CheckScript(String),
-    Noop,
+    /// Activates the named script, or deactivates the active script if
+    /// `None`.
+    SetActive(Option<SieveName>),
+    DeleteScript(SieveName),
+    /// Renames the first script to the second name.
+    RenameScript(SieveName, SieveName),
+    CheckScript(SieveScript),
+    /// NOOP, with any tag the server is to echo in its response.
+    Noop(Option<String>),
     UnAuthenticate,
 }
 
 impl Command {
-    pub fn authenticate() -> Command {
-        Command::Authenticate
+    /// `initial_response` is base64-encoded, as sent.
+    pub fn authenticate(mechanism: &str, initial_response: Option<&str>) -> Command {
+        Command::Authenticate(mechanism.to_owned(), initial_response.map(str::to_owned))
     }
 
     pub fn start_tls() -> Command {
@@ -92,90 +214,412 @@ impl Command {
     }
 
     pub fn have_space(name: &str, size: usize) -> Result<Command, Error> {
-        Ok(Command::HaveSpace(to_sieve_name(name)?, size))
+        Ok(Command::HaveSpace(SieveName::new(name)?, size))
+    }
+
+    /// Asks whether there is room for `script` under `name`.
+    pub fn have_space_for(name: &str, script: &SieveScript) -> Result<Command, Error> {
+        Command::have_space(name, script.len())
+    }
+
+    pub fn put_script(name: &str, script: impl Into<SieveScript>) -> Result<Command, Error> {
+        Ok(Command::PutScript(SieveName::new(name)?, script.into()))
     }
 
-    pub fn put_script(name: &str, script: &str) -> Result<Command, Error> {
-        Ok(Command::PutScript(to_sieve_name(name)?, script.to_owned()))
+    pub fn get_script(name: &str) -> Result<Command, Error> {
+        Ok(Command::GetScript(SieveName::new(name)?))
     }
 
     pub fn list_scripts() -> Command {
         Command::ListScripts
     }
 
+    /// An empty `name` deactivates the active script.
     pub fn set_active(name: &str) -> Result<Command, Error> {
-        Ok(Command::SetActive(to_sieve_name(name)?))
+        match name {
+            "" => Ok(Command::SetActive(None)),
+            name => Ok(Command::SetActive(Some(SieveName::new(name)?))),
+        }
     }
 
     pub fn deletescript(name: &str) -> Result<Command, Error> {
-        Ok(Command::DeleteScript(to_sieve_name(name)?))
+        Ok(Command::DeleteScript(SieveName::new(name)?))
     }
 
-    pub fn renamescript(name: &str) -> Result<Command, Error> {
-        Ok(Command::RenameScript(to_sieve_name(name)?))
+    pub fn renamescript(old_name: &str, new_name: &str) -> Result<Command, Error> {
+        Ok(Command::RenameScript(
+            SieveName::new(old_name)?,
+            SieveName::new(new_name)?,
+        ))
     }
 
-    pub fn checkscript(name: &str) -> Result<Command, Error> {
-        Ok(Command::CheckScript(to_sieve_name(name)?))
+    pub fn checkscript(script: impl Into<SieveScript>) -> Command {
+        Command::CheckScript(script.into())
     }
 
     pub fn noop() -> Command {
-        Command::Noop
+        Command::Noop(None)
+    }
+
+    pub fn noop_with_tag(tag: &str) -> Command {
+        Command::Noop(Some(tag.to_owned()))
     }
 
     pub fn unauthenticate() -> Command {
         Command::UnAuthenticate
     }
+
+    /// A copy of the command with any script or SASL initial response
+    /// replaced by a placeholder, for logging.
+    pub fn redacted(&self) -> Command {
+        match self {
+            Command::Authenticate(mechanism, Some(_)) => {
+                Command::Authenticate(mechanism.clone(), Some(REDACTED.to_string()))
+            }
+            Command::PutScript(name, _) => Command::PutScript(name.clone(), REDACTED.into()),
+            Command::CheckScript(_) => Command::CheckScript(REDACTED.into()),
+            command => command.clone(),
+        }
+    }
+
+    /// Writes the command to `w`, as rendered by its `Display`
+    /// implementation. A script is written as it is, without being copied;
+    /// wrap `w` in a `BufWriter` to batch the smaller writes.
+    pub fn encode<W: io::Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        let script = match self {
+            Command::PutScript(name, script) => {
+                write!(w, "PUTSCRIPT {} ", QuotedArg(name.as_str()))?;
+                script
+            }
+            Command::CheckScript(script) => {
+                w.write_all(b"CHECKSCRIPT ")?;
+                script
+            }
+            command => return write!(w, "{}", command),
+        };
+        write!(w, "{{{}+}}\r\n", script.len())?;
+        w.write_all(script.as_str().as_bytes())?;
+        w.write_all(b"\r\n")
+    }
+
+    /// Appends the command to `buf`, as rendered by its `Display`
+    /// implementation. Fails if `buf` cannot grow to hold it.
+    #[cfg(feature = "bytes")]
+    pub fn encode_buf<B: bytes::BufMut>(&self, buf: &mut B) -> io::Result<()> {
+        use bytes::BufMut;
+        use std::io::Write;
+
+        write!(buf.writer(), "{}", self)
+    }
 }
 
-fn to_sieve_name(s: &str) -> Result<String, Error> {
-    if s.chars().find(|c| p::is_bad_sieve_name_char(*c)).is_some() {
-        return Err(Error::InvalidInput);
+// Stands in for script contents and SASL payloads removed by redacted().
+const REDACTED: &str = "<redacted>";
+
+// Displays a string as a quoted string or, if it holds a CR or LF, which
+// quoted strings cannot, as a literal.
+struct Quoted<'a>(&'a str);
+
+// Like Quoted, for strings sent by a client, whose literals are
+// non-synchronizing.
+struct QuotedArg<'a>(&'a str);
+
+impl fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_string(f, self.0, "")
     }
+}
 
-    Ok(s.to_owned())
+impl fmt::Display for QuotedArg<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_string(f, self.0, "+")
+    }
 }
 
-// to quotedstring
-fn to_qs(s: &str) -> String {
-    // TODO: escape some things in s?
-    format!("\"{}\"", s)
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str, plus: &str) -> fmt::Result {
+    if s.contains(['\r', '\n']) {
+        return write!(f, "{{{}{}}}\r\n{}", s.len(), plus, s);
+    }
+    f.write_char('"')?;
+    let mut rest = s;
+    while let Some(i) = rest.find(['"', '\\']) {
+        f.write_str(&rest[..i])?;
+        f.write_char('\\')?;
+        f.write_str(&rest[i..=i])?;
+        rest = &rest[i + 1..];
+    }
+    f.write_str(rest)?;
+    f.write_char('"')
 }
 
-fn to_lit_c2s(s: &str) -> String {
-    format!("{{{}+}}\r\n{}", s.len(), s)
+// to quotedstring
+pub(crate) fn to_qs(s: &str) -> String {
+    Quoted(s).to_string()
 }
 
-impl ToString for Command {
-    fn to_string(&self) -> String {
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Command::Authenticate => "AUTHENTICATE\r\n".into(),
-            Command::StartTls => "STARTTLS\r\n".into(),
-            Command::Logout => "LOGOUT\r\n".into(),
-            Command::Capability => "CAPABILITY\r\n".into(),
-            Command::HaveSpace(name, size) => format!("HAVESPACE {} {}\r\n", to_qs(name), size),
-            Command::PutScript(name, script) => {
-                format!("PUTSCRIPT {} {}\r\n", to_qs(name), to_lit_c2s(script))
+            Command::Authenticate(mechanism, None) => {
+                write!(f, "AUTHENTICATE {}\r\n", QuotedArg(mechanism))
             }
-            Command::ListScripts => "LISTSCRIPTS\r\n".into(),
-            Command::SetActive(name) => format!("SETACTIVE {}\r\n", to_qs(name)),
-            Command::DeleteScript(name) => format!("DELETESCRIPT {}\r\n", to_qs(name)),
-            Command::RenameScript(name) => format!("RENAMESCRIPT {}\r\n", to_qs(name)),
-            Command::CheckScript(name) => format!("CHECKSCRIPT {}\r\n", to_qs(name)),
-            Command::Noop => "NOOP\r\n".into(),
-            Command::UnAuthenticate => "UNAUTHENTICATE\r\n".into(),
+            Command::Authenticate(mechanism, Some(initial_response)) => write!(
+                f,
+                "AUTHENTICATE {} {}\r\n",
+                QuotedArg(mechanism),
+                QuotedArg(initial_response)
+            ),
+            Command::StartTls => write!(f, "STARTTLS\r\n"),
+            Command::Logout => write!(f, "LOGOUT\r\n"),
+            Command::Capability => write!(f, "CAPABILITY\r\n"),
+            Command::HaveSpace(name, size) => {
+                write!(f, "HAVESPACE {} {}\r\n", QuotedArg(name.as_str()), size)
+            }
+            Command::PutScript(name, script) => write!(
+                f,
+                "PUTSCRIPT {} {{{}+}}\r\n{}\r\n",
+                QuotedArg(name.as_str()),
+                script.len(),
+                script
+            ),
+            Command::GetScript(name) => write!(f, "GETSCRIPT {}\r\n", QuotedArg(name.as_str())),
+            Command::ListScripts => write!(f, "LISTSCRIPTS\r\n"),
+            Command::SetActive(name) => write!(
+                f,
+                "SETACTIVE {}\r\n",
+                QuotedArg(name.as_ref().map_or("", SieveName::as_str))
+            ),
+            Command::DeleteScript(name) => {
+                write!(f, "DELETESCRIPT {}\r\n", QuotedArg(name.as_str()))
+            }
+            Command::RenameScript(old_name, new_name) => write!(
+                f,
+                "RENAMESCRIPT {} {}\r\n",
+                QuotedArg(old_name.as_str()),
+                QuotedArg(new_name.as_str())
+            ),
+            Command::CheckScript(script) => {
+                write!(f, "CHECKSCRIPT {{{}+}}\r\n{}\r\n", script.len(), script)
+            }
+            Command::Noop(None) => write!(f, "NOOP\r\n"),
+            Command::Noop(Some(tag)) => write!(f, "NOOP {}\r\n", QuotedArg(tag)),
+            Command::UnAuthenticate => write!(f, "UNAUTHENTICATE\r\n"),
+        }
+    }
+}
+
+#[test]
+fn test_command_encode() {
+    let command = Command::put_script("a\"b\\", "keep;").unwrap();
+    let expected = "PUTSCRIPT \"a\\\"b\\\\\" {5+}\r\nkeep;\r\n";
+    assert_eq!(command.to_string(), expected);
+
+    let mut buf = Vec::new();
+    command.encode(&mut buf).unwrap();
+    Command::noop().encode(&mut buf).unwrap();
+    assert_eq!(buf, format!("{}NOOP\r\n", expected).as_bytes());
+
+    // Records whether the script itself, rather than a copy, was written.
+    struct Borrowed<'a>(&'a str, bool);
+
+    impl io::Write for Borrowed<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1 |= buf.as_ptr() == self.0.as_ptr() && buf.len() == self.0.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
         }
     }
+
+    let script = SieveScript::new("keep;\r\n");
+    for command in [
+        Command::put_script("a", script.clone()).unwrap(),
+        Command::checkscript(script.clone()),
+    ] {
+        let (Command::PutScript(_, s) | Command::CheckScript(s)) = &command else {
+            unreachable!()
+        };
+        let mut w = Borrowed(s.as_str(), false);
+        command.encode(&mut w).unwrap();
+        assert!(w.1);
+        let mut buf = Vec::new();
+        command.encode(&mut buf).unwrap();
+        assert_eq!(buf, command.to_string().as_bytes());
+    }
+
+    assert_eq!(
+        Command::noop_with_tag("a\nb").to_string(),
+        "NOOP {3+}\r\na\nb\r\n"
+    );
+    assert_eq!(
+        Command::set_active("").unwrap().to_string(),
+        "SETACTIVE \"\"\r\n"
+    );
+    assert_eq!(
+        Command::renamescript("a", "b").unwrap().to_string(),
+        "RENAMESCRIPT \"a\" \"b\"\r\n"
+    );
+    let script = SieveScript::new("\u{feff}keep;\n").normalized();
+    assert_eq!(
+        Command::checkscript(script.clone()).to_string(),
+        "CHECKSCRIPT {7+}\r\nkeep;\r\n\r\n"
+    );
+    assert_eq!(
+        Command::have_space_for("a", &script).unwrap().to_string(),
+        "HAVESPACE \"a\" 7\r\n"
+    );
+    assert_eq!(
+        Command::authenticate("PLAIN", Some("AGEAYg==")).to_string(),
+        "AUTHENTICATE \"PLAIN\" \"AGEAYg==\"\r\n"
+    );
+    assert_eq!(
+        Command::authenticate("PLAIN", Some("AGEAYg==")).redacted(),
+        Command::authenticate("PLAIN", Some("<redacted>"))
+    );
+    assert_eq!(Command::get_script(""), Err(Error::InvalidInput));
+    assert_eq!(Command::deletescript("e\u{301}"), Err(Error::InvalidInput));
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn test_command_encode_buf() {
+    let mut buf = bytes::BytesMut::new();
+    Command::get_script("a")
+        .unwrap()
+        .encode_buf(&mut buf)
+        .unwrap();
+    assert_eq!(&buf[..], b"GETSCRIPT \"a\"\r\n");
+
+    let mut small = [0u8; 4];
+    assert!(Command::noop().encode_buf(&mut &mut small[..]).is_err());
+}
+
+/// Parses a command sent by the client, as a server would. A malformed
+/// command results in `Error::InvalidCommand`, locating the offending input
+/// as for responses.
+pub fn parse_command(input: &str) -> Result<(&str, Command), Error> {
+    parse_command_with_limits(input, &DEFAULT_LIMITS)
+}
+
+pub(crate) fn parse_command_with_limits<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> Result<(&'a str, Command), Error> {
+    use p::Argument::{Number, String as Str};
+
+    let (left, (name, args)) =
+        p::command(input, limits).map_err(|e| Error::parse_command(input, e))?;
+    let sieve_name = |s: &str| {
+        SieveName::new(s)
+            .map_err(|_| Error::InvalidCommand(ParseFailure::at(input, s, "sieve-name")))
+    };
+    let command = match (name.to_ascii_uppercase().as_str(), args.as_slice()) {
+        ("AUTHENTICATE", [Str(mechanism)]) => Command::Authenticate(mechanism.to_string(), None),
+        ("AUTHENTICATE", [Str(mechanism), Str(initial_response)]) => {
+            Command::Authenticate(mechanism.to_string(), Some(initial_response.to_string()))
+        }
+        ("STARTTLS", []) => Command::StartTls,
+        ("LOGOUT", []) => Command::Logout,
+        ("CAPABILITY", []) => Command::Capability,
+        ("HAVESPACE", [Str(name), Number(size)]) => Command::HaveSpace(sieve_name(name)?, *size),
+        ("PUTSCRIPT", [Str(name), Str(script)]) => {
+            Command::PutScript(sieve_name(name)?, SieveScript::new(script))
+        }
+        ("GETSCRIPT", [Str(name)]) => Command::GetScript(sieve_name(name)?),
+        ("LISTSCRIPTS", []) => Command::ListScripts,
+        ("SETACTIVE", [Str(name)]) if name.is_empty() => Command::SetActive(None),
+        ("SETACTIVE", [Str(name)]) => Command::SetActive(Some(sieve_name(name)?)),
+        ("DELETESCRIPT", [Str(name)]) => Command::DeleteScript(sieve_name(name)?),
+        ("RENAMESCRIPT", [Str(old_name), Str(new_name)]) => {
+            Command::RenameScript(sieve_name(old_name)?, sieve_name(new_name)?)
+        }
+        ("CHECKSCRIPT", [Str(script)]) => Command::CheckScript(SieveScript::new(script)),
+        ("NOOP", []) => Command::Noop(None),
+        ("NOOP", [Str(tag)]) => Command::Noop(Some(tag.to_string())),
+        ("UNAUTHENTICATE", []) => Command::UnAuthenticate,
+        _ => {
+            return Err(Error::InvalidCommand(ParseFailure::at(
+                input, name, "command",
+            )))
+        }
+    };
+    Ok((left, command))
+}
+
+#[test]
+fn test_parse_command() {
+    for command in [
+        Command::Capability,
+        Command::authenticate("SCRAM-SHA-256", None),
+        Command::authenticate("PLAIN", Some("AGEAYg==")),
+        Command::have_space("a", 10).unwrap(),
+        Command::put_script("a\"b", "keep;\r\n").unwrap(),
+        Command::get_script("a").unwrap(),
+        Command::set_active("").unwrap(),
+        Command::renamescript("a", "b").unwrap(),
+        Command::checkscript("keep;"),
+        Command::noop(),
+        Command::noop_with_tag("a\"b"),
+    ] {
+        let encoded = format!("{}NOOP\r\n", command);
+        assert_eq!(parse_command(&encoded), Ok(("NOOP\r\n", command)));
+    }
+    assert!(parse_command("GETSCRIPT").unwrap_err().is_incomplete());
+    assert!(matches!(
+        parse_command("DELETESCRIPT \"\"\r\n"),
+        Err(Error::InvalidCommand(ParseFailure {
+            offset: 14,
+            element: "sieve-name",
+            ..
+        }))
+    ));
+    assert!(matches!(
+        parse_command("FROB\r\n"),
+        Err(Error::InvalidCommand(ParseFailure {
+            offset: 0,
+            element: "command",
+            ..
+        }))
+    ));
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Response {
     pub tag: OkNoBye,
     pub code: Option<(ResponseCode, Option<String>)>,
     pub human: Option<HumanReadableString>,
 }
 
+/// A response borrowing its strings from the parsed input where possible.
+/// Strings are only allocated when they contain escapes.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResponseRef<'a> {
+    pub tag: OkNoBye,
+    pub code: Option<(ResponseCode, Option<Cow<'a, str>>)>,
+    pub human: Option<Cow<'a, str>>,
+}
+
+impl ResponseRef<'_> {
+    pub fn into_owned(self) -> Response {
+        Response {
+            tag: self.tag,
+            code: self.code.map(|(code, s)| (code, s.map(Cow::into_owned))),
+            human: self.human.map(Cow::into_owned),
+        }
+    }
+}
+
+impl From<ResponseRef<'_>> for Response {
+    fn from(r: ResponseRef<'_>) -> Self {
+        r.into_owned()
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OkNoBye {
     Ok,
     No,
@@ -199,6 +643,7 @@ impl std::fmt::Display for OkNoBye {
 pub type SieveUrl = String;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum QuotaVariant {
     None,
     MaxScripts,
@@ -209,6 +654,7 @@ type SieveString = String;
 type HumanReadableString = SieveString;
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ResponseCode {
     AuthTooWeak,
     EncryptNeeded,
@@ -224,19 +670,195 @@ pub enum ResponseCode {
     Warnings,
 }
 
+impl fmt::Display for ResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseCode::AuthTooWeak => f.write_str("AUTH-TOO-WEAK"),
+            ResponseCode::EncryptNeeded => f.write_str("ENCRYPT-NEEDED"),
+            ResponseCode::Quota(QuotaVariant::None) => f.write_str("QUOTA"),
+            ResponseCode::Quota(QuotaVariant::MaxScripts) => f.write_str("QUOTA/MAXSCRIPTS"),
+            ResponseCode::Quota(QuotaVariant::MaxSize) => f.write_str("QUOTA/MAXSIZE"),
+            ResponseCode::Referral(url) => write!(f, "REFERRAL {}", Quoted(url)),
+            ResponseCode::Sasl => f.write_str("SASL"),
+            ResponseCode::TransitionNeeded => f.write_str("TRANSITION-NEEDED"),
+            ResponseCode::TryLater => f.write_str("TRYLATER"),
+            ResponseCode::Active => f.write_str("ACTIVE"),
+            ResponseCode::Nonexistent => f.write_str("NONEXISTENT"),
+            ResponseCode::AlreadyExists => f.write_str("ALREADYEXISTS"),
+            ResponseCode::Tag => f.write_str("TAG"),
+            ResponseCode::Warnings => f.write_str("WARNINGS"),
+        }
+    }
+}
+
+/// Displays the response as the line sent by the server.
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.tag)?;
+        match &self.code {
+            Some((code, Some(s))) => write!(f, " ({} {})", code, Quoted(s))?,
+            Some((code, None)) => write!(f, " ({})", code)?,
+            None => {}
+        }
+        if let Some(human) = &self.human {
+            write!(f, " {}", Quoted(human))?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_response_display() {
+    let (_, r) = response_putscript("NO (QUOTA/MAXSIZE) \"Too \\\"big\\\"\"\r\n").unwrap();
+    assert_eq!(r.to_string(), "NO (QUOTA/MAXSIZE) \"Too \\\"big\\\"\"");
+    let (_, r) = response_noop("OK (TAG \"a\")\r\n").unwrap();
+    assert_eq!(r.to_string(), "OK (TAG \"a\")");
+
+    // Text holding a line break is sent as a literal.
+    let r = Response {
+        tag: OkNoBye::No,
+        code: None,
+        human: Some("disk I/O error\r\nat line 2".to_string()),
+    };
+    let line = format!("{}\r\n", r);
+    assert_eq!(line, "NO {25}\r\ndisk I/O error\r\nat line 2\r\n");
+    assert_eq!(response_putscript(&line), Ok(("", r)));
+}
+
+/// A script name and whether it is the active script.
+pub type ScriptEntry = (SieveName, bool);
+
+/// A script name borrowed from the parsed input where possible, and whether
+/// it is the active script. The name has been checked to be a valid
+/// [`SieveName`].
+pub type ScriptEntryRef<'a> = (Cow<'a, str>, bool);
+
+/// The server may send BYE at any time before closing the connection,
+/// regardless of which response was expected.
+fn check_bye(input: &str, limits: &ParserLimits) -> Result<(), Error> {
+    match p::response_bye(input, limits) {
+        Ok((_, r)) => Err(Error::UnsolicitedBye(r.into_owned())),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Parses a response consisting of only an OK, NO or BYE line, as returned
+/// for most commands, borrowing its strings from `input`.
+pub fn response_oknobye_ref(input: &str) -> Result<(&str, ResponseRef<'_>), Error> {
+    parse_oknobye(input, &DEFAULT_LIMITS)
+}
+
+// The limits applied by the response_ functions.
+const DEFAULT_LIMITS: ParserLimits = ParserLimits::new();
+
+pub(crate) fn parse_oknobye<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> Result<(&'a str, ResponseRef<'a>), Error> {
+    check_bye(input, limits)?;
+    p::response(input, limits).map_err(|e| Error::parse(input, e))
+}
+
 fn response_oknobye(input: &str) -> Result<(&str, Response), Error> {
-    match p::response(input) {
-        Ok((left, response)) => Ok((left, response)),
-        Err(e) => match e {
-            nom::Err::Incomplete(_) => Err(Error::IncompleteResponse),
-            nom::Err::Error(_) => Err(Error::InvalidResponse),
-            nom::Err::Failure(_) => Err(Error::InvalidResponse),
-        },
+    response_oknobye_ref(input).map(|(left, r)| (left, r.into_owned()))
+}
+
+fn to_capabilities<'a>(
+    input: &str,
+    s: Vec<p::CapabilityLine<'a>>,
+) -> Result<Vec<CapabilityRef<'a>>, Error> {
+    s.into_iter()
+        .map(|(cap, rest)| {
+            let offset = offset_in(input, &cap);
+            CapabilityRef::try_from((cap, rest)).map_err(|_| {
+                Error::InvalidResponse(ParseFailure::new(input, offset, "single-capability"))
+            })
+        })
+        .collect()
+}
+
+fn into_owned_capabilities(caps: Vec<CapabilityRef>) -> Vec<Capability> {
+    caps.into_iter().map(CapabilityRef::into_owned).collect()
+}
+
+/// Parses the greeting sent by the server when the connection is opened.
+/// Returns list of capabilities. A server refusing the connection, possibly
+/// with a referral to another server, results in `Error::UnsolicitedBye`.
+pub fn response_greeting(input: &str) -> Result<(&str, Vec<Capability>, Response), Error> {
+    let (left, caps, resp) = response_greeting_ref(input)?;
+    Ok((left, into_owned_capabilities(caps), resp.into_owned()))
+}
+
+/// Like [`response_greeting`], but borrows strings from `input`.
+pub fn response_greeting_ref(
+    input: &str,
+) -> Result<(&str, Vec<CapabilityRef<'_>>, ResponseRef<'_>), Error> {
+    let (left, caps, resp) = parse_capability(input, &DEFAULT_LIMITS)?;
+    match resp.tag {
+        OkNoBye::Ok => Ok((left, caps, resp)),
+        OkNoBye::Bye => Err(Error::UnsolicitedBye(resp.into_owned())),
+        OkNoBye::No => Err(Error::Refused(resp.into_owned())),
     }
 }
 
-pub fn response_authenticate(_input: &str) -> Result<OkNoBye, Error> {
-    unimplemented!()
+#[test]
+fn test_response_greeting() {
+    let inc1 = include_str!("test_input/response_capability-1.txt");
+    let (left, caps, resp) = response_greeting(inc1).unwrap();
+    assert_eq!(left, "\r\n");
+    assert_eq!(caps.len(), 6);
+    assert_eq!(resp.human.as_deref(), Some("Dovecot ready."));
+
+    assert_eq!(
+        response_greeting("BYE (REFERRAL \"sieve://b.example.com\") \"Moved\"\r\n"),
+        Err(Error::UnsolicitedBye(Response {
+            tag: OkNoBye::Bye,
+            code: Some((
+                ResponseCode::Referral("sieve://b.example.com".to_string()),
+                None
+            )),
+            human: Some("Moved".to_string()),
+        }))
+    );
+    assert!(matches!(
+        response_greeting("\"SASL\" \"PLAIN\"\r\nBYE\r\n"),
+        Err(Error::UnsolicitedBye(_))
+    ));
+    assert!(matches!(
+        response_greeting("\"SASL\" \"PLAIN\"\r\n"),
+        Err(Error::IncompleteResponse(_))
+    ));
+    assert!(matches!(
+        response_greeting("NO\r\n"),
+        Err(Error::Refused(_))
+    ));
+}
+
+/// Parses text returned from the server in response to the AUTHENTICATE
+/// command, or to the client's answer to a SASL challenge. Returns either the
+/// server's next challenge or its final response.
+pub fn response_authenticate(input: &str) -> Result<(&str, Either<String, Response>), Error> {
+    let (left, r) = response_authenticate_ref(input)?;
+    Ok((
+        left,
+        r.map_left(Cow::into_owned)
+            .map_right(ResponseRef::into_owned),
+    ))
+}
+
+/// Like [`response_authenticate`], but borrows strings from `input`.
+pub fn response_authenticate_ref(
+    input: &str,
+) -> Result<(&str, Either<Cow<'_, str>, ResponseRef<'_>>), Error> {
+    parse_authenticate(input, &DEFAULT_LIMITS)
+}
+
+pub(crate) fn parse_authenticate<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> Result<(&'a str, Either<Cow<'a, str>, ResponseRef<'a>>), Error> {
+    check_bye(input, limits)?;
+    p::response_authenticate(input, limits).map_err(|e| Error::parse(input, e))
 }
 
 /// Parses text returned from the server in response to the LOGOUT command.
@@ -246,14 +868,41 @@ pub fn response_logout(input: &str) -> Result<(&str, Response), Error> {
 
 /// Parses text returned from the server in response to the GETSCRIPT command.
 pub fn response_getscript(input: &str) -> Result<(&str, String, Response), Error> {
-    match p::response_getscript(input) {
-        Ok((left, (Some(s), resp))) => Ok((left, s, resp)),
-        Err(nom::Err::Incomplete(_)) => Err(Error::IncompleteResponse),
-        _ => Err(Error::InvalidResponse),
+    let (left, s, resp) = response_getscript_ref(input)?;
+    Ok((left, s.into_owned(), resp.into_owned()))
+}
+
+/// Like [`response_getscript`], but borrows the script from `input`.
+pub fn response_getscript_ref(input: &str) -> Result<(&str, Cow<'_, str>, ResponseRef<'_>), Error> {
+    check_bye(input, &DEFAULT_LIMITS)?;
+    match p::response_getscript(input, &DEFAULT_LIMITS).map_err(|e| Error::parse(input, e))? {
+        (left, (Some(s), resp)) => Ok((left, s, resp)),
+        (_, (None, resp)) => Err(Error::Refused(resp.into_owned())),
     }
 }
 
-/// Parses text returned from the server in response to the GETSCRIPT command.
+#[test]
+fn test_response_getscript_refused() {
+    assert_eq!(
+        response_getscript("NO (NONEXISTENT)\r\n"),
+        Err(Error::Refused(Response {
+            tag: OkNoBye::No,
+            code: Some((ResponseCode::Nonexistent, None)),
+            human: None,
+        }))
+    );
+    let e = response_listscripts("\"a\" ACTIVE\r\n\"b\" ACTIVE\r\nOK\r\n").unwrap_err();
+    assert!(matches!(
+        e,
+        Error::InvalidResponse(ParseFailure {
+            offset: 13,
+            element: "response-listscripts",
+            ..
+        })
+    ));
+}
+
+/// Parses text returned from the server in response to the SETACTIVE command.
 pub fn response_setactive(input: &str) -> Result<(&str, Response), Error> {
     response_oknobye(input)
 }
@@ -261,17 +910,74 @@ pub fn response_setactive(input: &str) -> Result<(&str, Response), Error> {
 /// Parses text returned from the server in response to the LISTSCRIPTS command.
 /// Returns list of scripts and a bool indicating if that script is the active
 /// script.
-pub fn response_listscripts(input: &str) -> Result<(&str, Vec<(String, bool)>, Response), Error> {
-    match p::response_listscripts(input) {
-        Ok((left, (s, resp))) => {
-            if s.iter().filter(|(_, is_active)| *is_active).count() > 1 {
-                Err(Error::InvalidResponse)
-            } else {
-                Ok((left, s, resp))
-            }
-        }
-        Err(nom::Err::Incomplete(_)) => Err(Error::IncompleteResponse),
-        _ => Err(Error::InvalidResponse),
+pub fn response_listscripts(input: &str) -> Result<(&str, Vec<ScriptEntry>, Response), Error> {
+    let (left, s, resp) = response_listscripts_ref(input)?;
+    Ok((left, into_owned_scripts(s), resp.into_owned()))
+}
+
+// Names in entries returned by parse_listscripts are already validated.
+pub(crate) fn into_owned_scripts(s: Vec<ScriptEntryRef>) -> Vec<ScriptEntry> {
+    s.into_iter()
+        .map(|(name, active)| (SieveName::from_valid(name.into_owned()), active))
+        .collect()
+}
+
+/// Like [`response_listscripts`], but borrows script names from `input`.
+pub fn response_listscripts_ref(
+    input: &str,
+) -> Result<(&str, Vec<ScriptEntryRef<'_>>, ResponseRef<'_>), Error> {
+    parse_listscripts(input, &DEFAULT_LIMITS)
+}
+
+pub(crate) fn parse_listscripts<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> Result<(&'a str, Vec<ScriptEntryRef<'a>>, ResponseRef<'a>), Error> {
+    check_bye(input, limits)?;
+    let (left, (s, resp)) =
+        p::response_listscripts(input, limits).map_err(|e| Error::parse(input, e))?;
+    if let Some((name, _)) = s.iter().find(|(name, _)| !sieve_name::is_valid(name)) {
+        return Err(Error::InvalidResponse(ParseFailure::at(
+            input,
+            name,
+            "sieve-name",
+        )));
+    }
+    match s.iter().filter(|(_, is_active)| *is_active).nth(1) {
+        Some((name, _)) => Err(Error::InvalidResponse(ParseFailure::at(
+            input,
+            name,
+            "response-listscripts",
+        ))),
+        None => Ok((left, s, resp)),
+    }
+}
+
+#[test]
+fn test_response_listscripts_ref() {
+    let (left, scripts, resp) =
+        response_listscripts_ref("\"a\" ACTIVE\r\n\"b\\\"c\"\r\nOK \"Done\"\r\n").unwrap();
+    assert_eq!(left, "");
+    assert!(matches!(scripts[0], (Cow::Borrowed("a"), true)));
+    assert!(matches!(&scripts[1], (Cow::Owned(s), false) if s == "b\"c"));
+    assert!(matches!(resp.human, Some(Cow::Borrowed("Done"))));
+    assert_eq!(
+        resp.into_owned(),
+        response_listscripts("OK \"Done\"\r\n").unwrap().2
+    );
+
+    let (_, scripts, _) = response_listscripts("\"a\" ACTIVE\r\nOK\r\n").unwrap();
+    assert_eq!(scripts, vec![(SieveName::new("a").unwrap(), true)]);
+    for bad in ["\"\"", "\"a\u{85}\"", "\"e\u{301}\""] {
+        let input = format!("\"ok\"\r\n{}\r\nOK\r\n", bad);
+        assert!(matches!(
+            response_listscripts_ref(&input),
+            Err(Error::InvalidResponse(ParseFailure {
+                offset: 7,
+                element: "sieve-name",
+                ..
+            }))
+        ));
     }
 }
 
@@ -293,17 +999,25 @@ pub fn response_checkscript(input: &str) -> Result<(&str, Response), Error> {
 /// Parses text returned from the server in response to the CAPABILITY command.
 /// Returns list of capabilities and optional additional strings.
 pub fn response_capability(input: &str) -> Result<(&str, Vec<Capability>, Response), Error> {
-    match p::response_capability(input) {
-        Ok((left, (s, resp))) => {
-            let caps = s
-                .iter()
-                .map(|(cap, rest)| Capability::try_from((&**cap, rest.as_deref())).unwrap())
-                .collect();
-            Ok((left, caps, resp))
-        }
-        Err(nom::Err::Incomplete(_)) => Err(Error::IncompleteResponse),
-        _ => Err(Error::InvalidResponse),
-    }
+    let (left, caps, resp) = response_capability_ref(input)?;
+    Ok((left, into_owned_capabilities(caps), resp.into_owned()))
+}
+
+/// Like [`response_capability`], but borrows strings from `input`.
+pub fn response_capability_ref(
+    input: &str,
+) -> Result<(&str, Vec<CapabilityRef<'_>>, ResponseRef<'_>), Error> {
+    parse_capability(input, &DEFAULT_LIMITS)
+}
+
+pub(crate) fn parse_capability<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> Result<(&'a str, Vec<CapabilityRef<'a>>, ResponseRef<'a>), Error> {
+    check_bye(input, limits)?;
+    let (left, (s, resp)) =
+        p::response_capability(input, limits).map_err(|e| Error::parse(input, e))?;
+    Ok((left, to_capabilities(input, s)?, resp))
 }
 
 /// Parses text returned from the server in response to the HAVESPACE command.
@@ -314,17 +1028,14 @@ pub fn response_havespace(input: &str) -> Result<(&str, Response), Error> {
 /// Parses text returned from the server in response to the STARTTLS command.
 /// Returns list of capabilities and optional additional strings.
 pub fn response_starttls(input: &str) -> Result<(&str, Vec<Capability>, Response), Error> {
-    match p::response_starttls(input) {
-        Ok((left, (s, resp))) => {
-            let caps = s
-                .iter()
-                .map(|(cap, rest)| Capability::try_from((&**cap, rest.as_deref())).unwrap())
-                .collect();
-            Ok((left, caps, resp))
-        }
-        Err(nom::Err::Incomplete(_)) => Err(Error::IncompleteResponse),
-        _ => Err(Error::InvalidResponse),
-    }
+    check_bye(input, &DEFAULT_LIMITS)?;
+    let (left, (s, resp)) =
+        p::response_starttls(input, &DEFAULT_LIMITS).map_err(|e| Error::parse(input, e))?;
+    Ok((
+        left,
+        into_owned_capabilities(to_capabilities(input, s)?),
+        resp.into_owned(),
+    ))
 }
 
 /// Parses text returned from the server in response to the RENAMESCRIPT command.
@@ -334,14 +1045,21 @@ pub fn response_renamescript(input: &str) -> Result<(&str, Response), Error> {
 
 /// Parses text returned from the server in response to the NOOP command.
 pub fn response_noop(input: &str) -> Result<(&str, Response), Error> {
-    match response_oknobye(input) {
+    parse_noop(input, &DEFAULT_LIMITS).map(|(left, r)| (left, r.into_owned()))
+}
+
+pub(crate) fn parse_noop<'a>(
+    input: &'a str,
+    limits: &ParserLimits,
+) -> Result<(&'a str, ResponseRef<'a>), Error> {
+    match parse_oknobye(input, limits) {
         Ok((
             left,
-            r @ Response {
+            r @ ResponseRef {
                 tag: OkNoBye::Ok, ..
             },
         )) => Ok((left, r)),
-        Ok(_) => Err(Error::InvalidResponse),
+        Ok((_, r)) => Err(Error::Refused(r.into_owned())),
         Err(e) => Err(e),
     }
 }
@@ -350,3 +1068,141 @@ pub fn response_noop(input: &str) -> Result<(&str, Response), Error> {
 pub fn response_unauthenticate(input: &str) -> Result<(&str, Response), Error> {
     response_oknobye(input)
 }
+
+/// A parsed response of any kind, as returned by [`ResponseKind::parse`](crate::ResponseKind::parse).
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CommandResponse {
+    /// Capabilities listed in the greeting or in response to CAPABILITY or
+    /// STARTTLS.
+    Capabilities(Vec<Capability>, Response),
+    /// A SASL challenge sent in response to AUTHENTICATE.
+    Challenge(String),
+    /// A script returned by GETSCRIPT.
+    Script(String, Response),
+    /// Scripts listed by LISTSCRIPTS, and whether each is the active script.
+    ScriptList(Vec<ScriptEntry>, Response),
+    /// Any other OK response.
+    Ok(Response),
+    /// A NO response referring the client to another server.
+    Referral(SieveUrl, Response),
+    /// Any other NO response.
+    No(Response),
+    /// The server is closing the connection.
+    Bye(Response),
+}
+
+impl CommandResponse {
+    /// A copy of the response with any script or SASL challenge replaced by a
+    /// placeholder, for logging.
+    pub fn redacted(&self) -> CommandResponse {
+        match self {
+            CommandResponse::Challenge(_) => CommandResponse::Challenge(REDACTED.to_string()),
+            CommandResponse::Script(_, r) => {
+                CommandResponse::Script(REDACTED.to_string(), r.clone())
+            }
+            response => response.clone(),
+        }
+    }
+}
+
+impl From<Response> for CommandResponse {
+    /// Classifies a response that carries no data by its tag and code.
+    fn from(r: Response) -> Self {
+        match (&r.tag, &r.code) {
+            (OkNoBye::Ok, _) => CommandResponse::Ok(r),
+            (OkNoBye::No, Some((ResponseCode::Referral(url), _))) => {
+                CommandResponse::Referral(url.clone(), r)
+            }
+            (OkNoBye::No, _) => CommandResponse::No(r),
+            (OkNoBye::Bye, _) => CommandResponse::Bye(r),
+        }
+    }
+}
+
+#[test]
+fn test_unsolicited_bye() {
+    let bye = "BYE (TRYLATER) \"Shutting down\"\r\n";
+    let expected = || {
+        Error::UnsolicitedBye(Response {
+            tag: OkNoBye::Bye,
+            code: Some((ResponseCode::TryLater, None)),
+            human: Some("Shutting down".to_string()),
+        })
+    };
+    assert_eq!(response_noop(bye), Err(expected()));
+    assert_eq!(response_logout(bye), Err(expected()));
+    assert_eq!(response_getscript(bye), Err(expected()));
+    assert_eq!(response_listscripts(bye), Err(expected()));
+    assert_eq!(response_capability(bye), Err(expected()));
+    assert_eq!(response_starttls(bye), Err(expected()));
+    assert!(matches!(
+        response_noop("BY"),
+        Err(Error::IncompleteResponse(_))
+    ));
+    response_noop("OK\r\n").unwrap();
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    use serde_json::{from_str, json, to_string, to_value};
+
+    let command = Command::put_script("a", "keep;").unwrap();
+    assert_eq!(
+        to_value(&command).unwrap(),
+        json!({"PutScript": ["a", "keep;"]})
+    );
+    assert_eq!(
+        to_value(command.redacted()).unwrap(),
+        json!({"PutScript": ["a", "<redacted>"]})
+    );
+    assert_eq!(
+        to_value(Command::ListScripts).unwrap(),
+        json!("ListScripts")
+    );
+    for command in [
+        command,
+        Command::set_active("").unwrap(),
+        Command::renamescript("a", "b").unwrap(),
+        Command::have_space("a", 10).unwrap(),
+        Command::noop_with_tag("a"),
+    ] {
+        assert_eq!(
+            from_str::<Command>(&to_string(&command).unwrap()).unwrap(),
+            command
+        );
+    }
+    assert!(from_str::<Command>(r#"{"GetScript": ""}"#).is_err());
+
+    let (_, caps, response) = response_capability(
+        "\"SIEVE\" \"fileinto\"\r\n\"STARTTLS\"\r\nNO (QUOTA/MAXSIZE) \"x\"\r\n",
+    )
+    .unwrap();
+    assert_eq!(
+        to_value(&caps).unwrap(),
+        json!([{"Sieve": ["fileinto"]}, "StartTls"])
+    );
+    assert_eq!(
+        to_value(&response).unwrap(),
+        json!({"tag": "No", "code": [{"Quota": "MaxSize"}, null], "human": "x"})
+    );
+    assert_eq!(
+        from_str::<Vec<Capability>>(&to_string(&caps).unwrap()).unwrap(),
+        caps
+    );
+    assert_eq!(
+        from_str::<Response>(&to_string(&response).unwrap()).unwrap(),
+        response
+    );
+
+    let script = CommandResponse::Script("keep;".to_string(), response.clone());
+    assert_eq!(
+        from_str::<CommandResponse>(&to_string(&script).unwrap()).unwrap(),
+        script
+    );
+    assert_eq!(
+        script.redacted(),
+        CommandResponse::Script("<redacted>".to_string(), response)
+    );
+}
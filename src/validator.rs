@@ -0,0 +1,312 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::script::SieveScript;
+use crate::sieve;
+use crate::store::StoreError;
+
+// Distinguishes temporary directories created by one process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Where in a script a [`Diagnostic`] applies. Lines and columns count
+/// from 1, and columns count characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    pub line: usize,
+    pub column: Option<usize>,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.column {
+            Some(column) => write!(f, "line {}, column {}", self.line, column),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Severity {
+    /// The script is rejected.
+    Error,
+    /// The script is accepted, but the client is warned.
+    Warning,
+}
+
+/// A problem found in a script by a [`ScriptValidator`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub position: Option<Position>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(position: Option<Position>, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            position,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(position: Option<Position>, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            position,
+            message: message.into(),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.position {
+            Some(position) => write!(f, "{}: {}", position, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// Checks the scripts sent with PUTSCRIPT and CHECKSCRIPT to a
+/// [`Server`](crate::Server) given it with
+/// [`Server::with_validator`](crate::Server::with_validator).
+///
+/// Scripts with errors are refused with NO, the errors being the
+/// human-readable text. Those with only warnings are accepted with
+/// `OK (WARNINGS)` and the warnings.
+pub trait ScriptValidator: Send + Sync {
+    /// The errors and warnings found in `script`. Fails if the script
+    /// could not be checked at all.
+    fn validate(&self, script: &SieveScript) -> io::Result<Vec<Diagnostic>>;
+}
+
+// Validates `script`, failing with its errors, or returning the text of its
+// warnings.
+pub(crate) fn check_script(
+    validator: Option<&dyn ScriptValidator>,
+    script: &SieveScript,
+) -> Result<Option<String>, StoreError> {
+    let diagnostics = match validator {
+        Some(validator) => validator.validate(script)?,
+        None => return Ok(None),
+    };
+    let join = |severity| {
+        let text = diagnostics
+            .iter()
+            .filter(|d| d.severity == severity)
+            .map(Diagnostic::to_string)
+            .collect::<Vec<_>>()
+            .join("; ");
+        Some(text).filter(|text| !text.is_empty())
+    };
+    match join(Severity::Error) {
+        Some(errors) => Err(StoreError::InvalidScript(errors)),
+        None => Ok(join(Severity::Warning)),
+    }
+}
+
+/// A [`ScriptValidator`] that runs a local Sieve compiler, such as Dovecot
+/// Pigeonhole's `sievec`, on each script.
+///
+/// The script is written to a file in a new temporary directory, whose
+/// path is passed after the configured arguments. The directory is removed
+/// afterwards, with any compiled output. Lines of output of the form
+/// `line N: error: ...` or `line N: warning: ...`, as `sievec` prints,
+/// become diagnostics. If the command fails without reporting an error,
+/// its first line of output is the error.
+#[derive(Debug, Clone)]
+pub struct CommandValidator {
+    program: PathBuf,
+    args: Vec<OsString>,
+    temp_dir: PathBuf,
+}
+
+impl CommandValidator {
+    pub fn new(program: impl Into<PathBuf>) -> CommandValidator {
+        CommandValidator {
+            program: program.into(),
+            args: Vec::new(),
+            temp_dir: std::env::temp_dir(),
+        }
+    }
+
+    /// Runs `sievec` as found on the `PATH`.
+    pub fn sievec() -> CommandValidator {
+        CommandValidator::new("sievec")
+    }
+
+    /// Passes `args` before the path of the script.
+    pub fn with_args<I, A>(mut self, args: I) -> CommandValidator
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<OsString>,
+    {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Writes scripts under `dir`, rather than the system's temporary
+    /// directory.
+    pub fn with_temp_dir(mut self, dir: impl Into<PathBuf>) -> CommandValidator {
+        self.temp_dir = dir.into();
+        self
+    }
+
+    fn run(&self, dir: &Path, script: &SieveScript) -> io::Result<Vec<Diagnostic>> {
+        let path = dir.join("script.sieve");
+        fs::write(&path, script.as_str())?;
+        let output = process::Command::new(&self.program)
+            .args(&self.args)
+            .arg(&path)
+            .current_dir(dir)
+            .stdin(Stdio::null())
+            .output()?;
+        let text = String::from_utf8_lossy(&output.stderr).into_owned()
+            + &String::from_utf8_lossy(&output.stdout);
+        let mut diagnostics: Vec<_> = text.lines().filter_map(parse_diagnostic).collect();
+        if !output.status.success() && !diagnostics.iter().any(Diagnostic::is_error) {
+            let message = match text.lines().map(str::trim).find(|line| !line.is_empty()) {
+                Some(line) => line.to_owned(),
+                None => format!("{} rejected the script", self.program.display()),
+            };
+            diagnostics.push(Diagnostic::error(None, message));
+        }
+        Ok(diagnostics)
+    }
+}
+
+impl ScriptValidator for CommandValidator {
+    fn validate(&self, script: &SieveScript) -> io::Result<Vec<Diagnostic>> {
+        let dir = self.temp_dir.join(format!(
+            "managesieve-{}-{}",
+            process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&dir)?;
+        let result = self.run(&dir, script);
+        let _ = fs::remove_dir_all(&dir);
+        result
+    }
+}
+
+// Reads a diagnostic from a line such as
+// `script: line 2: error: unknown command 'foo'.`
+fn parse_diagnostic(line: &str) -> Option<Diagnostic> {
+    let (_, rest) = line.split_once("line ")?;
+    let (number, rest) = rest.split_once(": ")?;
+    let position = Some(Position {
+        line: number.parse().ok()?,
+        column: None,
+    });
+    if let Some(message) = rest.strip_prefix("error: ") {
+        Some(Diagnostic::error(position, message))
+    } else {
+        rest.strip_prefix("warning: ")
+            .map(|message| Diagnostic::warning(position, message))
+    }
+}
+
+#[test]
+fn test_parse_diagnostic() {
+    let at = |line| Some(Position { line, column: None });
+    assert_eq!(
+        parse_diagnostic("script: line 2: error: unknown command 'foo'."),
+        Some(Diagnostic::error(at(2), "unknown command 'foo'."))
+    );
+    assert_eq!(
+        parse_diagnostic("script: line 10: warning: ignored."),
+        Some(Diagnostic::warning(at(10), "ignored."))
+    );
+    assert_eq!(parse_diagnostic("script: error: validation failed."), None);
+    assert_eq!(parse_diagnostic("line x: error: no"), None);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_command_validator() {
+    let script = SieveScript::new("keep;\r\n");
+    let validator = |shell: &str| CommandValidator::new("sh").with_args(["-c", shell, "sh"]);
+
+    let accept = validator("grep -q keep \"$1\"");
+    assert_eq!(accept.validate(&script).unwrap(), vec![]);
+    let warn = validator("echo 'script: line 1: warning: keep is implicit.'");
+    assert_eq!(
+        check_script(Some(&warn), &script),
+        Ok(Some("line 1: keep is implicit.".to_string()))
+    );
+    let reject = validator("echo 'script: line 1: error: no.' >&2; exit 1");
+    assert_eq!(
+        check_script(Some(&reject), &script),
+        Err(StoreError::InvalidScript("line 1: no.".to_string()))
+    );
+    let fail = validator("echo 'config missing' >&2; exit 1");
+    assert_eq!(
+        fail.validate(&script).unwrap(),
+        vec![Diagnostic::error(None, "config missing")]
+    );
+    assert!(CommandValidator::new("/nonexistent/sievec")
+        .validate(&script)
+        .is_err());
+}
+
+/// A [`ScriptValidator`] checking scripts within the process, reporting
+/// the first error in their syntax found by [`sieve::parse`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyntaxValidator;
+
+impl ScriptValidator for SyntaxValidator {
+    fn validate(&self, script: &SieveScript) -> io::Result<Vec<Diagnostic>> {
+        Ok(sieve::parse(script.as_str())
+            .err()
+            .into_iter()
+            .map(Diagnostic::from)
+            .collect())
+    }
+}
+
+#[test]
+fn test_syntax_validator() {
+    let validate = |text: &str| {
+        SyntaxValidator
+            .validate(&SieveScript::new(text))
+            .unwrap()
+            .iter()
+            .map(Diagnostic::to_string)
+            .collect::<Vec<_>>()
+    };
+    let valid = "require [\"fileinto\"]; # {\r\n\
+                 if header :contains \"subject\" \"}\\\"\" {\r\n\
+                 /* ( */ fileinto text: # [\r\n\
+                 line\r\n..\r\n.\r\n;\r\n}\r\n";
+    assert!(validate(valid).is_empty());
+    assert_eq!(
+        validate("keep;\r\nif true {\r\n  stop;\r\n"),
+        ["line 2, column 9: '{' is not closed"]
+    );
+    assert_eq!(
+        validate("if true { stop; ]"),
+        ["line 1, column 17: expected a command or '}'"]
+    );
+    assert_eq!(
+        validate("keep;\r\n  \"a\\\"\r\n"),
+        ["line 2, column 3: unterminated string"]
+    );
+    assert_eq!(
+        validate("/* keep; *\r\n"),
+        ["line 1, column 1: unterminated comment"]
+    );
+    assert_eq!(
+        validate("reject text:\r\nbye\r\n"),
+        ["line 1, column 8: unterminated multi-line string"]
+    );
+}